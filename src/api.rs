@@ -0,0 +1,402 @@
+use crate::cargo;
+use crate::cli::{
+    BuildDetails, Command, FlashInput, GenerateInput, MeasureInput, ProbeOptions, ResetStrategy,
+    Timer,
+};
+use crate::flash;
+use crate::generate;
+use crate::measure;
+use crate::metadata::{self, RaukMetadata};
+use crate::settings::{self, RaukSettings};
+use anyhow::{Context, Result};
+use std::fs::{canonicalize, create_dir_all};
+use std::os::unix::fs::symlink;
+use std::path::PathBuf;
+
+/// The cargo target to build.
+#[derive(Debug, Clone, Default)]
+pub struct BuildConfig {
+    /// Name of the bin target
+    pub bin: Option<String>,
+    /// Name of the example target
+    pub example: Option<String>,
+    /// Build artifacts in release mode
+    pub release: bool,
+    /// Name of the package in a Cargo workspace that contains the target
+    pub package: Option<String>,
+}
+
+impl BuildConfig {
+    /// Builds the bin target with the given name.
+    pub fn bin(name: &str) -> BuildConfig {
+        BuildConfig {
+            bin: Some(name.to_string()),
+            ..BuildConfig::default()
+        }
+    }
+
+    /// Builds the example target with the given name.
+    pub fn example(name: &str) -> BuildConfig {
+        BuildConfig {
+            example: Some(name.to_string()),
+            ..BuildConfig::default()
+        }
+    }
+}
+
+impl From<BuildConfig> for BuildDetails {
+    fn from(config: BuildConfig) -> BuildDetails {
+        BuildDetails {
+            bin: config.bin,
+            example: config.example,
+            release: config.release,
+            package: config.package,
+        }
+    }
+}
+
+/// Configuration of the generate command.
+#[derive(Debug, Clone, Default)]
+pub struct GenerateConfig {
+    pub build: BuildConfig,
+    /// Emit all KLEE errors
+    pub emit_all_errors: bool,
+    /// The cargo feature that enables the test harness
+    pub analysis_feature: Option<String>,
+    /// Extra arguments passed verbatim to KLEE
+    pub klee_args: Vec<String>,
+    /// Halt KLEE after this many seconds
+    pub klee_max_time: Option<u64>,
+    /// Name or path of the KLEE binary. Defaults to "klee"
+    pub klee: Option<String>,
+    /// Use the already built test harness instead of building it
+    pub no_build: bool,
+}
+
+impl GenerateConfig {
+    pub fn new(build: BuildConfig) -> GenerateConfig {
+        GenerateConfig {
+            build,
+            ..GenerateConfig::default()
+        }
+    }
+}
+
+impl From<GenerateConfig> for GenerateInput {
+    fn from(config: GenerateConfig) -> GenerateInput {
+        GenerateInput {
+            build: config.build.into(),
+            verbose: false,
+            keep_artifacts: false,
+            emit_all_errors: config.emit_all_errors,
+            analysis_feature: config.analysis_feature,
+            klee_args: config.klee_args,
+            klee_max_time: config.klee_max_time,
+            klee: config.klee,
+            list_tests: false,
+            no_build: config.no_build,
+        }
+    }
+}
+
+/// Configuration of the flash command.
+#[derive(Debug, Clone, Default)]
+pub struct FlashConfig {
+    pub build: BuildConfig,
+    /// The target architecture to build the executable for
+    pub target: Option<String>,
+    /// The name of the chip to flash to
+    pub chip: Option<String>,
+    /// How many seconds to wait for the core to halt
+    pub halt_timeout: Option<u64>,
+    /// The cargo feature that enables the replay harness
+    pub replay_feature: Option<String>,
+    /// Read back a sample of the flashed program and compare it with the binary
+    pub verify: bool,
+    /// Flash the already built replay harness instead of building it
+    pub no_build: bool,
+    /// Index of the core running the RTIC application
+    pub core: usize,
+    /// How to connect to the debug probe
+    pub probe: ProbeOptions,
+}
+
+impl FlashConfig {
+    pub fn new(build: BuildConfig) -> FlashConfig {
+        FlashConfig {
+            build,
+            ..FlashConfig::default()
+        }
+    }
+}
+
+impl From<FlashConfig> for FlashInput {
+    fn from(config: FlashConfig) -> FlashInput {
+        FlashInput {
+            build: config.build.into(),
+            verbose: false,
+            target: config.target,
+            chip: config.chip,
+            halt_timeout: config.halt_timeout,
+            replay_feature: config.replay_feature,
+            verify: config.verify,
+            no_build: config.no_build,
+            reset: ResetStrategy::Halt,
+            core: config.core,
+            probe: config.probe,
+        }
+    }
+}
+
+/// Configuration of the measure command.
+#[derive(Debug, Clone, Default)]
+pub struct MeasureConfig {
+    pub build: BuildConfig,
+    /// Path to DWARF. Defaults to the output of the last flash
+    pub dwarf: Option<PathBuf>,
+    /// Path to KLEE tests. Defaults to the output of the last generate
+    pub ktests: Option<PathBuf>,
+    /// The name of the chip to measure on
+    pub chip: Option<String>,
+    /// How many seconds to wait for the core to halt
+    pub halt_timeout: Option<u64>,
+    /// Index of the core running the RTIC application
+    pub core: usize,
+    /// How to connect to the debug probe
+    pub probe: ProbeOptions,
+    /// Print the progress of the measurement for each test vector
+    pub progress: bool,
+    /// The counter used to measure clock cycles
+    pub timer: Timer,
+    /// Start address of the RAM. Defaults to 0x2000_0000
+    pub ram_start: Option<u64>,
+    /// Replay each test vector this many times and keep the maximum cycles per
+    /// breakpoint. Zero is treated as one
+    pub samples: u32,
+    /// Fail if the name of a task or resource lock can't be resolved
+    pub strict_names: bool,
+    /// Only output the traces of these tasks. All tasks if empty
+    pub tasks: Vec<String>,
+    /// Name patterns of the subroutines that read a peripheral. vcell and
+    /// read_volatile if empty
+    pub vcell_patterns: Vec<String>,
+    /// Name prefixes of the KTest objects not to write to the target. KLEE's
+    /// bookkeeping objects if empty
+    pub ignore_objects: Vec<String>,
+    /// Reset the target and skip test vectors that do not halt in time
+    pub recover: bool,
+    /// Add the traces to the existing output instead of overwriting it
+    pub append: bool,
+    /// Name or path of the llvm-objdump binary. Defaults to "llvm-objdump"
+    pub objdump: Option<String>,
+    /// Append every breakpoint to `breakpoints.log` in the output directory
+    pub trace_breakpoints: bool,
+}
+
+impl MeasureConfig {
+    pub fn new(build: BuildConfig) -> MeasureConfig {
+        MeasureConfig {
+            build,
+            ..MeasureConfig::default()
+        }
+    }
+}
+
+impl From<MeasureConfig> for MeasureInput {
+    fn from(config: MeasureConfig) -> MeasureInput {
+        MeasureInput {
+            build: config.build.into(),
+            quiet: false,
+            dwarf: config.dwarf,
+            ktests: config.ktests,
+            chip: config.chip,
+            halt_timeout: config.halt_timeout,
+            core: config.core,
+            probe: config.probe,
+            progress: config.progress,
+            timer: config.timer,
+            ram_start: config.ram_start,
+            samples: config.samples,
+            strict_names: config.strict_names,
+            tasks: config.tasks,
+            vcell_patterns: config.vcell_patterns,
+            ignore_objects: config.ignore_objects,
+            recover: config.recover,
+            append: config.append,
+            objdump: config.objdump,
+            trace_breakpoints: config.trace_breakpoints,
+        }
+    }
+}
+
+/// Runs the rauk commands on an RTIC project without the command line interface.
+/// The project's Cargo.toml is patched for the duration of each command, and the
+/// metadata is saved afterwards so commands can be chained like on the command line.
+///
+/// ```no_run
+/// use rauk::{BuildConfig, FlashConfig, GenerateConfig, MeasureConfig, Rauk};
+///
+/// let mut rauk = Rauk::new("path/to/rtic-app")?;
+/// rauk.generate(GenerateConfig::new(BuildConfig::bin("app")))?;
+///
+/// let mut flash = FlashConfig::new(BuildConfig::bin("app"));
+/// flash.chip = Some("STM32F401RETx".to_string());
+/// rauk.flash(flash)?;
+///
+/// let mut measure = MeasureConfig::new(BuildConfig::bin("app"));
+/// measure.chip = Some("STM32F401RETx".to_string());
+/// let traces = rauk.measure(measure)?;
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+pub struct Rauk {
+    settings: RaukSettings,
+    metadata: RaukMetadata,
+    no_patch: bool,
+    patch_template: Option<PathBuf>,
+}
+
+impl Rauk {
+    /// Loads the settings and metadata of the RTIC project at `project_dir`.
+    pub fn new<P: Into<PathBuf>>(project_dir: P) -> Result<Rauk> {
+        let project_dir = canonicalize(project_dir.into())?;
+        let output_dir = metadata::get_rauk_output_path(&project_dir);
+        let _ = create_dir_all(&output_dir);
+
+        cargo::restore_previous_run(&project_dir)?;
+
+        let settings = settings::load_settings(&project_dir)?;
+        let metadata = metadata::load_metadata(&project_dir, &output_dir)?;
+
+        let no_patch = settings.resolve_no_patch(false);
+
+        Ok(Rauk {
+            settings,
+            metadata,
+            no_patch,
+            patch_template: None,
+        })
+    }
+
+    /// Don't patch the project's Cargo.toml. The project needs to have the
+    /// correct dependencies and features set.
+    pub fn no_patch(&mut self, no_patch: bool) -> &mut Rauk {
+        self.no_patch = no_patch;
+        self
+    }
+
+    /// Patch the project's Cargo.toml with this template instead of the one
+    /// provided by rauk.
+    pub fn patch_template<P: Into<PathBuf>>(&mut self, path: P) -> &mut Rauk {
+        self.patch_template = Some(path.into());
+        self
+    }
+
+    /// Build the Cargo.toml at `path` instead of the one in the project directory.
+    pub fn manifest_path<P: Into<PathBuf>>(&mut self, path: P) -> Result<&mut Rauk> {
+        let path = path.into();
+        let manifest = cargo::resolve_manifest_path(&self.metadata.project_directory, Some(&path))?;
+        self.metadata.manifest_path = Some(manifest);
+        Ok(self)
+    }
+
+    /// Write the metadata, caches and results to `path` instead of `target/rauk` in the
+    /// project directory. The metadata of a previous run in `path` is loaded.
+    pub fn output_dir<P: Into<PathBuf>>(&mut self, path: P) -> Result<&mut Rauk> {
+        let output_dir = path.into();
+        let _ = create_dir_all(&output_dir);
+        let manifest_path = self.metadata.manifest_path.take();
+        self.metadata = metadata::load_metadata(&self.metadata.project_directory, &output_dir)?;
+        self.metadata.manifest_path = manifest_path;
+        Ok(self)
+    }
+
+    /// Generates test vectors using KLEE. Returns the path to the generated tests.
+    pub fn generate(&mut self, config: GenerateConfig) -> Result<PathBuf> {
+        let mut input = GenerateInput::from(config);
+        input.get_missing_input(&self.settings);
+        let path = self.run_patched(|settings, metadata| {
+            generate::generate_klee_tests(&input, settings, metadata)
+                .context("Failed to execute generate command")
+        })?;
+        let _ = symlink(
+            &path,
+            &self.metadata.rauk_output_directory.join("klee-last"),
+        );
+        let stats = generate::read_klee_stats(&path);
+        let build = input.build.clone();
+        self.metadata
+            .update_output(&build, Some(path.clone()), &Command::Generate(input))?;
+        self.metadata.update_klee_stats(&build, stats);
+        self.save_metadata()?;
+        Ok(path)
+    }
+
+    /// Builds and flashes the replay harness. Returns the path to the flashed binary.
+    pub fn flash(&mut self, config: FlashConfig) -> Result<PathBuf> {
+        let mut input = FlashInput::from(config);
+        input.get_missing_input(&self.settings);
+        let path = self.run_patched(|settings, metadata| {
+            flash::flash_to_target(&input, settings, metadata)
+                .context("Failed to execute flash command")
+        })?;
+        self.save_output(
+            &input.build.clone(),
+            Some(path.clone()),
+            Command::Flash(input),
+        )?;
+        Ok(path)
+    }
+
+    /// Measures the WCET of the flashed replay harness using the generated test vectors.
+    /// Returns the path to the saved traces.
+    pub fn measure(&mut self, config: MeasureConfig) -> Result<Option<PathBuf>> {
+        let mut input = MeasureInput::from(config);
+        input.get_missing_input(&self.settings);
+        let path = self.run_patched(|settings, metadata| {
+            measure::wcet_measurement(&input, settings, metadata)
+                .context("Failed to execute measure command")
+        })?;
+        self.save_output(&input.build.clone(), path.clone(), Command::Measure(input))?;
+        Ok(path)
+    }
+
+    /// Runs `f` with the project's Cargo.toml patched and restores it afterwards,
+    /// also if `f` fails.
+    fn run_patched<T, F>(&mut self, f: F) -> Result<T>
+    where
+        F: FnOnce(&RaukSettings, &mut RaukMetadata) -> Result<T>,
+    {
+        let cargo_dir = self.metadata.cargo_directory();
+
+        if !self.no_patch {
+            cargo::backup_original_cargo_files(&cargo_dir)?;
+            cargo::update_custom_cargo_toml(&cargo_dir, self.patch_template.as_ref())?;
+            cargo::change_cargo_toml_to_custom(&cargo_dir)?;
+        }
+
+        let res = f(&self.settings, &mut self.metadata);
+
+        if !self.no_patch {
+            cargo::restore_orignal_cargo_files(&cargo_dir)?;
+        }
+
+        res
+    }
+
+    /// Stores the output of a command in the metadata and saves it.
+    fn save_output(
+        &mut self,
+        build: &BuildDetails,
+        path: Option<PathBuf>,
+        command: Command,
+    ) -> Result<()> {
+        self.metadata.update_output(build, path, &command)?;
+        self.save_metadata()
+    }
+
+    /// Marks the execution as successful and saves the metadata.
+    fn save_metadata(&mut self) -> Result<()> {
+        self.metadata.program_execution_successful();
+        self.metadata.save()
+    }
+}
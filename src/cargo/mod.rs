@@ -1,17 +1,32 @@
-use anyhow::{Context, Result};
+use crate::utils::hash::stable_hash;
+use anyhow::{anyhow, Context, Result};
 use cargo_toml::Manifest;
-use std::fs::{copy, rename, write};
+use std::fs::{canonicalize, copy, read, read_to_string, remove_file, rename, write};
 use std::path::PathBuf;
+use std::process::Command;
 use toml;
 
 /// Name of the Rauk Cargo.toml
 pub const RAUK_CARGO_TOML: &str = ".rauk_cargo.toml";
 /// Name of the backup of the original Cargo.toml
 pub const CARGO_TOML_BACKUP: &str = ".Cargo.toml.backup";
+/// Marker that exists while the project's Cargo.toml is patched by rauk. Holds the hash
+/// of the backed up Cargo.lock
+pub const RAUK_PATCH_MARKER: &str = ".rauk_patched";
+/// Marker that exists while the patched Cargo files are kept on purpose by
+/// `--keep-artifacts`, so the next run does not take them for an interrupted one
+pub const RAUK_KEPT_MARKER: &str = ".rauk_kept";
+
+/// Name of the backup of the original Cargo.lock
+pub const CARGO_LOCK_BACKUP: &str = ".Cargo.lock.backup";
 
 const CARGO_TOML: &str = "Cargo.toml";
 const CARGO_LOCK: &str = "Cargo.lock";
-const CARGO_LOCK_BACKUP: &str = ".Cargo.lock.backup";
+
+/// The RTIC crate whose version decides which patch template to use
+const RTIC_CRATE: &str = "cortex-m-rtic";
+/// The patch templates provided by rauk and the `major.minor` RTIC version they support
+const PATCH_TEMPLATES: &[(&str, &str)] = &[("0.6", include_str!("templates/v0_6.toml"))];
 
 struct CargoPaths {
     cargo_toml: PathBuf,
@@ -19,6 +34,8 @@ struct CargoPaths {
     toml_backup: PathBuf,
     lock_backup: PathBuf,
     rauk_cargo_toml: PathBuf,
+    patch_marker: PathBuf,
+    kept_marker: PathBuf,
 }
 
 impl CargoPaths {
@@ -29,57 +46,136 @@ impl CargoPaths {
             toml_backup: project_dir.join(CARGO_TOML_BACKUP),
             lock_backup: project_dir.join(CARGO_LOCK_BACKUP),
             rauk_cargo_toml: project_dir.join(RAUK_CARGO_TOML),
+            patch_marker: project_dir.join(RAUK_PATCH_MARKER),
+            kept_marker: project_dir.join(RAUK_KEPT_MARKER),
         }
     }
 }
 
 /// Saves copies of the orignal Cargo.toml and Cargo.lock files in the project directory.
+/// The hash of the Cargo.lock is recorded in the patch marker to verify the restore.
 ///
 /// * `project_dir` - The path to the RTIC project
 pub fn backup_original_cargo_files(project_dir: &PathBuf) -> Result<()> {
     let paths = CargoPaths::new(project_dir);
 
-    copy(&paths.cargo_toml, &paths.toml_backup).with_context(|| {
-        format!(
-            "Could not backup {:?} to {:?}",
-            &paths.cargo_toml, &paths.toml_backup
-        )
-    })?;
-
-    if paths.cargo_lock.exists() {
-        rename(&paths.cargo_lock, &paths.lock_backup).with_context(|| {
-            format!(
-                "Could not backup {:?} to {:?}",
-                &paths.cargo_lock, &paths.lock_backup
-            )
-        })?;
-    }
+    atomic_copy(&paths.cargo_toml, &paths.toml_backup)?;
+    let lock_hash = if paths.cargo_lock.exists() {
+        atomic_copy(&paths.cargo_lock, &paths.lock_backup)?;
+        format!("{:016x}", file_hash(&paths.cargo_lock)?)
+    } else {
+        // A backup from an earlier run must not be restored over the lock of this run
+        let _ = remove_file(&paths.lock_backup);
+        String::new()
+    };
+    write(&paths.patch_marker, lock_hash).context("Could not write the patch marker")?;
 
     Ok(())
 }
 
 /// Restores copies of the original Cargo.toml and Cargo.lock files in the project directory.
+/// If the project had no Cargo.lock, the one created while patched is removed. The lock
+/// backup is checked against the hash recorded when it was made before it is restored.
+/// If it changed, the current Cargo.lock is kept and an error is returned, but the patch
+/// marker is still removed so later runs are not blocked.
 ///
 /// * `project_dir` - The path to the RTIC project
 pub fn restore_orignal_cargo_files(project_dir: &PathBuf) -> Result<()> {
     let paths = CargoPaths::new(project_dir);
 
-    copy(&paths.toml_backup, &paths.cargo_toml).with_context(|| {
-        format!(
-            "Could not restore backup from {:?} to {:?}",
-            &paths.toml_backup, &paths.cargo_toml
-        )
-    })?;
+    atomic_copy(&paths.toml_backup, &paths.cargo_toml)?;
+    if paths.lock_backup.exists() {
+        if let Some(recorded) = recorded_lock_hash(&paths.patch_marker) {
+            if file_hash(&paths.lock_backup)? != recorded {
+                let _ = remove_file(&paths.patch_marker);
+                let _ = remove_file(&paths.kept_marker);
+                return Err(anyhow!(
+                    "The backup {:?} changed since it was made and was not restored. {:?} is left as is",
+                    &paths.lock_backup,
+                    &paths.cargo_lock
+                ));
+            }
+        }
+        atomic_copy(&paths.lock_backup, &paths.cargo_lock)?;
+    } else if paths.cargo_lock.exists() {
+        remove_file(&paths.cargo_lock)
+            .with_context(|| format!("Could not remove {:?}", &paths.cargo_lock))?;
+    }
 
-    if paths.cargo_lock.exists() && paths.lock_backup.exists() {
-        copy(&paths.lock_backup, &paths.cargo_lock).with_context(|| {
-            format!(
-                "Could not restore backup from {:?} to {:?}",
-                &paths.lock_backup, &paths.cargo_lock
-            )
-        })?;
+    let _ = remove_file(&paths.patch_marker);
+    let _ = remove_file(&paths.kept_marker);
+
+    Ok(())
+}
+
+/// Records that the patched Cargo files are kept on purpose, e.g. by `--keep-artifacts`.
+/// Does nothing if the Cargo.toml is not patched.
+///
+/// * `project_dir` - The path to the RTIC project
+pub fn keep_patched_cargo_files(project_dir: &PathBuf) -> Result<()> {
+    let paths = CargoPaths::new(project_dir);
+
+    if paths.patch_marker.exists() {
+        write(&paths.kept_marker, "").context("Could not write the keep marker")?;
     }
+    Ok(())
+}
+
+/// Copies `from` to `to` through a temporary file next to `to` that is then renamed,
+/// so `to` is never left partially written.
+fn atomic_copy(from: &PathBuf, to: &PathBuf) -> Result<()> {
+    let mut temp_name = to.file_name().unwrap_or_default().to_os_string();
+    temp_name.push(".rauk_tmp");
+    let temp = to.with_file_name(temp_name);
+
+    copy(from, &temp).with_context(|| format!("Could not copy {:?} to {:?}", from, &temp))?;
+    rename(&temp, to).with_context(|| format!("Could not move {:?} to {:?}", &temp, to))?;
+    Ok(())
+}
+
+/// Returns the Cargo.lock hash recorded in the patch marker, if any. Markers written
+/// by older versions of rauk are empty.
+fn recorded_lock_hash(patch_marker: &PathBuf) -> Option<u64> {
+    let content = read_to_string(patch_marker).ok()?;
+    u64::from_str_radix(content.trim(), 16).ok()
+}
+
+/// Hashes the contents of a file with a hash that is stable across builds of rauk.
+fn file_hash(path: &PathBuf) -> Result<u64> {
+    let content = read(path).with_context(|| format!("Could not read {:?}", path))?;
+    Ok(stable_hash(&content))
+}
+
+/// Restores the original Cargo files if a previous execution of rauk was interrupted
+/// (e.g. killed or power loss) while the project's Cargo.toml was patched.
+/// Returns true if the files were restored.
+///
+/// * `project_dir` - The path to the RTIC project
+pub fn restore_interrupted_run(project_dir: &PathBuf) -> Result<bool> {
+    let paths = CargoPaths::new(project_dir);
+
+    if paths.patch_marker.exists() && paths.toml_backup.exists() {
+        restore_orignal_cargo_files(project_dir)?;
+        Ok(true)
+    } else {
+        Ok(false)
+    }
+}
 
+/// Restores the Cargo files left patched by a previous execution of rauk, either because
+/// it was interrupted or because its artifacts were kept with `--keep-artifacts`. Only
+/// an interruption is warned about.
+///
+/// * `project_dir` - The path to the RTIC project
+pub fn restore_previous_run(project_dir: &PathBuf) -> Result<()> {
+    let paths = CargoPaths::new(project_dir);
+
+    if paths.kept_marker.exists() {
+        info!("Restoring Cargo files kept by --keep-artifacts");
+        restore_interrupted_run(project_dir)?;
+    } else if restore_interrupted_run(project_dir)? {
+        warn!("Previous execution of rauk was interrupted. The original Cargo files were restored");
+    }
     Ok(())
 }
 
@@ -87,7 +183,11 @@ pub fn restore_orignal_cargo_files(project_dir: &PathBuf) -> Result<()> {
 /// If no such configuration exists it will create a new one.
 ///
 /// * `project_dir` - The path to the RTIC project
-pub fn update_custom_cargo_toml(project_dir: &PathBuf) -> Result<()> {
+/// * `template_path` - A patch template to use instead of the one provided by rauk
+pub fn update_custom_cargo_toml(
+    project_dir: &PathBuf,
+    template_path: Option<&PathBuf>,
+) -> Result<()> {
     let mut rauk_path = project_dir.clone();
     rauk_path.push(RAUK_CARGO_TOML);
 
@@ -95,7 +195,20 @@ pub fn update_custom_cargo_toml(project_dir: &PathBuf) -> Result<()> {
     cargo_path.push(CARGO_TOML);
 
     let mut user_manifest_copy = Manifest::from_path(&cargo_path)?;
-    let template = read_rauk_patch_template()?;
+    let template = match template_path {
+        Some(path) => {
+            info!("Using the patch template {:?}", path);
+            let content = read_to_string(path)
+                .with_context(|| format!("Could not read the patch template {:?}", path))?;
+            toml::from_str(&content)
+                .with_context(|| format!("Could not parse the patch template {:?}", path))?
+        }
+        None => {
+            let rtic_version = detect_rtic_version(project_dir, &user_manifest_copy)?;
+            info!("Detected RTIC version {}", &rtic_version);
+            read_rauk_patch_template(&rtic_version)?
+        }
+    };
     patch_rauk_cargo_toml(&mut user_manifest_copy, &template);
 
     let toml_output = toml::to_string(&user_manifest_copy)?;
@@ -109,19 +222,225 @@ pub fn update_custom_cargo_toml(project_dir: &PathBuf) -> Result<()> {
 /// * `project_dir` - The path to the RTIC project
 pub fn change_cargo_toml_to_custom(project_dir: &PathBuf) -> Result<()> {
     let paths = CargoPaths::new(project_dir);
+    // The marker is written first so an interrupted swap is always restored. It is
+    // kept if the backup already wrote it with the hash of the Cargo.lock
+    if !paths.patch_marker.exists() {
+        write(&paths.patch_marker, "").context("Could not write the patch marker")?;
+    }
     copy(&paths.rauk_cargo_toml, &paths.cargo_toml)
         .context("Could not swap Cargo.toml with custom one.")?;
     Ok(())
 }
 
-/// Reads the template file provided by RAUK
-fn read_rauk_patch_template() -> Result<Manifest> {
-    let content = include_str!("templates/v0_6.toml");
+/// Detects the RTIC version of the project. Prefers the locked version from the
+/// Cargo.lock (or its backup) and falls back to the requirement in the manifest.
+fn detect_rtic_version(project_dir: &PathBuf, manifest: &Manifest) -> Result<String> {
+    let paths = CargoPaths::new(project_dir);
+
+    for lock in &[&paths.cargo_lock, &paths.lock_backup] {
+        if let Ok(content) = read_to_string(lock) {
+            if let Some(version) = parse_locked_version(&content, RTIC_CRATE) {
+                return Ok(version);
+            }
+        }
+    }
+
+    match manifest.dependencies.get(RTIC_CRATE) {
+        Some(dependency) => Ok(dependency.req().to_string()),
+        None => Err(anyhow!(
+            "Could not find the RTIC version. Is {} a dependency of the project?",
+            RTIC_CRATE
+        )),
+    }
+}
+
+/// Returns the version of the package `name` in the contents of a Cargo.lock.
+fn parse_locked_version(lock_content: &str, name: &str) -> Option<String> {
+    let lock: toml::Value = toml::from_str(lock_content).ok()?;
+    let packages = lock.get("package")?.as_array()?;
+    packages
+        .iter()
+        .find(|package| package.get("name").and_then(|n| n.as_str()) == Some(name))
+        .and_then(|package| package.get("version"))
+        .and_then(|version| version.as_str())
+        .map(|version| version.to_string())
+}
+
+/// Selects the patch template matching the `major.minor` part of the RTIC version.
+/// The version can be a locked version or a version requirement.
+fn select_patch_template(rtic_version: &str) -> Result<&'static str> {
+    let version = rtic_version.trim_start_matches(|c: char| "^~=>< ".contains(c));
+    let major_minor: Vec<&str> = version.split('.').take(2).collect();
+    let major_minor = major_minor.join(".");
+
+    PATCH_TEMPLATES
+        .iter()
+        .find(|(supported, _)| *supported == major_minor)
+        .map(|(_, template)| *template)
+        .ok_or_else(|| {
+            let supported: Vec<&str> = PATCH_TEMPLATES.iter().map(|(v, _)| *v).collect();
+            anyhow!(
+                "RTIC version {} is not supported by rauk. Supported versions: {}",
+                rtic_version,
+                supported.join(", ")
+            )
+        })
+}
+
+/// Returns the path to the Cargo.toml rauk patches and builds. That is the Cargo.toml
+/// in the project directory unless `manifest_path` is given.
+///
+/// * `project_dir` - The path to the RTIC project
+/// * `manifest_path` - The `--manifest-path` given on the command line
+pub fn resolve_manifest_path(
+    project_dir: &PathBuf,
+    manifest_path: Option<&PathBuf>,
+) -> Result<PathBuf> {
+    let path = match manifest_path {
+        Some(path) => path,
+        None => return Ok(project_dir.join(CARGO_TOML)),
+    };
+    if path.file_name().and_then(|name| name.to_str()) != Some(CARGO_TOML) {
+        return Err(anyhow!(
+            "The manifest path {:?} must point at a {} file",
+            path,
+            CARGO_TOML
+        ));
+    }
+    canonicalize(path).with_context(|| format!("Could not find the manifest {:?}", path))
+}
+
+/// Writes the patch template provided by rauk for the given RTIC version to `path`,
+/// so it can be edited and passed with `--patch-template`.
+///
+/// * `rtic_version` - The RTIC version the template is for
+/// * `path` - Where to write the template
+pub fn dump_patch_template(rtic_version: &str, path: &PathBuf) -> Result<()> {
+    let template = select_patch_template(rtic_version)?;
+    write(path, template)
+        .with_context(|| format!("Could not write the patch template to {:?}", path))
+}
+
+/// Returns the target directory of the project, shared by all members of a workspace
+/// and overridden by `CARGO_TARGET_DIR`. Asks `cargo metadata`, unless rauk does not
+/// build, in which case the directory is found without running cargo.
+///
+/// * `cargo_path` - The path to the project's Cargo.toml
+/// * `no_build` - If rauk uses an already built harness instead of building it
+pub fn resolve_target_directory(cargo_path: &PathBuf, no_build: bool) -> Result<PathBuf> {
+    resolve_target_directory_in(
+        cargo_path,
+        no_build,
+        std::env::var_os("CARGO_TARGET_DIR").map(PathBuf::from),
+    )
+}
+
+fn resolve_target_directory_in(
+    cargo_path: &PathBuf,
+    no_build: bool,
+    env_target_dir: Option<PathBuf>,
+) -> Result<PathBuf> {
+    if no_build {
+        Ok(find_target_directory(cargo_path, env_target_dir))
+    } else {
+        target_directory(cargo_path)
+    }
+}
+
+/// Returns the target directory of the project as reported by `cargo metadata`.
+///
+/// * `cargo_path` - The path to the project's Cargo.toml
+fn target_directory(cargo_path: &PathBuf) -> Result<PathBuf> {
+    let output = Command::new("cargo")
+        .args(&["metadata", "--format-version", "1", "--no-deps"])
+        .arg("--manifest-path")
+        .arg(cargo_path)
+        .output()
+        .context("Failed to execute cargo metadata")?;
+
+    if !output.status.success() {
+        return Err(anyhow!(
+            "cargo metadata failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+
+    let metadata: serde_json::Value = serde_json::from_slice(&output.stdout)?;
+    metadata["target_directory"]
+        .as_str()
+        .map(PathBuf::from)
+        .ok_or_else(|| anyhow!("cargo metadata did not report a target directory"))
+}
+
+/// Returns the target directory of the project without running cargo. Uses
+/// `CARGO_TARGET_DIR` if set, otherwise the `target/` directory of the closest
+/// workspace root above the Cargo.toml, or of the Cargo.toml itself.
+fn find_target_directory(cargo_path: &PathBuf, env_target_dir: Option<PathBuf>) -> PathBuf {
+    if let Some(target_dir) = env_target_dir {
+        return target_dir;
+    }
+    let package_dir = cargo_path.parent().map(PathBuf::from).unwrap_or_default();
+    let is_workspace_root = |dir: &std::path::Path| {
+        read_to_string(dir.join(CARGO_TOML))
+            .ok()
+            .and_then(|contents| contents.parse::<toml::Value>().ok())
+            .map_or(false, |manifest| manifest.get("workspace").is_some())
+    };
+    package_dir
+        .ancestors()
+        .find(|dir| is_workspace_root(dir))
+        .unwrap_or(&package_dir)
+        .join("target")
+}
+
+/// Returns the directory cargo writes the artifacts of a build to, e.g.
+/// `target/thumbv7em-none-eabi/release/`.
+///
+/// * `target_dir` - The target directory of the project
+/// * `target` - The target triple given to cargo, if any
+/// * `release` - If the build is in release mode
+pub fn profile_directory(target_dir: &PathBuf, target: Option<&str>, release: bool) -> PathBuf {
+    let mut path = target_dir.clone();
+    if let Some(target) = target {
+        path.push(target);
+    }
+    path.push(if release { "release" } else { "debug" });
+    path
+}
+
+/// Returns the path of the executable cargo builds for a bin or example target, e.g.
+/// `target/thumbv7em-none-eabi/release/examples/app`.
+///
+/// * `target_dir` - The target directory of the project
+/// * `target` - The target triple given to cargo, if any
+/// * `release` - If the build is in release mode
+/// * `name` - The name of the bin or example
+/// * `example` - If `name` is an example
+pub fn executable_path(
+    target_dir: &PathBuf,
+    target: Option<&str>,
+    release: bool,
+    name: &str,
+    example: bool,
+) -> PathBuf {
+    let mut path = profile_directory(target_dir, target, release);
+    if example {
+        path.push("examples");
+    }
+    path.push(name);
+    path
+}
+
+/// Reads the template file provided by RAUK for the given RTIC version
+fn read_rauk_patch_template(rtic_version: &str) -> Result<Manifest> {
+    let content = select_patch_template(rtic_version)?;
     let manifest: Manifest = toml::from_str(&content)?;
     Ok(manifest)
 }
 
 /// Patch the manifest with new dependencies, features and patches to crates.io.
+/// Patches are merged per crate, so the user's own patches are kept unless rauk
+/// needs to patch the same crate.
 fn patch_rauk_cargo_toml(manifest: &mut Manifest, patch: &Manifest) {
     for (name, dep) in patch.dependencies.iter() {
         manifest.dependencies.insert(name.clone(), dep.clone());
@@ -131,7 +450,291 @@ fn patch_rauk_cargo_toml(manifest: &mut Manifest, patch: &Manifest) {
         manifest.features.insert(name.clone(), features.clone());
     }
 
-    for (name, patch) in patch.patch.iter() {
-        manifest.patch.insert(name.clone(), patch.clone());
+    for (registry, patches) in patch.patch.iter() {
+        let user_patches = manifest.patch.entry(registry.clone()).or_default();
+        for (name, dep) in patches.iter() {
+            if user_patches.contains_key(name) {
+                warn!(
+                    "The project already patches {} in [patch.{}]. It will be replaced by the patch from rauk",
+                    name, registry
+                );
+            }
+            user_patches.insert(name.clone(), dep.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const V0_6: &str = include_str!("templates/v0_6.toml");
+
+    #[test]
+    fn test_select_patch_template_prerelease() {
+        assert_eq!(select_patch_template("0.6.0-alpha.4").unwrap(), V0_6);
+    }
+
+    #[test]
+    fn test_select_patch_template_requirement() {
+        assert_eq!(select_patch_template("^0.6").unwrap(), V0_6);
+        assert_eq!(select_patch_template("=0.6.0-rc.2").unwrap(), V0_6);
+    }
+
+    #[test]
+    fn test_select_patch_template_unsupported() {
+        assert!(select_patch_template("0.5.6").is_err());
+        assert!(select_patch_template("1.0.0").is_err());
+        assert!(select_patch_template("*").is_err());
+    }
+
+    #[test]
+    fn test_parse_locked_version() {
+        let lock = r#"
+[[package]]
+name = "cortex-m"
+version = "0.7.2"
+
+[[package]]
+name = "cortex-m-rtic"
+version = "0.6.0-alpha.4"
+"#;
+        assert_eq!(
+            parse_locked_version(lock, RTIC_CRATE),
+            Some("0.6.0-alpha.4".to_string())
+        );
+        assert_eq!(parse_locked_version(lock, "vcell"), None);
+    }
+
+    #[test]
+    fn test_patch_keeps_user_patches() {
+        let user = r#"
+[package]
+name = "app"
+version = "0.1.0"
+
+[patch.crates-io]
+cortex-m = { git = "https://example.com/cortex-m.git" }
+stm32f4 = { git = "https://example.com/stm32f4.git" }
+"#;
+        let mut manifest: Manifest = toml::from_str(user).unwrap();
+        let template = read_rauk_patch_template("0.6").unwrap();
+        patch_rauk_cargo_toml(&mut manifest, &template);
+
+        let crates_io = manifest.patch.get("crates-io").unwrap();
+        let git = |name: &str| {
+            crates_io
+                .get(name)
+                .and_then(|dep| dep.detail())
+                .and_then(|detail| detail.git.clone())
+        };
+        assert_eq!(
+            git("stm32f4"),
+            Some("https://example.com/stm32f4.git".to_string())
+        );
+        assert_eq!(
+            git("cortex-m"),
+            Some("https://github.com/markhakansson/cortex-m.git".to_string())
+        );
+        assert!(crates_io.contains_key("cortex-m-rtic"));
+    }
+
+    #[test]
+    fn test_external_patch_template() {
+        let dir = std::env::temp_dir().join("rauk-cargo-patch-template");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        // No RTIC dependency, the version is not needed with an external template
+        write(
+            dir.join(CARGO_TOML),
+            "[package]\nname = \"app\"\nversion = \"0.1.0\"\n",
+        )
+        .unwrap();
+        let template_path = dir.join("template.toml");
+        write(
+            &template_path,
+            r#"
+[patch.crates-io]
+cortex-m = { git = "https://example.com/cortex-m.git" }
+"#,
+        )
+        .unwrap();
+
+        update_custom_cargo_toml(&dir, Some(&template_path)).unwrap();
+        let manifest = Manifest::from_path(dir.join(RAUK_CARGO_TOML)).unwrap();
+        let crates_io = manifest.patch.get("crates-io").unwrap();
+        assert_eq!(
+            crates_io
+                .get("cortex-m")
+                .and_then(|dep| dep.detail())
+                .and_then(|detail| detail.git.clone()),
+            Some("https://example.com/cortex-m.git".to_string())
+        );
+        // Nothing from the embedded template
+        assert!(!crates_io.contains_key("cortex-m-rtic"));
+
+        // The dumped default template is the embedded one
+        let dumped = dir.join("dumped.toml");
+        dump_patch_template("0.6", &dumped).unwrap();
+        assert_eq!(read_to_string(&dumped).unwrap(), V0_6);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_backup_restore_round_trip() {
+        let dir = std::env::temp_dir().join("rauk-cargo-round-trip");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let toml = b"[package]\r\nname = \"app\"\n\n".to_vec();
+        let lock = b"version = 3\n\n[[package]]\nname = \"app\"\n".to_vec();
+        write(dir.join(CARGO_TOML), &toml).unwrap();
+        write(dir.join(CARGO_LOCK), &lock).unwrap();
+
+        backup_original_cargo_files(&dir).unwrap();
+        write(dir.join(CARGO_TOML), "patched").unwrap();
+        write(dir.join(CARGO_LOCK), "patched").unwrap();
+        restore_orignal_cargo_files(&dir).unwrap();
+
+        assert_eq!(read(dir.join(CARGO_TOML)).unwrap(), toml);
+        assert_eq!(read(dir.join(CARGO_LOCK)).unwrap(), lock);
+        assert!(!dir.join("Cargo.lock.rauk_tmp").exists());
+        assert!(!dir.join(RAUK_PATCH_MARKER).exists());
+
+        // A backup that changed after it was made is not restored, and does not block
+        // later runs
+        backup_original_cargo_files(&dir).unwrap();
+        assert_eq!(
+            read_to_string(dir.join(RAUK_PATCH_MARKER)).unwrap(),
+            format!("{:016x}", stable_hash(&lock))
+        );
+        write(dir.join(CARGO_LOCK), "patched").unwrap();
+        write(dir.join(CARGO_LOCK_BACKUP), "corrupted").unwrap();
+        let err = restore_orignal_cargo_files(&dir).unwrap_err();
+        assert!(err.to_string().contains("changed since it was made"));
+        assert_eq!(read_to_string(dir.join(CARGO_LOCK)).unwrap(), "patched");
+        assert!(!dir.join(RAUK_PATCH_MARKER).exists());
+        assert!(!restore_interrupted_run(&dir).unwrap());
+        write(dir.join(CARGO_LOCK), &lock).unwrap();
+
+        // Without a Cargo.lock the one created while patched is removed
+        remove_file(dir.join(CARGO_LOCK)).unwrap();
+        backup_original_cargo_files(&dir).unwrap();
+        assert!(!dir.join(CARGO_LOCK_BACKUP).exists());
+        write(dir.join(CARGO_LOCK), "patched").unwrap();
+        restore_orignal_cargo_files(&dir).unwrap();
+        assert!(!dir.join(CARGO_LOCK).exists());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_custom_manifest_path() {
+        let dir = std::env::temp_dir().join("rauk-cargo-manifest-path");
+        let _ = std::fs::remove_dir_all(&dir);
+        let firmware = dir.join("firmware");
+        std::fs::create_dir_all(&firmware).unwrap();
+        write(firmware.join(CARGO_TOML), "original").unwrap();
+
+        let manifest = resolve_manifest_path(&dir, Some(&firmware.join(CARGO_TOML))).unwrap();
+        assert_eq!(manifest, canonicalize(firmware.join(CARGO_TOML)).unwrap());
+        assert_eq!(
+            resolve_manifest_path(&dir, None).unwrap(),
+            dir.join(CARGO_TOML)
+        );
+        assert!(resolve_manifest_path(&dir, Some(&firmware.join("app.toml"))).is_err());
+
+        // The backup is made next to the custom manifest
+        backup_original_cargo_files(&manifest.parent().unwrap().to_path_buf()).unwrap();
+        assert!(firmware.join(CARGO_TOML_BACKUP).exists());
+        assert!(!dir.join(CARGO_TOML_BACKUP).exists());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_restore_interrupted_run() {
+        let dir = std::env::temp_dir().join("rauk-cargo-interrupted");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        write(dir.join(CARGO_TOML), "patched").unwrap();
+        write(dir.join(CARGO_TOML_BACKUP), "original").unwrap();
+
+        // Not interrupted, nothing is restored
+        assert!(!restore_interrupted_run(&dir).unwrap());
+        assert_eq!(read_to_string(dir.join(CARGO_TOML)).unwrap(), "patched");
+
+        // Interrupted while patched
+        write(dir.join(RAUK_PATCH_MARKER), "").unwrap();
+        assert!(restore_interrupted_run(&dir).unwrap());
+        assert_eq!(read_to_string(dir.join(CARGO_TOML)).unwrap(), "original");
+        assert!(!dir.join(RAUK_PATCH_MARKER).exists());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_resolve_target_directory() {
+        let dir = std::env::temp_dir().join("rauk-cargo-target-dir");
+        let _ = std::fs::remove_dir_all(&dir);
+        let member = dir.join("firmware");
+        std::fs::create_dir_all(&member).unwrap();
+        std::fs::write(member.join(CARGO_TOML), "[package]\nname = \"firmware\"\n").unwrap();
+        let manifest = member.join(CARGO_TOML);
+        let resolve = |env_target_dir: Option<PathBuf>| {
+            resolve_target_directory_in(&manifest, true, env_target_dir).unwrap()
+        };
+
+        assert_eq!(resolve(None), member.join("target"));
+
+        // A workspace member builds into the target directory of the workspace
+        std::fs::write(
+            dir.join(CARGO_TOML),
+            "[workspace]\nmembers = [\"firmware\"]\n",
+        )
+        .unwrap();
+        let target_dir = resolve(None);
+        for (release, example, expected) in &[
+            (false, false, "target/debug/app"),
+            (true, false, "target/release/app"),
+            (false, true, "target/debug/examples/app"),
+            (true, true, "target/release/examples/app"),
+        ] {
+            assert_eq!(
+                executable_path(&target_dir, None, *release, "app", *example),
+                dir.join(expected)
+            );
+        }
+
+        // CARGO_TARGET_DIR takes precedence
+        let target_dir = resolve(Some(PathBuf::from("/tmp/out")));
+        assert_eq!(
+            executable_path(&target_dir, None, true, "app", true),
+            PathBuf::from("/tmp/out/release/examples/app")
+        );
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_executable_path() {
+        let target_dir = PathBuf::from("/project/target");
+        let target = Some("thumbv7em-none-eabi");
+
+        assert_eq!(
+            executable_path(&target_dir, None, false, "app", false),
+            PathBuf::from("/project/target/debug/app")
+        );
+        assert_eq!(
+            executable_path(&target_dir, None, true, "app", true),
+            PathBuf::from("/project/target/release/examples/app")
+        );
+        assert_eq!(
+            executable_path(&target_dir, target, false, "app", true),
+            PathBuf::from("/project/target/thumbv7em-none-eabi/debug/examples/app")
+        );
+        assert_eq!(
+            executable_path(&target_dir, target, true, "app", false),
+            PathBuf::from("/project/target/thumbv7em-none-eabi/release/app")
+        );
     }
 }
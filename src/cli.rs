@@ -1,5 +1,6 @@
 use serde::Deserialize;
 use std::path::PathBuf;
+use std::str::FromStr;
 use structopt::StructOpt;
 
 /// Cargo target details
@@ -14,6 +15,9 @@ pub struct BuildDetails {
     /// Build artifacts in release mode
     #[structopt(short, long)]
     pub release: bool,
+    /// Name of the package in a Cargo workspace that contains the target
+    #[structopt(long)]
+    pub package: Option<String>,
 }
 
 impl BuildDetails {
@@ -39,6 +43,53 @@ impl BuildDetails {
     }
 }
 
+/// How rauk connects to the debug probe
+#[derive(Debug, StructOpt, PartialEq, Clone, Deserialize)]
+pub struct ProbeOptions {
+    /// How many times to retry attaching to the probe if it is not ready.
+    #[structopt(long, default_value = "3")]
+    pub attach_retries: u32,
+    /// Milliseconds to wait before the first retry. Doubles after each retry.
+    #[structopt(long, default_value = "100")]
+    pub attach_delay_ms: u64,
+    /// The protocol used to talk to the target: swd or jtag. Default is the probe's default.
+    #[structopt(long)]
+    pub protocol: Option<Protocol>,
+    /// The clock speed of the protocol in kHz. Default is the probe's default.
+    #[structopt(long)]
+    pub speed_khz: Option<u32>,
+}
+
+impl Default for ProbeOptions {
+    fn default() -> Self {
+        ProbeOptions {
+            attach_retries: 3,
+            attach_delay_ms: 100,
+            protocol: None,
+            speed_khz: None,
+        }
+    }
+}
+
+/// The protocol the probe uses to talk to the target
+#[derive(Debug, PartialEq, Clone, Copy, Deserialize)]
+pub enum Protocol {
+    Swd,
+    Jtag,
+}
+
+impl FromStr for Protocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "swd" => Ok(Protocol::Swd),
+            "jtag" => Ok(Protocol::Jtag),
+            _ => Err(format!("Invalid protocol '{}'. Expected swd or jtag", s)),
+        }
+    }
+}
+
 #[derive(Debug, StructOpt)]
 pub struct CliOptions {
     /// Path to the RTIC project directory. Defaults to the current directory if not specified.
@@ -51,13 +102,59 @@ pub struct CliOptions {
     /// you don't have the correct dependencies/features set!
     #[structopt(long)]
     pub no_patch: bool,
-    /// Verbose output
-    #[structopt(short, long)]
-    pub verbose: bool,
+    /// Path to the Cargo.toml of the RTIC application, if it is not in the project
+    /// directory. This Cargo.toml is patched, built and restored.
+    #[structopt(long, parse(from_os_str))]
+    pub manifest_path: Option<PathBuf>,
+    /// Patch the project's Cargo.toml with this template instead of the one provided
+    /// by rauk. See `rauk dump-template`.
+    #[structopt(long, parse(from_os_str))]
+    pub patch_template: Option<PathBuf>,
+    /// Directory to write the metadata, logs, caches and results to instead of
+    /// target/rauk in the project directory.
+    #[structopt(long, parse(from_os_str))]
+    pub output_dir: Option<PathBuf>,
+    /// Keep the patched Cargo.toml and the generated .ll file after execution.
+    /// Run `rauk cleanup` afterwards to restore the project's Cargo files.
+    #[structopt(long)]
+    pub keep_artifacts: bool,
+    /// Verbose output. Repeat for more detail: -v info, -vv debug, -vvv trace
+    #[structopt(short, long, parse(from_occurrences))]
+    pub verbose: u8,
+    /// Only log errors and don't print the measured traces to stdout
+    #[structopt(short, long, conflicts_with = "verbose")]
+    pub quiet: bool,
+    /// Format of the records written to the log file: human or json
+    #[structopt(long, default_value = "human")]
+    pub log_format: LogFormat,
     #[structopt(subcommand)]
     pub cmd: Command,
 }
 
+/// Format of the records written to the log file
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum LogFormat {
+    /// Plain text lines
+    Human,
+    /// Newline-delimited JSON records
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "human" => Ok(LogFormat::Human),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!(
+                "Invalid log format '{}'. Expected human or json",
+                s
+            )),
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, StructOpt)]
 pub enum Command {
     /// Generate test vectors using KLEE
@@ -67,7 +164,23 @@ pub enum Command {
     /// WCET measure for each task using the test vectors on the replay harness
     Measure(MeasureInput),
     /// Removes all metadata generated by rauk from project directory
-    Cleanup,
+    Cleanup(CleanupInput),
+    /// Inspect the rauk settings file without running any analysis
+    Config(ConfigCommand),
+    /// Compare the WCET of each task between two measurement runs
+    Diff(DiffInput),
+    /// List the chips known to probe-rs, as accepted by --chip
+    Chips(ChipsInput),
+    /// Print the objects, subprograms and subroutines found in the DWARF of a binary
+    Inspect(InspectInput),
+    /// Write the Cargo.toml patch template provided by rauk to a file for editing
+    DumpTemplate(DumpTemplateInput),
+}
+
+#[derive(Debug, PartialEq, StructOpt)]
+pub enum ConfigCommand {
+    /// Validate rauk.toml and print the resolved settings
+    Check,
 }
 
 /// Generate test vectors for an RTIC application
@@ -77,9 +190,31 @@ pub struct GenerateInput {
     pub build: BuildDetails,
     #[structopt(skip = false)]
     pub verbose: bool,
+    #[structopt(skip = false)]
+    pub keep_artifacts: bool,
     /// Emit all KLEE errors.
     #[structopt(long)]
     pub emit_all_errors: bool,
+    /// The cargo feature that enables the test harness. Default "klee-analysis".
+    #[structopt(long)]
+    pub analysis_feature: Option<String>,
+    /// Extra argument passed verbatim to KLEE, e.g. `--klee-arg=--max-depth=100`.
+    /// Can be given multiple times.
+    #[structopt(long = "klee-arg", number_of_values = 1, allow_hyphen_values = true)]
+    pub klee_args: Vec<String>,
+    /// Halt KLEE after this many seconds. Not all paths might be explored.
+    #[structopt(long)]
+    pub klee_max_time: Option<u64>,
+    /// Name or path of the KLEE binary. Default "klee".
+    #[structopt(long)]
+    pub klee: Option<String>,
+    /// Print the test vectors of the last generate, with the name and size of each
+    /// object, instead of generating new ones.
+    #[structopt(long)]
+    pub list_tests: bool,
+    /// Use the already built test harness instead of building it.
+    #[structopt(long)]
+    pub no_build: bool,
 }
 
 impl GenerateInput {
@@ -104,6 +239,23 @@ pub struct FlashInput {
     /// How many seconds to wait for core to halt before panicking. Default 10s.
     #[structopt(short, long)]
     pub halt_timeout: Option<u64>,
+    /// The cargo feature that enables the replay harness. Default "klee-replay".
+    #[structopt(long)]
+    pub replay_feature: Option<String>,
+    /// Read back a sample of the flashed program and compare it with the binary.
+    #[structopt(long)]
+    pub verify: bool,
+    /// Flash the already built replay harness instead of building it.
+    #[structopt(long)]
+    pub no_build: bool,
+    /// How to reset the core after flashing: halt, hw or sw.
+    #[structopt(long, default_value = "halt")]
+    pub reset: ResetStrategy,
+    /// Index of the core running the RTIC application.
+    #[structopt(long, default_value = "0")]
+    pub core: usize,
+    #[structopt(flatten)]
+    pub probe: ProbeOptions,
 }
 
 impl FlashInput {
@@ -112,11 +264,130 @@ impl FlashInput {
     }
 }
 
+/// Removes the files rauk generated in the project directory
+#[derive(Debug, PartialEq, Clone, StructOpt)]
+pub struct CleanupInput {
+    /// Also remove the backups of Cargo.toml and Cargo.lock
+    #[structopt(long)]
+    pub all: bool,
+}
+
+/// Compares two measurement outputs
+#[derive(Debug, PartialEq, Clone, StructOpt)]
+pub struct DiffInput {
+    /// The output of the old measurement, e.g. a copy of target/rauk/rauk.json
+    #[structopt(parse(from_os_str))]
+    pub old: PathBuf,
+    /// The output of the new measurement
+    #[structopt(parse(from_os_str))]
+    pub new: PathBuf,
+    /// Fail if a task's worst-case duration increased by more than this many percent.
+    #[structopt(long, default_value = "0")]
+    pub threshold: f64,
+}
+
+/// Lists the chips known to probe-rs
+#[derive(Debug, PartialEq, Clone, StructOpt)]
+pub struct ChipsInput {
+    /// Only list chips whose name contains this, ignoring case
+    #[structopt(long)]
+    pub filter: Option<String>,
+}
+
+/// Writes a patch template provided by rauk to disk
+#[derive(Debug, PartialEq, Clone, StructOpt)]
+pub struct DumpTemplateInput {
+    /// The file to write the template to
+    #[structopt(parse(from_os_str), default_value = "rauk_template.toml")]
+    pub output: PathBuf,
+    /// The RTIC version to write the template for
+    #[structopt(long, default_value = "0.6")]
+    pub rtic_version: String,
+}
+
+/// Prints what rauk finds in the DWARF of a binary
+#[derive(Debug, PartialEq, Clone, StructOpt)]
+pub struct InspectInput {
+    /// The binary to inspect, e.g. the replay harness built by the flash command
+    #[structopt(parse(from_os_str))]
+    pub binary: PathBuf,
+    /// Output format: text or json
+    #[structopt(long, default_value = "text")]
+    pub format: OutputFormat,
+    /// Start address of the RAM, e.g. 0x2000_0000. Default 0x2000_0000.
+    #[structopt(long, parse(try_from_str = parse_address))]
+    pub ram_start: Option<u64>,
+}
+
+/// Format of the output printed to stdout
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum OutputFormat {
+    /// Human readable text
+    Text,
+    /// A single JSON document
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(format!(
+                "Invalid output format '{}'. Expected text or json",
+                s
+            )),
+        }
+    }
+}
+
+/// How the core is reset after flashing
+#[derive(Debug, PartialEq, Clone, Copy, Deserialize)]
+pub enum ResetStrategy {
+    /// Reset and halt the core via the debug interface. The core halts before the first
+    /// instruction of the firmware.
+    Halt,
+    /// Hardware reset by attaching while holding the nRST line, then halt. The firmware
+    /// may run between releasing nRST and the halt, so use it only if the other
+    /// strategies can't attach.
+    Hardware,
+    /// Software reset via SYSRESETREQ with the reset vector catch set. The core halts
+    /// before the first instruction of the firmware.
+    Software,
+}
+
+impl ResetStrategy {
+    /// Returns true if the probe should attach while holding the nRST line.
+    pub fn attach_under_reset(&self) -> bool {
+        *self == ResetStrategy::Hardware
+    }
+}
+
+impl FromStr for ResetStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "halt" => Ok(ResetStrategy::Halt),
+            "hw" => Ok(ResetStrategy::Hardware),
+            "sw" => Ok(ResetStrategy::Software),
+            _ => Err(format!(
+                "Invalid reset strategy '{}'. Expected halt, hw or sw",
+                s
+            )),
+        }
+    }
+}
+
 /// Measures WCET on hardware using the generated tests
 #[derive(Debug, PartialEq, Clone, StructOpt, Deserialize)]
 pub struct MeasureInput {
     #[structopt(flatten)]
     pub build: BuildDetails,
+    #[structopt(skip = false)]
+    pub quiet: bool,
     /// Path to DWARF.
     #[structopt(short, long, parse(from_os_str))]
     pub dwarf: Option<PathBuf>,
@@ -129,6 +400,83 @@ pub struct MeasureInput {
     /// How many seconds to wait for core to halt before panicking. Default 10s.
     #[structopt(short, long)]
     pub halt_timeout: Option<u64>,
+    /// Index of the core running the RTIC application.
+    #[structopt(long, default_value = "0")]
+    pub core: usize,
+    #[structopt(flatten)]
+    pub probe: ProbeOptions,
+    /// Print the progress of the measurement for each test vector.
+    #[structopt(long)]
+    pub progress: bool,
+    /// The counter used to measure clock cycles: dwt or systick.
+    #[structopt(long, default_value = "dwt")]
+    pub timer: Timer,
+    /// Start address of the RAM, e.g. 0x2000_0000. Variables located below it are not
+    /// written to. Default 0x2000_0000.
+    #[structopt(long, parse(try_from_str = parse_address))]
+    pub ram_start: Option<u64>,
+    /// Replay each test vector this many times and keep the maximum number of cycles
+    /// between each pair of consecutive breakpoints.
+    #[structopt(long, default_value = "1")]
+    pub samples: u32,
+    /// Fail if the name of a task or resource lock can't be resolved, instead of
+    /// naming it <unknown>.
+    #[structopt(long)]
+    pub strict_names: bool,
+    /// Only output the traces of this task. Can be given multiple times.
+    #[structopt(long = "task", number_of_values = 1)]
+    pub tasks: Vec<String>,
+    /// Name pattern of the subroutines that read a peripheral, where `*` matches any
+    /// characters, e.g. `volatile_register*read`. Can be given multiple times. Defaults
+    /// to vcell and read_volatile.
+    #[structopt(long = "vcell-pattern", number_of_values = 1)]
+    pub vcell_patterns: Vec<String>,
+    /// Name prefix of the KTest objects not to write to the target. Can be given multiple
+    /// times. Defaults to KLEE's bookkeeping objects such as `model_version`.
+    #[structopt(long = "ignore-object", number_of_values = 1)]
+    pub ignore_objects: Vec<String>,
+    /// Reset the target and skip the test vector if the core does not halt in time,
+    /// instead of stopping the measurement. The skipped test vectors are listed at the end.
+    #[structopt(long)]
+    pub recover: bool,
+    /// Add the traces to the existing rauk.json instead of overwriting it. Traces
+    /// already in the file are not added again.
+    #[structopt(long)]
+    pub append: bool,
+    /// Name or path of the llvm-objdump binary, e.g. `llvm-objdump-15`. Default "llvm-objdump".
+    #[structopt(long)]
+    pub objdump: Option<String>,
+    /// Append the PC, immediate and cycle count of every breakpoint to `breakpoints.log`
+    /// in the output directory.
+    #[structopt(long)]
+    pub trace_breakpoints: bool,
+}
+
+/// The counter that measures the clock cycles at each breakpoint
+#[derive(Debug, PartialEq, Clone, Copy, Deserialize)]
+pub enum Timer {
+    /// The DWT cycle counter (CYCCNT)
+    Dwt,
+    /// The SysTick timer, for cores without a DWT cycle counter such as the Cortex-M0+
+    SysTick,
+}
+
+impl Default for Timer {
+    fn default() -> Self {
+        Timer::Dwt
+    }
+}
+
+impl FromStr for Timer {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "dwt" => Ok(Timer::Dwt),
+            "systick" => Ok(Timer::SysTick),
+            _ => Err(format!("Invalid timer '{}'. Expected dwt or systick", s)),
+        }
+    }
 }
 
 impl MeasureInput {
@@ -145,6 +493,48 @@ impl MeasureInput {
     }
 }
 
+/// Parses a memory address given in hexadecimal with a `0x` prefix or in decimal.
+/// Underscores are ignored.
+fn parse_address(s: &str) -> Result<u64, String> {
+    let digits = s.replace('_', "");
+    let result = match digits.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => digits.parse::<u64>(),
+    };
+    result.map_err(|e| format!("Invalid address '{}': {}", s, e))
+}
+
 pub fn get_cli_opts() -> CliOptions {
     CliOptions::from_args()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reset_strategy_parsing() {
+        assert_eq!(ResetStrategy::from_str("halt"), Ok(ResetStrategy::Halt));
+        assert_eq!(ResetStrategy::from_str("hw"), Ok(ResetStrategy::Hardware));
+        assert_eq!(ResetStrategy::from_str("sw"), Ok(ResetStrategy::Software));
+        assert!(ResetStrategy::from_str("nrst").is_err());
+    }
+
+    #[test]
+    fn test_parse_address() {
+        assert_eq!(parse_address("0x2000_0000"), Ok(0x2000_0000));
+        assert_eq!(parse_address("0x1fff0000"), Ok(0x1fff_0000));
+        assert_eq!(parse_address("4096"), Ok(4096));
+        assert!(parse_address("0xnope").is_err());
+    }
+
+    #[test]
+    fn test_reset_strategy_default_and_attach() {
+        let input = FlashInput::from_iter(&["flash", "--bin", "app"]);
+        assert_eq!(input.reset, ResetStrategy::Halt);
+        assert!(!input.reset.attach_under_reset());
+
+        let input = FlashInput::from_iter(&["flash", "--bin", "app", "--reset", "hw"]);
+        assert!(input.reset.attach_under_reset());
+    }
+}
@@ -1,94 +1,361 @@
-use crate::cli::FlashInput;
+use crate::cargo;
+use crate::cli::{FlashInput, ResetStrategy};
 use crate::metadata::RaukMetadata;
 use crate::settings::RaukSettings;
 use crate::utils::core as core_utils;
 use anyhow::{anyhow, Context, Result};
+use object::{Object, ObjectSection, SectionKind};
 use probe_rs::flashing::{download_file, Format};
+use probe_rs::{Core, MemoryInterface};
 use std::path::PathBuf;
-use std::process::{Command, ExitStatus};
+use std::process::Command;
 
-const DEFAULT_HALT_TIMEOUT_SECONDS: u64 = 5;
+pub(crate) const DEFAULT_HALT_TIMEOUT_SECONDS: u64 = 5;
+pub(crate) const DEFAULT_REPLAY_FEATURE: &str = "klee-replay";
+/// Number of bytes read back from the start of each program section when verifying
+const VERIFY_SAMPLE_BYTES: usize = 256;
 
-/// Builds the replay harness and flashes it to the target hardware.
-/// Returns the path to the built executable.
+/// Builds the replay harness and flashes it to the target hardware. With `--no-build`
+/// the already built replay harness is flashed. Returns the path to the executable.
 pub fn flash_to_target(
     input: &FlashInput,
     settings: &RaukSettings,
     metadata: &RaukMetadata,
 ) -> Result<PathBuf> {
-    let mut target_dir = metadata.project_directory.clone();
-    let mut cargo_path = metadata.project_directory.clone();
-    target_dir.push("target/");
-    cargo_path.push("Cargo.toml");
-
+    let mut cargo_path = metadata.cargo_manifest();
     let mut updated_input = input.clone();
     updated_input.get_missing_input(settings);
     let halt_timeout = updated_input
         .halt_timeout
         .unwrap_or(DEFAULT_HALT_TIMEOUT_SECONDS);
 
-    build_replay_harness(&updated_input, &mut cargo_path, &mut target_dir)
-        .context("Failed to build the replay harness")?;
-    let mut session = if let Some(chip) = updated_input.chip {
-        core_utils::open_and_attach_probe(&chip)?
-    } else {
-        return Err(anyhow!(
-            "Can't attach to hardware. No chip type given as input"
-        ));
+    // Checked before building so a typo does not fail after a long build
+    let chip = match &updated_input.chip {
+        Some(chip) => chip,
+        None => {
+            return Err(anyhow!(
+                "Can't attach to hardware. No chip type given as input"
+            ))
+        }
     };
+    core_utils::validate_chip(chip)?;
+
+    let target_dir = cargo::resolve_target_directory(&cargo_path, updated_input.no_build)?;
+
+    if let Some(mut build) = replay_harness_command(&updated_input, &mut cargo_path) {
+        let status = build
+            .status()
+            .context("Failed to build the replay harness")?;
+
+        if !status.success() {
+            return Err(anyhow!("Failed to build the replay harness"));
+        }
+    } else {
+        info!("Skipping build of the replay harness");
+    }
+    let binary_path = resolve_binary_path(
+        &replay_harness_path(&updated_input, &target_dir),
+        updated_input.no_build,
+    )?;
+    info!("Flashing {:?}", &binary_path);
+    let mut session = core_utils::open_and_attach_probe(chip, false, &updated_input.probe)?;
 
     // Flash the card with binary
-    download_file(&mut session, &target_dir.as_path(), Format::Elf)
+    download_file(&mut session, binary_path.as_path(), Format::Elf)
         .context("Could not flash replay harness to hardware")?;
 
+    // A hardware reset is done by attaching again while holding the nRST line
+    if updated_input.reset.attach_under_reset() {
+        drop(session);
+        session = core_utils::open_and_attach_probe(chip, true, &updated_input.probe)?;
+    }
+
     // Reset the core and halt
-    let mut core = session.core(0)?;
-    core.reset_and_halt(std::time::Duration::from_secs(halt_timeout))?;
+    let mut core = core_utils::attach_core(&mut session, updated_input.core)?;
+    let timeout = std::time::Duration::from_secs(halt_timeout);
+    match updated_input.reset {
+        ResetStrategy::Halt => {
+            core.reset_and_halt(timeout)?;
+        }
+        ResetStrategy::Software => {
+            core_utils::software_reset_and_halt(&mut core, timeout)?;
+        }
+        ResetStrategy::Hardware => {
+            core.halt(timeout)?;
+        }
+    }
 
-    Ok(target_dir)
+    if updated_input.verify {
+        verify_flash(&mut core, &binary_path)
+            .context("Could not verify the flashed replay harness")?;
+        info!("Flashed replay harness verified");
+    }
+
+    Ok(binary_path)
 }
 
-/// Builds the replay harness by setting the correct features for all patched
-/// crates.
-fn build_replay_harness(
-    input: &FlashInput,
-    cargo_path: &mut PathBuf,
-    target_dir: &mut PathBuf,
-) -> Result<ExitStatus, std::io::Error> {
+/// Returns the absolute path of the replay harness executable. Errors with the attempted
+/// path if there is no executable there.
+///
+/// * `path` - The path the executable is expected at
+/// * `no_build` - If the replay harness was not built by rauk
+fn resolve_binary_path(path: &PathBuf, no_build: bool) -> Result<PathBuf> {
+    if !path.is_file() {
+        if no_build {
+            return Err(anyhow!(
+                "No replay harness found at {:?}. Build it or flash without --no-build",
+                path
+            ));
+        }
+        return Err(anyhow!("No replay harness found at {:?}", path));
+    }
+    path.canonicalize()
+        .with_context(|| format!("Could not resolve the path {:?}", path))
+}
+
+/// Reads back the start of each program section from the target and compares it
+/// with the binary.
+fn verify_flash(core: &mut Core, binary_path: &PathBuf) -> Result<()> {
+    let data = std::fs::read(binary_path)?;
+    let binary = object::File::parse(&*data)?;
+
+    for section in binary.sections() {
+        match section.kind() {
+            SectionKind::Text | SectionKind::ReadOnlyData => (),
+            _ => continue,
+        }
+
+        let expected = section.data()?;
+        let len = expected.len().min(VERIFY_SAMPLE_BYTES);
+        if len == 0 {
+            continue;
+        }
+
+        let mut actual = vec![0u8; len];
+        core.read_8(section.address() as u32, &mut actual)?;
+        compare_flash_sample(section.address(), &expected[..len], &actual).with_context(|| {
+            format!(
+                "Section {} does not match",
+                section.name().unwrap_or("<unknown>")
+            )
+        })?;
+    }
+
+    Ok(())
+}
+
+/// Compares the expected bytes of the binary with the bytes read back from flash,
+/// starting at `address`.
+fn compare_flash_sample(address: u64, expected: &[u8], actual: &[u8]) -> Result<()> {
+    if expected.len() != actual.len() {
+        return Err(anyhow!(
+            "Expected {} bytes at address {:#x}, read {}",
+            expected.len(),
+            address,
+            actual.len()
+        ));
+    }
+
+    match expected.iter().zip(actual).position(|(e, a)| e != a) {
+        Some(offset) => Err(anyhow!(
+            "Flash mismatch at address {:#x}: expected {:#04x}, read {:#04x}",
+            address + offset as u64,
+            expected[offset],
+            actual[offset]
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Returns the path cargo builds the replay harness executable to.
+///
+/// * `input` - Input for the flash command
+/// * `target_dir` - The target directory of the project
+fn replay_harness_path(input: &FlashInput, target_dir: &PathBuf) -> PathBuf {
+    cargo::executable_path(
+        target_dir,
+        input.target.as_deref(),
+        input.is_release(),
+        input.build.get_name(),
+        input.build.is_example(),
+    )
+}
+
+/// Returns the cargo command that builds the replay harness by setting the correct
+/// features for all patched crates. Returns `None` with `--no-build`.
+fn replay_harness_command(input: &FlashInput, cargo_path: &mut PathBuf) -> Option<Command> {
+    if input.no_build {
+        return None;
+    }
+
     let mut cargo = Command::new("cargo");
     cargo.arg("build");
 
-    if input.target.is_some() {
-        let target = input.target.clone().unwrap();
+    if let Some(target) = &input.target {
         cargo.args(&["--target", target.as_str()]);
-        target_dir.push(target);
     }
 
     if input.is_release() {
         cargo.arg("--release");
-        target_dir.push("release/");
-    } else {
-        target_dir.push("debug/");
+    }
+
+    if let Some(package) = &input.build.package {
+        cargo.args(&["-p", package.as_str()]);
     }
 
     if input.verbose {
         cargo.arg("--verbose");
     }
 
-    let name: String;
-    if input.build.example.is_none() {
-        name = input.build.bin.as_ref().unwrap().to_string();
-        cargo.args(&["--bin", name.as_str()]);
+    if input.build.is_example() {
+        cargo.args(&["--example", input.build.get_name()]);
     } else {
-        name = input.build.example.as_ref().unwrap().to_string();
-        cargo.args(&["--example", name.as_str()]);
-        target_dir.push("examples/");
+        cargo.args(&["--bin", input.build.get_name()]);
     }
-    target_dir.push(name);
+
+    let feature = input
+        .replay_feature
+        .as_deref()
+        .unwrap_or(DEFAULT_REPLAY_FEATURE);
 
     cargo
-        .args(&["--features", "klee-replay"])
+        .args(&["--features", feature])
         .args(&["--manifest-path", cargo_path.to_str().unwrap()]);
 
-    cargo.status()
+    Some(cargo)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use structopt::StructOpt;
+
+    #[test]
+    fn test_replay_harness_custom_manifest_path() {
+        let mut metadata = RaukMetadata::new(&PathBuf::from("/project"));
+        metadata.manifest_path = Some(PathBuf::from("/project/firmware/Cargo.toml"));
+        let input = FlashInput::from_iter(&["flash", "--bin", "app"]);
+
+        let target_dir = metadata.cargo_directory().join("target/");
+        let command = replay_harness_command(&input, &mut metadata.cargo_manifest()).unwrap();
+        let args: Vec<String> = command
+            .get_args()
+            .map(|arg| arg.to_string_lossy().to_string())
+            .collect();
+        assert!(args
+            .windows(2)
+            .any(|arg| arg == ["--manifest-path", "/project/firmware/Cargo.toml"]));
+        assert_eq!(
+            replay_harness_path(&input, &target_dir),
+            PathBuf::from("/project/firmware/target/debug/app")
+        );
+    }
+
+    #[test]
+    fn test_replay_harness_path() {
+        let path = |args: &[&str]| {
+            replay_harness_path(
+                &FlashInput::from_iter(args),
+                &PathBuf::from("/project/target/"),
+            )
+        };
+
+        assert_eq!(
+            path(&["flash", "--bin", "app"]),
+            PathBuf::from("/project/target/debug/app")
+        );
+        assert_eq!(
+            path(&["flash", "--example", "app"]),
+            PathBuf::from("/project/target/debug/examples/app")
+        );
+        assert_eq!(
+            path(&[
+                "flash",
+                "--example",
+                "app",
+                "--release",
+                "--target",
+                "thumbv7em-none-eabi"
+            ]),
+            PathBuf::from("/project/target/thumbv7em-none-eabi/release/examples/app")
+        );
+    }
+
+    #[test]
+    fn test_resolve_binary_path() {
+        let dir = std::env::temp_dir().join("rauk-flash-binary-path");
+        std::fs::create_dir_all(&dir).unwrap();
+        let binary = dir.join("app");
+        std::fs::write(&binary, "").unwrap();
+
+        let resolved = resolve_binary_path(&dir.join("../rauk-flash-binary-path/app"), false);
+        assert_eq!(resolved.unwrap(), binary.canonicalize().unwrap());
+
+        let missing = dir.join("examples/app");
+        let message = resolve_binary_path(&missing, false)
+            .unwrap_err()
+            .to_string();
+        assert!(message.contains(&format!("{:?}", missing)));
+    }
+
+    #[test]
+    fn test_unknown_chip_rejected_before_build() {
+        // The project does not exist, so building would fail with another error
+        let dir = std::env::temp_dir().join("rauk-flash-unknown-chip");
+        let input = FlashInput::from_iter(&["flash", "--bin", "app", "--chip", "NOT_A_CHIP_123"]);
+
+        let err =
+            flash_to_target(&input, &RaukSettings::new(), &RaukMetadata::new(&dir)).unwrap_err();
+        assert!(err.to_string().starts_with("Unknown chip 'NOT_A_CHIP_123'"));
+    }
+
+    #[test]
+    fn test_no_build_missing_replay_harness() {
+        let args = [
+            "flash",
+            "--bin",
+            "app",
+            "--chip",
+            "STM32F401RETx",
+            "--package",
+            "member",
+            "--no-build",
+        ];
+        let input = FlashInput::from_iter(&args);
+        assert!(replay_harness_command(&input, &mut PathBuf::from("Cargo.toml")).is_none());
+        assert!(replay_harness_command(
+            &FlashInput::from_iter(&args[..args.len() - 1]),
+            &mut PathBuf::from("Cargo.toml")
+        )
+        .is_some());
+
+        // The target directory of the package is found without `cargo metadata`, which
+        // would fail since the project has no Cargo.toml
+        let dir = std::env::temp_dir().join("rauk-flash-no-build");
+        let err =
+            flash_to_target(&input, &RaukSettings::new(), &RaukMetadata::new(&dir)).unwrap_err();
+        let message = err.to_string();
+        assert!(message.starts_with("No replay harness found at"));
+        assert!(message.contains("debug/app"));
+    }
+
+    #[test]
+    fn test_compare_flash_sample_matching() {
+        let expected = [0x00, 0x10, 0x00, 0x20];
+        assert!(compare_flash_sample(0x0800_0000, &expected, &expected).is_ok());
+    }
+
+    #[test]
+    fn test_compare_flash_sample_mismatch() {
+        let expected = [0x00, 0x10, 0x00, 0x20];
+        let actual = [0x00, 0x10, 0xff, 0x20];
+        let error = compare_flash_sample(0x0800_0000, &expected, &actual).unwrap_err();
+        assert!(error.to_string().contains("0x8000002"));
+    }
+
+    #[test]
+    fn test_compare_flash_sample_partial_read() {
+        let expected = [0x00, 0x10, 0x00, 0x20];
+        assert!(compare_flash_sample(0x0800_0000, &expected, &expected[..2]).is_err());
+    }
 }
@@ -1,79 +1,299 @@
+use crate::cargo;
 use crate::cli::GenerateInput;
-use crate::metadata::RaukMetadata;
+use crate::measure;
+use crate::metadata::{KleeStats, RaukMetadata};
+use crate::settings::RaukSettings;
+use crate::utils::command as command_utils;
 use anyhow::{anyhow, Context, Result};
 use glob::glob;
+use std::fs::{copy, read_dir, read_to_string};
 use std::path::PathBuf;
-use std::process::{Command, ExitStatus, Stdio};
+use std::process::{Command, Stdio};
+use std::time::SystemTime;
 
 const DEFAULT_KLEE_TARGET: &str = "x86_64-unknown-linux-gnu";
+pub(crate) const DEFAULT_ANALYSIS_FEATURE: &str = "klee-analysis";
+pub(crate) const DEFAULT_KLEE: &str = "klee";
+/// Message KLEE logs when it is halted by `--max-time`
+const KLEE_HALT_TIMER_MESSAGE: &str = "HaltTimer invoked";
+/// KLEE output files that contain its log messages
+const KLEE_LOG_FILES: [&str; 2] = ["messages.txt", "info"];
+/// KLEE output file with the statistics of the run
+const KLEE_INFO_FILE: &str = "info";
+/// KLEE output file with the coverage statistics of the run
+const KLEE_RUN_STATS_FILE: &str = "run.stats";
+/// Number of lines at the end of stderr included in the error of a failed command
+const STDERR_TAIL_LINES: usize = 20;
+/// How to install KLEE if it is not found
+const KLEE_INSTALL_HINT: &str =
+    "Build KLEE from source or install the klee package of your distribution, then make sure the `klee` binary can be found";
+
+/// Lists the test vectors the last generate produced for the artifact, with the name
+/// and size of each object.
+///
+/// * `input` - Input for this command
+/// * `metadata` - The metadata for Rauk
+pub fn list_klee_tests(input: &GenerateInput, metadata: &RaukMetadata) -> Result<String> {
+    let name = input.build.get_name();
+    let path = metadata
+        .get_artifact_detail(name, input.is_release(), input.build.is_example())
+        .and_then(|artifact| artifact.get_ktest_path())
+        .ok_or(anyhow!(
+            "No test vectors have been generated for '{}'. Run the generate command first",
+            name
+        ))?;
+    measure::list_ktest_files(&path)
+}
 
 /// Builds the test harness, then generates test vectors from it using KLEE.
 /// Returns the path to where KLEE generated its tests.
-pub fn generate_klee_tests(input: &GenerateInput, metadata: &RaukMetadata) -> Result<PathBuf> {
-    let mut target_dir = metadata.project_directory.clone();
-    let mut cargo_path = metadata.project_directory.clone();
-    let mut project_name: String = String::from("");
-    target_dir.push("target/");
-    cargo_path.push("Cargo.toml");
+pub fn generate_klee_tests(
+    input: &GenerateInput,
+    settings: &RaukSettings,
+    metadata: &RaukMetadata,
+) -> Result<PathBuf> {
+    let mut input = input.clone();
+    input.get_missing_input(settings);
+    let mut cargo_path = metadata.cargo_manifest();
+    let target_dir = cargo::resolve_target_directory(&cargo_path, input.no_build)?;
 
-    // Build the project
-    let status = build_test_harness(&input, &mut cargo_path, &mut target_dir, &mut project_name)
-        .context("Failed to build the test harness")?;
+    // Build the project, unless the already built test harness is used
+    let profile_dir =
+        cargo::profile_directory(&target_dir, Some(DEFAULT_KLEE_TARGET), input.is_release());
+    let mut build = test_harness_command(&input, &mut cargo_path);
+    if input.no_build {
+        info!(
+            "Skipping build, using the test harness in {:?}",
+            &profile_dir
+        );
+    } else {
+        let status = build.status().context("Failed to build the test harness")?;
 
-    if !status.success() {
-        return Err(anyhow!("Failed to build the test harness"));
+        if !status.success() {
+            return Err(anyhow!("Failed to build the test harness"));
+        }
     }
 
-    let ll = fetch_latest_ll_file(&mut target_dir, &mut project_name)
-        .context("Failed to retrieve the test harness' .ll file")?;
+    let ll = fetch_latest_ll_file(
+        &profile_dir,
+        input.build.get_name(),
+        input.build.is_example(),
+    )
+    .context("Failed to retrieve the test harness' .ll file")?;
+
+    if input.keep_artifacts {
+        let kept_ll = metadata
+            .rauk_output_directory
+            .join(format!("{}.ll", input.build.get_name()));
+        copy(&ll, &kept_ll).context("Failed to copy the .ll file to the output directory")?;
+        info!("Kept the test harness' .ll file at {:?}", &kept_ll);
+    }
 
     // Run KLEE
-    let mut klee = Command::new("klee");
+    let mut klee = klee_command(&input, &ll)?;
+    klee.stdout(Stdio::null());
+    let klee_program = input.klee.as_deref().unwrap_or(DEFAULT_KLEE);
+    run_with_captured_stderr(&mut klee, klee_program, KLEE_INSTALL_HINT)?;
+
+    // KLEE writes its output next to the .ll file
+    let mut klee_dir = ll
+        .parent()
+        .unwrap_or_else(|| profile_dir.as_path())
+        .to_path_buf();
+    klee_dir.push("klee-last/");
+
+    if input.klee_max_time.is_some() {
+        warn_if_klee_halted(&klee_dir)?;
+    }
+
+    Ok(klee_dir)
+}
+
+/// Reads the statistics of the KLEE run in `klee_dir` from its `info` file and, if it
+/// is in the text format, its `run.stats` file. The statistics are logged.
+pub fn read_klee_stats(klee_dir: &PathBuf) -> KleeStats {
+    let mut stats = read_to_string(klee_dir.join(KLEE_INFO_FILE))
+        .map(|info| parse_klee_info(&info))
+        .unwrap_or_default();
+
+    // Newer versions of KLEE write run.stats as an SQLite database, which is skipped
+    if let Ok(run_stats) = read_to_string(klee_dir.join(KLEE_RUN_STATS_FILE)) {
+        if let Some((covered, uncovered)) = parse_klee_run_stats(&run_stats) {
+            stats.covered_instructions = Some(covered);
+            stats.uncovered_instructions = Some(uncovered);
+        }
+    }
+
+    info!(
+        "KLEE completed {} paths and generated {} tests",
+        display_stat(stats.completed_paths),
+        display_stat(stats.generated_tests)
+    );
+    if let (Some(covered), Some(uncovered)) =
+        (stats.covered_instructions, stats.uncovered_instructions)
+    {
+        info!(
+            "KLEE covered {} of {} instructions",
+            covered,
+            covered + uncovered
+        );
+    }
+
+    stats
+}
+
+fn display_stat(stat: Option<u64>) -> String {
+    stat.map_or_else(|| "an unknown number of".to_string(), |n| n.to_string())
+}
+
+/// Parses the `KLEE: done: <stat> = <value>` lines of KLEE's `info` file.
+fn parse_klee_info(info: &str) -> KleeStats {
+    let mut stats = KleeStats::default();
+
+    for line in info.lines() {
+        let line = match line.trim().strip_prefix("KLEE: done:") {
+            Some(line) => line,
+            None => continue,
+        };
+        let mut split = line.splitn(2, '=');
+        let (key, value) = match (split.next(), split.next()) {
+            (Some(key), Some(value)) => (key.trim(), value.trim().parse::<u64>().ok()),
+            _ => continue,
+        };
+
+        match key {
+            "explored paths" => stats.explored_paths = value,
+            "completed paths" => stats.completed_paths = value,
+            "partially completed paths" => stats.partially_completed_paths = value,
+            "generated tests" => stats.generated_tests = value,
+            "total instructions" => stats.total_instructions = value,
+            _ => (),
+        }
+    }
+
+    stats
+}
+
+/// Parses the covered and uncovered instructions from the last row of a text `run.stats`
+/// file. The first line holds the column names and each following line a row of values.
+fn parse_klee_run_stats(run_stats: &str) -> Option<(u64, u64)> {
+    let split_row = |row: &str| -> Vec<String> {
+        row.trim()
+            .trim_start_matches('(')
+            .trim_end_matches(')')
+            .split(',')
+            .map(|column| column.trim().trim_matches('\'').to_string())
+            .collect()
+    };
+
+    let mut lines = run_stats.lines().filter(|line| !line.trim().is_empty());
+    let header = split_row(lines.next()?);
+    let last = split_row(lines.last()?);
+
+    let value = |name: &str| -> Option<u64> {
+        let index = header.iter().position(|column| column == name)?;
+        last.get(index)?.parse().ok()
+    };
+
+    Some((
+        value("CoveredInstructions")?,
+        value("UncoveredInstructions")?,
+    ))
+}
+
+/// Returns the KLEE command that generates tests for the `.ll` file.
+fn klee_command(input: &GenerateInput, ll: &PathBuf) -> Result<Command> {
+    let mut klee = Command::new(input.klee.as_deref().unwrap_or(DEFAULT_KLEE));
     if input.emit_all_errors {
         klee.arg("--emit-all-errors");
     }
+    if let Some(seconds) = input.klee_max_time {
+        klee.arg(format!("--max-time={}s", seconds));
+    }
+    for arg in &input.klee_args {
+        if !arg.starts_with('-') {
+            return Err(anyhow!(
+                "Invalid KLEE argument {:?}. KLEE options must start with '-'",
+                arg
+            ));
+        }
+        klee.arg(arg);
+    }
     klee.arg(ll);
-    klee.stdout(Stdio::null()).status()?;
+    Ok(klee)
+}
+
+/// Runs the command and captures its stderr. If the command fails the error contains
+/// the tail of the captured stderr. If the program `name` is not installed the error
+/// contains the `hint` on how to install it.
+fn run_with_captured_stderr(command: &mut Command, name: &str, hint: &str) -> Result<()> {
+    let output = command
+        .stderr(Stdio::piped())
+        .output()
+        .map_err(|e| command_utils::spawn_error(e, name, hint))?;
 
-    target_dir.push("klee-last/");
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let lines: Vec<&str> = stderr.lines().collect();
+        let tail = lines[lines.len().saturating_sub(STDERR_TAIL_LINES)..].join("\n");
+        return Err(anyhow!("{} failed with {}:\n{}", name, output.status, tail));
+    }
 
-    Ok(target_dir)
+    Ok(())
 }
 
-/// Builds the test harness.
-fn build_test_harness(
-    input: &GenerateInput,
-    cargo_path: &mut PathBuf,
-    target_dir: &mut PathBuf,
-    project_name: &mut String,
-) -> Result<ExitStatus, std::io::Error> {
+/// Warns if KLEE was halted by the max time before exploring all paths.
+fn warn_if_klee_halted(klee_dir: &PathBuf) -> Result<()> {
+    let halted = KLEE_LOG_FILES.iter().any(|file| {
+        read_to_string(klee_dir.join(file))
+            .map(|log| log.contains(KLEE_HALT_TIMER_MESSAGE))
+            .unwrap_or(false)
+    });
+
+    if halted {
+        let tests = read_dir(klee_dir)?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().extension().map_or(false, |ext| ext == "ktest"))
+            .count();
+        warn!(
+            "KLEE was halted by the max time after generating {} tests. Not all paths were explored",
+            tests
+        );
+    }
+
+    Ok(())
+}
+
+/// Returns the cargo command that builds the test harness.
+fn test_harness_command(input: &GenerateInput, cargo_path: &mut PathBuf) -> Command {
     let mut cargo = Command::new("cargo");
     cargo.arg("rustc");
-    target_dir.push(DEFAULT_KLEE_TARGET);
 
     if input.is_release() {
         cargo.arg("--release");
-        target_dir.push("release/");
-    } else {
-        target_dir.push("debug/");
     }
 
-    if input.build.example.is_none() {
-        *project_name = input.build.bin.as_ref().unwrap().to_string();
-        cargo.args(&["--bin", project_name]);
-        target_dir.push("deps/");
+    if input.build.is_example() {
+        cargo.args(&["--example", input.build.get_name()]);
     } else {
-        *project_name = input.build.example.as_ref().unwrap().to_string();
-        cargo.args(&["--example", project_name]);
-        target_dir.push("examples/");
+        cargo.args(&["--bin", input.build.get_name()]);
+    }
+
+    if let Some(package) = &input.build.package {
+        cargo.args(&["-p", package.as_str()]);
     }
 
     if input.verbose {
         cargo.arg("--verbose");
     }
 
+    let feature = input
+        .analysis_feature
+        .as_deref()
+        .unwrap_or(DEFAULT_ANALYSIS_FEATURE);
+
     cargo
-        .args(&["--features", "klee-analysis"])
+        .args(&["--features", feature])
         .args(&["--manifest-path", cargo_path.to_str().unwrap()])
         .args(&["--target", DEFAULT_KLEE_TARGET])
         .arg("--")
@@ -86,44 +306,245 @@ fn build_test_harness(
         // force panic=abort in all crates, override .cargo settings
         .env("RUSTFLAGS", "-C panic=abort");
 
-    cargo.status()
+    cargo
 }
 
-/// Returns the path of the latest accessed .ll file inside the given target directory.
-fn fetch_latest_ll_file(target_dir: &mut PathBuf, project_name: &mut String) -> Result<PathBuf> {
-    let target_dir_clone = target_dir.clone();
-    let target_dir_str = match target_dir_clone.to_str() {
-        Some(string) => string,
-        None => {
-            return Err(anyhow!(
-                "Could not convert directory {:?} to str",
-                target_dir
-            ))
-        }
-    };
+/// Returns the path of the most recently modified .ll file of the target. Only the
+/// directory of the target kind is searched, `deps/` for binaries and `examples/` for
+/// examples, and only files named `<name>-<hash>.ll` match. Subdirectories are searched
+/// if the directory itself has no match.
+///
+/// * `profile_dir` - The profile directory of the build
+/// * `name` - The name of the binary or example
+/// * `example` - If the target is an example
+fn fetch_latest_ll_file(profile_dir: &PathBuf, name: &str, example: bool) -> Result<PathBuf> {
+    let file_name = format!("{}-*.ll", name.replace("-", "_"));
+    let search_dir = profile_dir.join(if example { "examples" } else { "deps" });
 
-    let glob_path = target_dir_str.to_owned() + &project_name.replace("-", "_") + "*.ll";
-    let ll_glob = glob(glob_path.as_str()).context("Failed to read glob pattern")?;
-    let mut ll_opt = None;
-    for path in ll_glob {
-        match path {
-            Ok(p) => {
-                if ll_opt.is_none() {
-                    ll_opt = Some(p);
-                } else {
-                    let md = p.metadata()?;
-                    let ll_md = ll_opt.clone().unwrap().metadata()?;
-                    if ll_md.accessed()? > md.accessed()? {
-                        ll_opt = Some(p);
-                    }
-                }
+    for glob_path in &[
+        search_dir.join(&file_name),
+        search_dir.join("**").join(&file_name),
+    ] {
+        let glob_str = glob_path
+            .to_str()
+            .ok_or_else(|| anyhow!("Could not convert directory {:?} to str", &search_dir))?;
+        let mut latest: Option<(SystemTime, PathBuf)> = None;
+        for path in glob(glob_str)
+            .context("Failed to read glob pattern")?
+            .flatten()
+        {
+            let modified = path.metadata()?.modified()?;
+            if latest.as_ref().map_or(true, |(time, _)| modified > *time) {
+                latest = Some((modified, path));
             }
-            _ => (),
         }
+        if let Some((_, ll)) = latest {
+            return Ok(ll);
+        }
+    }
+
+    Err(anyhow!(
+        "No .ll files found for {}. Searched directory: {:?}",
+        name,
+        search_dir
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use structopt::StructOpt;
+
+    fn harness_args(args: &[&str]) -> Vec<String> {
+        let input = GenerateInput::from_iter(args);
+        let cargo = test_harness_command(&input, &mut PathBuf::from("Cargo.toml"));
+        cargo
+            .get_args()
+            .map(|arg| arg.to_string_lossy().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_harness_command_default_feature() {
+        let args = harness_args(&["generate", "--bin", "app"]);
+        let features = args.iter().position(|arg| arg == "--features").unwrap();
+        assert_eq!(args[features + 1], DEFAULT_ANALYSIS_FEATURE);
+    }
+
+    #[test]
+    fn test_harness_command_overridden_feature() {
+        let args = harness_args(&[
+            "generate",
+            "--bin",
+            "app",
+            "--analysis-feature",
+            "rauk-klee",
+        ]);
+        let features = args.iter().position(|arg| arg == "--features").unwrap();
+        assert_eq!(args[features + 1], "rauk-klee");
+    }
+
+    #[test]
+    fn test_harness_command_package() {
+        let args = harness_args(&["generate", "--bin", "app", "--package", "member"]);
+        let package = args.iter().position(|arg| arg == "-p").unwrap();
+        assert_eq!(args[package + 1], "member");
+
+        let args = harness_args(&["generate", "--bin", "app"]);
+        assert!(!args.iter().any(|arg| arg == "-p"));
+    }
+
+    #[test]
+    fn test_klee_command_extra_args() {
+        let input = GenerateInput::from_iter(&[
+            "generate",
+            "--bin",
+            "app",
+            "--klee-arg",
+            "--max-depth=10",
+            "--klee-arg=--optimize",
+        ]);
+        let klee = klee_command(&input, &PathBuf::from("app.ll")).unwrap();
+        let args: Vec<String> = klee
+            .get_args()
+            .map(|arg| arg.to_string_lossy().to_string())
+            .collect();
+        assert_eq!(args, vec!["--max-depth=10", "--optimize", "app.ll"]);
+    }
+
+    #[test]
+    fn test_klee_command_custom_binary() {
+        let input = GenerateInput::from_iter(&["generate", "--bin", "app"]);
+        let klee = klee_command(&input, &PathBuf::from("app.ll")).unwrap();
+        assert_eq!(klee.get_program(), DEFAULT_KLEE);
+
+        let input =
+            GenerateInput::from_iter(&["generate", "--bin", "app", "--klee", "/opt/klee/bin/klee"]);
+        let klee = klee_command(&input, &PathBuf::from("app.ll")).unwrap();
+        assert_eq!(klee.get_program(), "/opt/klee/bin/klee");
+    }
+
+    #[test]
+    fn test_klee_command_max_time() {
+        let input =
+            GenerateInput::from_iter(&["generate", "--bin", "app", "--klee-max-time", "30"]);
+        let klee = klee_command(&input, &PathBuf::from("app.ll")).unwrap();
+        let args: Vec<String> = klee
+            .get_args()
+            .map(|arg| arg.to_string_lossy().to_string())
+            .collect();
+        assert_eq!(args, vec!["--max-time=30s", "app.ll"]);
+    }
+
+    #[test]
+    fn test_failing_command_carries_stderr() {
+        let mut command = Command::new("sh");
+        command.args(&["-c", "echo first >&2; echo last >&2; exit 3"]);
+        let error = run_with_captured_stderr(&mut command, "sh", "").unwrap_err();
+        let message = error.to_string();
+        assert!(message.contains("first"));
+        assert!(message.contains("last"));
+    }
+
+    #[test]
+    fn test_missing_klee_gives_install_hint() {
+        let mut command = Command::new("rauk-klee-that-does-not-exist");
+        let error = run_with_captured_stderr(&mut command, "klee", KLEE_INSTALL_HINT).unwrap_err();
+        let message = error.to_string();
+        assert!(message.contains("Could not find `klee`"));
+        assert!(message.contains(KLEE_INSTALL_HINT));
+    }
+
+    #[test]
+    fn test_no_build_missing_test_harness() {
+        // Building would fail since the project has no Cargo.toml, so the error comes
+        // from the missing .ll file only if cargo is never run
+        let dir = std::env::temp_dir().join("rauk-generate-no-build");
+        let input = GenerateInput::from_iter(&["generate", "--bin", "app", "--no-build"]);
+
+        let err = generate_klee_tests(&input, &RaukSettings::new(), &RaukMetadata::new(&dir))
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Failed to retrieve the test harness' .ll file"
+        );
+    }
+
+    #[test]
+    fn test_fetch_latest_ll_file() {
+        let profile_dir = std::env::temp_dir().join("rauk-generate-ll");
+        let _ = std::fs::remove_dir_all(&profile_dir);
+        let deps = profile_dir.join("deps");
+        let examples = profile_dir.join("examples");
+        let nested = deps.join("nested");
+        std::fs::create_dir_all(&nested).unwrap();
+        std::fs::create_dir_all(&examples).unwrap();
+        let touch = |path: PathBuf| {
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            std::fs::write(path, "").unwrap();
+        };
+
+        touch(nested.join("my_app-cdef.ll"));
+        touch(deps.join("my_app-0123.ll"));
+        // Newer files of a sibling with a shared prefix and of an example
+        touch(deps.join("my_app_utils-4567.ll"));
+        touch(examples.join("my_app-89ab.ll"));
+
+        let ll = fetch_latest_ll_file(&profile_dir, "my-app", false).unwrap();
+        assert_eq!(ll, deps.join("my_app-0123.ll"));
+        let ll = fetch_latest_ll_file(&profile_dir, "my-app", true).unwrap();
+        assert_eq!(ll, examples.join("my_app-89ab.ll"));
+
+        // Subdirectories are searched if there is no match at the top
+        std::fs::remove_file(deps.join("my_app-0123.ll")).unwrap();
+        let ll = fetch_latest_ll_file(&profile_dir, "my-app", false).unwrap();
+        assert_eq!(ll, nested.join("my_app-cdef.ll"));
+
+        assert!(fetch_latest_ll_file(&profile_dir, "missing", false).is_err());
+        assert!(fetch_latest_ll_file(&profile_dir, "my_app_utils", true).is_err());
+
+        let _ = std::fs::remove_dir_all(&profile_dir);
+    }
+
+    #[test]
+    fn test_parse_klee_info() {
+        let info = "klee --emit-all-errors app.ll\n\
+                    PID: 4242\n\
+                    Started: 2021-05-04 10:00:00\n\
+                    KLEE: done: explored paths = 12\n\
+                    KLEE: done: total queries = 54\n\
+                    KLEE: done: total instructions = 3481\n\
+                    KLEE: done: completed paths = 11\n\
+                    KLEE: done: partially completed paths = 1\n\
+                    KLEE: done: generated tests = 11\n";
+        let stats = parse_klee_info(info);
+        assert_eq!(
+            stats,
+            KleeStats {
+                explored_paths: Some(12),
+                completed_paths: Some(11),
+                partially_completed_paths: Some(1),
+                generated_tests: Some(11),
+                total_instructions: Some(3481),
+                covered_instructions: None,
+                uncovered_instructions: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_klee_run_stats() {
+        let run_stats =
+            "('Instructions','FullBranches','CoveredInstructions','UncoveredInstructions',)\n\
+                         (120,2,100,40,)\n\
+                         (3481,8,412,37,)\n";
+        assert_eq!(parse_klee_run_stats(run_stats), Some((412, 37)));
+        assert_eq!(parse_klee_run_stats("SQLite format 3"), None);
     }
 
-    match ll_opt {
-        Some(ll) => Ok(ll),
-        None => Err(anyhow!("No .ll files found in directory {:?}", target_dir)),
+    #[test]
+    fn test_klee_command_rejects_path() {
+        let input = GenerateInput::from_iter(&["generate", "--bin", "app", "--klee-arg", "app.ll"]);
+        assert!(klee_command(&input, &PathBuf::from("app.ll")).is_err());
     }
 }
@@ -0,0 +1,20 @@
+//! RTIC Analysis Using KLEE
+//!
+//! Rauk can be used as a library through [`Rauk`], which runs the same commands as the
+//! command line tool but takes plain configuration structs as input.
+pub mod cargo;
+pub mod cli;
+pub mod flash;
+pub mod generate;
+pub mod logger;
+pub mod measure;
+pub mod metadata;
+pub mod settings;
+pub mod utils;
+
+mod api;
+
+#[macro_use]
+extern crate log;
+
+pub use api::{BuildConfig, FlashConfig, GenerateConfig, MeasureConfig, Rauk};
@@ -1,37 +1,259 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
+use chrono::prelude::Utc;
+use log::{Log, Metadata, Record};
 use simplelog::*;
 use std::fs::File;
+use std::io::Write;
 use std::path::PathBuf;
+use std::sync::Mutex;
 
-use crate::metadata;
+use crate::cli::LogFormat;
 
 pub const RAUK_LOG_FILE: &str = "rauk.log";
+/// The level of the records written to the log file. Warnings and errors, so the
+/// diagnostics of a run are kept even when they scrolled by in the terminal.
+pub const FILE_LOG_LEVEL: LevelFilter = LevelFilter::Warn;
 
-/// Initializes a terminal and file logger
-pub fn init_logger(project_dir: &PathBuf, verbose: bool) -> Result<()> {
-    let mut log_output = project_dir.clone();
-    log_output.push(metadata::RAUK_OUTPUT_DIR);
-    let _ = std::fs::create_dir_all(&log_output);
+/// Initializes a terminal and file logger. The log file is written to `output_dir`.
+pub fn init_logger(
+    output_dir: &PathBuf,
+    verbosity: u8,
+    quiet: bool,
+    format: LogFormat,
+) -> Result<()> {
+    let mut log_output = output_dir.clone();
+    std::fs::create_dir_all(&log_output)
+        .with_context(|| format!("Could not create the log file in {:?}", output_dir))?;
     log_output.push(RAUK_LOG_FILE);
 
-    let log_level = match verbose {
-        true => LevelFilter::Info,
-        false => LevelFilter::Warn,
-    };
+    let log_file = File::create(log_output)
+        .with_context(|| format!("Could not create the log file in {:?}", output_dir))?;
 
     CombinedLogger::init(vec![
-        TermLogger::new(
-            log_level,
-            Config::default(),
-            TerminalMode::Mixed,
-            ColorChoice::Auto,
-        ),
-        WriteLogger::new(
-            LevelFilter::Warn,
-            Config::default(),
-            File::create(log_output).unwrap(),
-        ),
+        terminal_logger(verbosity, quiet),
+        file_logger(format, log_file),
     ])?;
 
     Ok(())
 }
+
+/// Creates the human readable logger of the terminal, at the level of `terminal_level`.
+pub fn terminal_logger(verbosity: u8, quiet: bool) -> Box<dyn SharedLogger> {
+    TermLogger::new(
+        terminal_level(verbosity, quiet),
+        Config::default(),
+        TerminalMode::Mixed,
+        ColorChoice::Auto,
+    )
+}
+
+/// Creates the logger that writes the records of at least `FILE_LOG_LEVEL` to the
+/// log file in the given format.
+pub fn file_logger<W: Write + Send + 'static>(
+    format: LogFormat,
+    writable: W,
+) -> Box<dyn SharedLogger> {
+    match format {
+        LogFormat::Human => WriteLogger::new(FILE_LOG_LEVEL, Config::default(), writable),
+        LogFormat::Json => JsonLogger::new(FILE_LOG_LEVEL, writable),
+    }
+}
+
+/// Returns the terminal log level. Quiet overrides any verbosity
+pub fn terminal_level(verbosity: u8, quiet: bool) -> LevelFilter {
+    if quiet {
+        LevelFilter::Error
+    } else {
+        verbosity_level(verbosity)
+    }
+}
+
+/// Maps the number of `-v` flags to the terminal log level
+pub fn verbosity_level(verbosity: u8) -> LevelFilter {
+    match verbosity {
+        0 => LevelFilter::Warn,
+        1 => LevelFilter::Info,
+        2 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    }
+}
+
+/// Writes each log record as a single line of JSON
+pub struct JsonLogger<W: Write + Send + 'static> {
+    level: LevelFilter,
+    config: Config,
+    writable: Mutex<W>,
+}
+
+impl<W: Write + Send + 'static> JsonLogger<W> {
+    pub fn new(level: LevelFilter, writable: W) -> Box<JsonLogger<W>> {
+        Box::new(JsonLogger {
+            level,
+            config: Config::default(),
+            writable: Mutex::new(writable),
+        })
+    }
+}
+
+impl<W: Write + Send + 'static> Log for JsonLogger<W> {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        if self.enabled(record.metadata()) {
+            let mut writable = self.writable.lock().unwrap();
+            let _ = writeln!(writable, "{}", json_log_line(record));
+        }
+    }
+
+    fn flush(&self) {
+        let _ = self.writable.lock().unwrap().flush();
+    }
+}
+
+impl<W: Write + Send + 'static> SharedLogger for JsonLogger<W> {
+    fn level(&self) -> LevelFilter {
+        self.level
+    }
+
+    fn config(&self) -> Option<&Config> {
+        Some(&self.config)
+    }
+
+    fn as_log(self: Box<Self>) -> Box<dyn Log> {
+        Box::new(*self)
+    }
+}
+
+/// Formats a log record as a JSON object with level, target, message and timestamp
+fn json_log_line(record: &Record<'_>) -> String {
+    serde_json::json!({
+        "level": record.level().to_string(),
+        "target": record.target(),
+        "message": record.args().to_string(),
+        "timestamp": Utc::now().to_rfc3339(),
+    })
+    .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::Level;
+    use std::sync::Arc;
+
+    /// Writer that keeps everything written to it for inspection
+    #[derive(Clone, Default)]
+    struct CaptureBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for CaptureBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_quiet_suppresses_info() {
+        let info = Metadata::builder().level(Level::Info).build();
+        let error = Metadata::builder().level(Level::Error).build();
+
+        // The terminal logger init_logger installs
+        let terminal = terminal_logger(2, true);
+        assert_eq!(terminal.level(), LevelFilter::Error);
+        assert!(!terminal.enabled(&info));
+        assert!(terminal.enabled(&error));
+        assert!(terminal_logger(2, false).enabled(&info));
+
+        // Both formats filter at the quiet level
+        let human = CaptureBuffer::default();
+        let json = CaptureBuffer::default();
+        let level = terminal_level(2, true);
+        let loggers: Vec<Box<dyn SharedLogger>> = vec![
+            WriteLogger::new(level, Config::default(), human.clone()),
+            JsonLogger::new(level, json.clone()),
+        ];
+        for logger in &loggers {
+            logger.log(
+                &Record::builder()
+                    .level(Level::Info)
+                    .args(format_args!("Parsing ktests"))
+                    .build(),
+            );
+            logger.log(
+                &Record::builder()
+                    .level(Level::Error)
+                    .args(format_args!("Could not attach"))
+                    .build(),
+            );
+        }
+
+        for buffer in &[human, json] {
+            let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+            assert!(!output.contains("Parsing ktests"));
+            assert!(output.contains("Could not attach"));
+        }
+    }
+
+    #[test]
+    fn test_file_logger_keeps_warnings() {
+        for format in &[LogFormat::Human, LogFormat::Json] {
+            let buffer = CaptureBuffer::default();
+            let logger = file_logger(*format, buffer.clone());
+            assert!(logger.level() >= LevelFilter::Warn);
+
+            logger.log(
+                &Record::builder()
+                    .level(Level::Warn)
+                    .args(format_args!("Task 'uart0' was never measured"))
+                    .build(),
+            );
+            let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+            assert!(output.contains("Task 'uart0' was never measured"));
+        }
+    }
+
+    #[test]
+    fn test_init_logger_reports_unwritable_output_dir() {
+        // A file where the output directory should be can't hold the log file
+        let blocker = std::env::temp_dir().join("rauk-logger-output-file");
+        std::fs::write(&blocker, "").unwrap();
+        let output_dir = blocker.join("rauk");
+
+        let err = init_logger(&output_dir, 0, false, LogFormat::Human).unwrap_err();
+        assert!(err.to_string().contains("Could not create the log file"));
+
+        let _ = std::fs::remove_file(&blocker);
+    }
+
+    #[test]
+    fn test_verbosity_level() {
+        assert_eq!(verbosity_level(0), LevelFilter::Warn);
+        assert_eq!(verbosity_level(1), LevelFilter::Info);
+        assert_eq!(verbosity_level(2), LevelFilter::Debug);
+        assert_eq!(verbosity_level(3), LevelFilter::Trace);
+        assert_eq!(verbosity_level(7), LevelFilter::Trace);
+    }
+
+    #[test]
+    fn test_json_log_line_is_valid_json() {
+        let line = json_log_line(
+            &Record::builder()
+                .level(Level::Warn)
+                .target("rauk::flash")
+                .args(format_args!("Probe \"{}\" not found", "stlink"))
+                .build(),
+        );
+        assert!(!line.contains('\n'));
+
+        let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(value["level"], "WARN");
+        assert_eq!(value["target"], "rauk::flash");
+        assert_eq!(value["message"], "Probe \"stlink\" not found");
+        assert!(value["timestamp"].is_string());
+    }
+}
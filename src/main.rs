@@ -1,19 +1,11 @@
-mod cargo;
-mod cli;
-mod flash;
-mod generate;
-mod logger;
-mod measure;
-mod metadata;
-mod settings;
-mod utils;
-
 #[macro_use]
 extern crate log;
 use anyhow::{Context, Result};
-use cli::{CliOptions, Command};
-use metadata::RaukMetadata;
-use settings::RaukSettings;
+use rauk::cli::{self, CliOptions, Command, ConfigCommand, GenerateInput};
+use rauk::metadata::{self, RaukMetadata};
+use rauk::settings::{self, RaukSettings};
+use rauk::utils::core;
+use rauk::{cargo, flash, generate, logger, measure};
 use std::fs::{canonicalize, create_dir_all, remove_dir_all, remove_file};
 use std::os::unix::fs::symlink;
 use std::path::PathBuf;
@@ -25,29 +17,64 @@ fn main() -> Result<()> {
         None => canonicalize(PathBuf::from("./"))?,
     };
 
-    logger::init_logger(&project_dir, opts.verbose)?;
+    let output_dir = metadata::output_directory(&project_dir, opts.output_dir.as_ref());
+    logger::init_logger(&output_dir, opts.verbose, opts.quiet, opts.log_format)?;
+    let manifest_path = cargo::resolve_manifest_path(&project_dir, opts.manifest_path.as_ref())?;
+    // The directory of the Cargo.toml, where its backups and the rauk Cargo.toml are kept
+    let cargo_dir = match manifest_path.parent() {
+        Some(dir) => dir.to_path_buf(),
+        None => project_dir.clone(),
+    };
 
-    if opts.cmd == Command::Cleanup {
-        complete_rauk_cleanup(&project_dir)
+    if let Command::Cleanup(cleanup) = &opts.cmd {
+        complete_rauk_cleanup(&output_dir, &cargo_dir, cleanup.all)
+    } else if let Command::Config(config) = &opts.cmd {
+        execute_config_command(&project_dir, config, opts.no_patch)
+    } else if let Command::Diff(diff) = &opts.cmd {
+        measure::diff_measurements(diff)
+    } else if let Command::DumpTemplate(dump) = &opts.cmd {
+        cargo::dump_patch_template(&dump.rtic_version, &dump.output)?;
+        println!("Wrote the patch template to {:?}", &dump.output);
+        Ok(())
+    } else if let Command::Inspect(inspect) = &opts.cmd {
+        measure::inspect_binary(inspect)
+    } else if let Command::Generate(
+        generate @ GenerateInput {
+            list_tests: true, ..
+        },
+    ) = &opts.cmd
+    {
+        list_generated_tests(&project_dir, &output_dir, generate)
+    } else if let Command::Chips(chips) = &opts.cmd {
+        for chip in core::list_chips(chips.filter.as_deref())? {
+            println!("{}", chip);
+        }
+        Ok(())
     } else {
+        let settings = settings::load_settings(&project_dir)?;
+        opts.no_patch = settings.resolve_no_patch(opts.no_patch);
+
         // Handle SIGINT and SIGTERM
         let no_patch = opts.no_patch;
-        let project_dir_copy = project_dir.clone();
+        let keep_artifacts = opts.keep_artifacts;
+        let cargo_dir_copy = cargo_dir.clone();
         ctrlc::set_handler(move || {
-            post_execution_cleanup(&project_dir_copy, no_patch).unwrap();
+            post_execution_cleanup(&cargo_dir_copy, no_patch, keep_artifacts).unwrap();
         })?;
 
-        let _ = create_dir_all(&project_dir.join(metadata::RAUK_OUTPUT_DIR));
+        let _ = create_dir_all(&output_dir);
 
-        let settings = settings::load_settings(&project_dir)?;
-        let mut metadata = metadata::load_metadata(&project_dir)?;
+        cargo::restore_previous_run(&cargo_dir)?;
+
+        let mut metadata = metadata::load_metadata(&project_dir, &output_dir)?;
+        metadata.manifest_path = Some(manifest_path);
 
         // Patch the project's Cargo.toml
         if !opts.no_patch {
-            cargo::backup_original_cargo_files(&project_dir)?;
+            cargo::backup_original_cargo_files(&cargo_dir)?;
             info!("User Cargo files backed up");
-            cargo::update_custom_cargo_toml(&project_dir)?;
-            cargo::change_cargo_toml_to_custom(&project_dir)?;
+            cargo::update_custom_cargo_toml(&cargo_dir, opts.patch_template.as_ref())?;
+            cargo::change_cargo_toml_to_custom(&cargo_dir)?;
             info!("Custom Cargo.toml patched");
         }
 
@@ -55,7 +82,7 @@ fn main() -> Result<()> {
         let res = match_cli_opts(&mut opts, &settings, &mut metadata);
 
         // Cleanup and save metadata
-        post_execution_cleanup(&project_dir, opts.no_patch)?;
+        post_execution_cleanup(&cargo_dir, opts.no_patch, opts.keep_artifacts)?;
         metadata.program_execution_successful();
         metadata.save()?;
 
@@ -68,20 +95,38 @@ fn match_cli_opts(
     settings: &RaukSettings,
     metadata: &mut RaukMetadata,
 ) -> Result<()> {
-    // Inherit verbose flag from main cli opts
+    // Inherit verbose and quiet flags from main cli opts and fill in the missing
+    // input from the settings. This is done before executing the command since
+    // the settings can change which artifact the output is stored for.
     match &mut opts.cmd {
-        Command::Generate(g) => g.verbose = opts.verbose,
-        Command::Flash(f) => f.verbose = opts.verbose,
+        Command::Generate(g) => {
+            g.verbose = opts.verbose > 0;
+            g.keep_artifacts = opts.keep_artifacts;
+            settings::log_resolved_settings(&g.resolved_settings(settings));
+            g.get_missing_input(settings);
+        }
+        Command::Flash(f) => {
+            f.verbose = opts.verbose > 0;
+            settings::log_resolved_settings(&f.resolved_settings(settings));
+            f.get_missing_input(settings);
+        }
+        Command::Measure(m) => {
+            m.quiet = opts.quiet;
+            settings::log_resolved_settings(&m.resolved_settings(settings));
+            m.get_missing_input(settings);
+        }
         _ => (),
     }
 
     match &opts.cmd {
         Command::Generate(g) => {
             info!("Executing generate command");
-            let path = generate::generate_klee_tests(g, &metadata)
+            let path = generate::generate_klee_tests(g, &settings, &metadata)
                 .context("Failed to execute generate command")?;
             let _ = symlink(&path, &metadata.rauk_output_directory.join("klee-last"));
+            let stats = generate::read_klee_stats(&path);
             metadata.update_output(&g.build, Some(path), &opts.cmd)?;
+            metadata.update_klee_stats(&g.build, stats);
         }
         Command::Flash(f) => {
             info!("Executing flash command");
@@ -91,7 +136,7 @@ fn match_cli_opts(
         }
         Command::Measure(a) => {
             info!("Executing measure command");
-            let path = measure::wcet_measurement(a, &settings, &metadata)
+            let path = measure::wcet_measurement(a, &settings, metadata)
                 .context("Failed to execute analyze command")?;
             metadata.update_output(&a.build, path, &opts.cmd)?;
         }
@@ -101,23 +146,173 @@ fn match_cli_opts(
     Ok(())
 }
 
+/// Prints the test vectors of the last generate. Does not touch the project's files.
+fn list_generated_tests(
+    project_dir: &PathBuf,
+    output_dir: &PathBuf,
+    input: &GenerateInput,
+) -> Result<()> {
+    let settings = settings::load_settings(project_dir)?;
+    let mut input = input.clone();
+    input.get_missing_input(&settings);
+    let metadata = metadata::load_metadata(project_dir, output_dir)?;
+    print!("{}", generate::list_klee_tests(&input, &metadata)?);
+    Ok(())
+}
+
+/// Executes the config commands. These never touch the project's files.
+fn execute_config_command(
+    project_dir: &PathBuf,
+    config: &ConfigCommand,
+    no_patch: bool,
+) -> Result<()> {
+    match config {
+        ConfigCommand::Check => {
+            let resolved = settings::resolved_settings_report(project_dir, no_patch)
+                .context("Settings file is not valid")?;
+            print!("{}", resolved);
+        }
+    }
+    Ok(())
+}
+
 /// Cleanup before exiting the program
-fn post_execution_cleanup(project_dir: &PathBuf, no_patch: bool) -> Result<()> {
+///
+/// * `cargo_dir` - The directory of the patched Cargo.toml
+fn post_execution_cleanup(cargo_dir: &PathBuf, no_patch: bool, keep_artifacts: bool) -> Result<()> {
+    if keep_artifacts {
+        if !no_patch {
+            cargo::keep_patched_cargo_files(&cargo_dir)?;
+        }
+        info!("Keeping artifacts. Run `rauk cleanup` to restore the original Cargo files");
+        return Ok(());
+    }
+
     // Restore original Cargo.toml
     if !no_patch {
-        cargo::restore_orignal_cargo_files(&project_dir)?;
+        cargo::restore_orignal_cargo_files(&cargo_dir)?;
         info!("User Cargo files restored");
     }
 
     Ok(())
 }
 
-/// Manual cleanup procedure. Removes metadata only.
-fn complete_rauk_cleanup(project_dir: &PathBuf) -> Result<()> {
-    let rauk_cargo_toml = project_dir.join(cargo::RAUK_CARGO_TOML);
-    let rauk_output_path = metadata::get_rauk_output_path(&project_dir);
-    let _ = remove_dir_all(&rauk_output_path);
-    let _ = remove_file(&rauk_cargo_toml);
+/// Manual cleanup procedure. Restores the original Cargo files if they are still
+/// patched and removes metadata. With `all` the backups of the Cargo files are
+/// removed as well.
+fn complete_rauk_cleanup(output_dir: &PathBuf, cargo_dir: &PathBuf, all: bool) -> Result<()> {
+    if cargo::restore_interrupted_run(&cargo_dir)? {
+        info!("User Cargo files restored");
+    }
+    for path in remove_rauk_files(output_dir, cargo_dir, all) {
+        println!("Removed {:?}", path);
+    }
     info!("Completed cleanup procedure of rauk data");
     Ok(())
 }
+
+/// Removes the rauk output directory `output_dir`, which contains the metadata, caches
+/// and logs, and the rauk Cargo.toml next to the project's Cargo.toml in `cargo_dir`. With
+/// `all` the backups of the Cargo files are removed as well. Returns the paths that were
+/// removed.
+fn remove_rauk_files(output_dir: &PathBuf, cargo_dir: &PathBuf, all: bool) -> Vec<PathBuf> {
+    let mut removed: Vec<PathBuf> = Vec::new();
+
+    if remove_dir_all(&output_dir).is_ok() {
+        removed.push(output_dir.clone());
+    }
+
+    let mut files = vec![cargo_dir.join(cargo::RAUK_CARGO_TOML)];
+    if all {
+        files.push(cargo_dir.join(cargo::CARGO_TOML_BACKUP));
+        files.push(cargo_dir.join(cargo::CARGO_LOCK_BACKUP));
+    }
+    for file in files {
+        if remove_file(&file).is_ok() {
+            removed.push(file);
+        }
+    }
+
+    removed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{read_to_string, write};
+
+    #[test]
+    fn test_cleanup_skipped_when_keeping_artifacts() {
+        let dir = std::env::temp_dir().join("rauk-main-keep-artifacts");
+        let _ = remove_dir_all(&dir);
+        create_dir_all(&dir).unwrap();
+        let cargo_toml = dir.join("Cargo.toml");
+        write(&cargo_toml, "patched").unwrap();
+        write(dir.join(cargo::CARGO_TOML_BACKUP), "original").unwrap();
+
+        post_execution_cleanup(&dir, false, true).unwrap();
+        assert_eq!(read_to_string(&cargo_toml).unwrap(), "patched");
+
+        post_execution_cleanup(&dir, false, false).unwrap();
+        assert_eq!(read_to_string(&cargo_toml).unwrap(), "original");
+
+        let _ = remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_kept_artifacts_restored_by_next_run() {
+        let dir = std::env::temp_dir().join("rauk-main-keep-then-run");
+        let _ = remove_dir_all(&dir);
+        create_dir_all(&dir).unwrap();
+        let cargo_toml = dir.join("Cargo.toml");
+        write(&cargo_toml, "original").unwrap();
+        cargo::backup_original_cargo_files(&dir).unwrap();
+        write(&cargo_toml, "patched").unwrap();
+
+        // The run with --keep-artifacts leaves the Cargo files patched on purpose
+        post_execution_cleanup(&dir, false, true).unwrap();
+        assert_eq!(read_to_string(&cargo_toml).unwrap(), "patched");
+        assert!(dir.join(cargo::RAUK_KEPT_MARKER).exists());
+
+        // The next run restores them without taking them for an interrupted run
+        cargo::restore_previous_run(&dir).unwrap();
+        assert_eq!(read_to_string(&cargo_toml).unwrap(), "original");
+        assert!(!dir.join(cargo::RAUK_KEPT_MARKER).exists());
+        assert!(!dir.join(cargo::RAUK_PATCH_MARKER).exists());
+
+        let _ = remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_cleanup_all_removes_backups() {
+        let dir = std::env::temp_dir().join("rauk-main-cleanup-all");
+        let _ = remove_dir_all(&dir);
+        let output_dir = metadata::get_rauk_output_path(&dir);
+        create_dir_all(&output_dir).unwrap();
+        write(output_dir.join(metadata::RAUK_METADATA_FILE), "{}").unwrap();
+        write(output_dir.join(logger::RAUK_LOG_FILE), "").unwrap();
+        write(dir.join(cargo::RAUK_CARGO_TOML), "").unwrap();
+        write(dir.join(cargo::CARGO_TOML_BACKUP), "").unwrap();
+        write(dir.join(cargo::CARGO_LOCK_BACKUP), "").unwrap();
+
+        // Without --all the backups are kept
+        let removed = remove_rauk_files(&output_dir, &dir, false);
+        assert_eq!(
+            removed,
+            vec![output_dir.clone(), dir.join(cargo::RAUK_CARGO_TOML)]
+        );
+        assert!(dir.join(cargo::CARGO_TOML_BACKUP).exists());
+
+        let removed = remove_rauk_files(&output_dir, &dir, true);
+        assert_eq!(
+            removed,
+            vec![
+                dir.join(cargo::CARGO_TOML_BACKUP),
+                dir.join(cargo::CARGO_LOCK_BACKUP)
+            ]
+        );
+        assert!(!dir.join(cargo::CARGO_LOCK_BACKUP).exists());
+
+        let _ = remove_dir_all(&dir);
+    }
+}
@@ -1,3 +1,5 @@
+use std::fmt;
+
 /// Information about the breakpoint type for RAUK analysis
 #[derive(Debug, Clone, PartialEq)]
 pub enum Breakpoint {
@@ -13,6 +15,26 @@ impl Breakpoint {
             _ => false,
         }
     }
+
+    /// Returns the immediate value of the breakpoint instruction. The inverse of
+    /// `Breakpoint::from(u8)`.
+    pub fn value(&self) -> u8 {
+        match self {
+            Breakpoint::Other(o) => o.clone() as u8,
+            Breakpoint::Entry(e) => e.clone() as u8,
+            Breakpoint::Exit(e) => e.clone() as u8,
+        }
+    }
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Breakpoint::Other(o) => write!(f, "{:?}({})", o, self.value()),
+            Breakpoint::Entry(e) => write!(f, "{:?}({})", e, self.value()),
+            Breakpoint::Exit(e) => write!(f, "{:?}({})", e, self.value()),
+        }
+    }
 }
 
 /// The type of the entry breakpoint for a new scope.
@@ -38,7 +60,8 @@ pub enum OtherBreakpoint {
     Default = 0,
     /// Inside an rtic task #[task]
     InsideTask = 1,
-    /// Inside a hardware read via a vcell
+    /// Inside a hardware read via a vcell. Emitted by `get` and `as_ptr` of the patched
+    /// vcell crate with the `klee-replay` feature
     InsideHardwareRead = 5,
     /// Any other breakpoint will be labelled as invalid
     Invalid = 100,
@@ -66,3 +89,46 @@ impl From<u8> for Breakpoint {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_value_round_trip() {
+        let breakpoints = vec![
+            Breakpoint::Other(OtherBreakpoint::Default),
+            Breakpoint::Other(OtherBreakpoint::InsideTask),
+            Breakpoint::Other(OtherBreakpoint::InsideHardwareRead),
+            Breakpoint::Other(OtherBreakpoint::Invalid),
+            Breakpoint::Other(OtherBreakpoint::InsideLock),
+            Breakpoint::Other(OtherBreakpoint::ReplayStart),
+            Breakpoint::Entry(EntryBreakpoint::HardwareTaskStart),
+            Breakpoint::Entry(EntryBreakpoint::ResourceLockStart),
+            Breakpoint::Entry(EntryBreakpoint::SoftwareTaskStart),
+            Breakpoint::Exit(ExitBreakpoint::SoftwareTaskEnd),
+            Breakpoint::Exit(ExitBreakpoint::ResourceLockEnd),
+            Breakpoint::Exit(ExitBreakpoint::HardwareTaskEnd),
+        ];
+        for bkpt in breakpoints {
+            assert_eq!(Breakpoint::from(bkpt.value()), bkpt);
+        }
+    }
+
+    #[test]
+    fn test_hardware_read_breakpoint() {
+        // The immediate emitted by the patched vcell crate
+        assert_eq!(
+            Breakpoint::from(5),
+            Breakpoint::Other(OtherBreakpoint::InsideHardwareRead)
+        );
+    }
+
+    #[test]
+    fn test_display() {
+        let bkpt = Breakpoint::Entry(EntryBreakpoint::HardwareTaskStart);
+        assert_eq!(bkpt.to_string(), "HardwareTaskStart(2)");
+        let bkpt = Breakpoint::Other(OtherBreakpoint::ReplayStart);
+        assert_eq!(bkpt.to_string(), "ReplayStart(255)");
+    }
+}
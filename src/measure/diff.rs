@@ -0,0 +1,209 @@
+use super::trace::{Trace, TraceType};
+use crate::cli::DiffInput;
+use anyhow::{anyhow, Context, Result};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+
+/// The worst-case duration of a task in two measurement runs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TaskDiff {
+    /// The name of the task
+    pub name: String,
+    /// The worst-case duration in the old run, if the task was measured
+    pub old: Option<u64>,
+    /// The worst-case duration in the new run, if the task was measured
+    pub new: Option<u64>,
+}
+
+impl TaskDiff {
+    /// Returns the change of the worst-case duration in percent of the old duration.
+    /// Returns `None` unless the task was measured in both runs.
+    pub fn change_percent(&self) -> Option<f64> {
+        let (old, new) = (self.old?, self.new?);
+        let percent = if old == 0 {
+            if new == 0 {
+                0.0
+            } else {
+                f64::INFINITY
+            }
+        } else {
+            (new as f64 - old as f64) * 100.0 / old as f64
+        };
+        Some(percent)
+    }
+
+    /// Returns true if the worst-case duration increased by more than `threshold` percent.
+    pub fn is_regression(&self, threshold: f64) -> bool {
+        match (self.old, self.new, self.change_percent()) {
+            (Some(old), Some(new), Some(percent)) => new > old && percent > threshold,
+            _ => false,
+        }
+    }
+
+    /// Returns true if the worst-case duration decreased.
+    pub fn is_improvement(&self) -> bool {
+        match (self.old, self.new) {
+            (Some(old), Some(new)) => new < old,
+            _ => false,
+        }
+    }
+}
+
+/// Compares the WCET of two measurement runs and prints the change of each task.
+/// Returns an error if any task regressed by more than the threshold.
+///
+/// * `input` - Input for this command
+pub fn diff_measurements(input: &DiffInput) -> Result<()> {
+    let old = load_traces(&input.old)?;
+    let new = load_traces(&input.new)?;
+    let diffs = diff_traces(&old, &new)?;
+
+    for diff in &diffs {
+        println!("{}", diff_line(diff));
+        if diff.is_regression(input.threshold) {
+            warn!("Regression above {}%: {}", input.threshold, diff_line(diff));
+        }
+    }
+
+    let regressed = diffs
+        .iter()
+        .filter(|diff| diff.is_regression(input.threshold))
+        .count();
+    let improved = diffs.iter().filter(|diff| diff.is_improvement()).count();
+    println!(
+        "{} regressed, {} improved, {} unchanged or not comparable",
+        regressed,
+        improved,
+        diffs.len() - regressed - improved
+    );
+
+    if regressed > 0 {
+        return Err(anyhow!(
+            "{} task(s) regressed by more than {}%",
+            regressed,
+            input.threshold
+        ));
+    }
+    Ok(())
+}
+
+/// Matches the tasks of two measurement runs by name and returns the worst-case
+/// duration of each task in both runs.
+///
+/// * `old` - The traces of the old run
+/// * `new` - The traces of the new run
+pub fn diff_traces(old: &[Trace], new: &[Trace]) -> Result<Vec<TaskDiff>> {
+    let old = worst_case_durations(old)?;
+    let new = worst_case_durations(new)?;
+
+    let mut names: Vec<&String> = old.keys().chain(new.keys()).collect();
+    names.sort();
+    names.dedup();
+
+    Ok(names
+        .into_iter()
+        .map(|name| TaskDiff {
+            name: name.clone(),
+            old: old.get(name).cloned(),
+            new: new.get(name).cloned(),
+        })
+        .collect())
+}
+
+/// Returns the longest duration of each task in the traces, including tasks that
+/// preempted another task.
+fn worst_case_durations(traces: &[Trace]) -> Result<BTreeMap<String, u64>> {
+    let mut durations: BTreeMap<String, u64> = BTreeMap::new();
+    collect_durations(traces, &mut durations)?;
+    Ok(durations)
+}
+
+fn collect_durations(traces: &[Trace], durations: &mut BTreeMap<String, u64>) -> Result<()> {
+    for trace in traces {
+        if trace.ttype != TraceType::ResourceLock {
+            let duration = trace.duration()?;
+            let worst = durations.entry(trace.name.clone()).or_insert(0);
+            *worst = (*worst).max(duration);
+        }
+        collect_durations(&trace.inner, durations)?;
+    }
+    Ok(())
+}
+
+/// Formats the change of a single task.
+fn diff_line(diff: &TaskDiff) -> String {
+    match (diff.old, diff.new, diff.change_percent()) {
+        (Some(old), Some(new), Some(percent)) => format!(
+            "{}: {} -> {} cycles ({:+}, {:+.2}%)",
+            diff.name,
+            old,
+            new,
+            new as i128 - old as i128,
+            percent
+        ),
+        (Some(old), None, _) => format!("{}: {} cycles, not measured in new run", diff.name, old),
+        (None, Some(new), _) => format!("{}: {} cycles, not measured in old run", diff.name, new),
+        _ => format!("{}: not measured", diff.name),
+    }
+}
+
+/// Reads the traces stored by the measure command.
+fn load_traces(path: &PathBuf) -> Result<Vec<Trace>> {
+    let data = fs::read_to_string(path)
+        .with_context(|| format!("Could not read traces from {:?}", path))?;
+    serde_json::from_str(&data).with_context(|| format!("Could not parse traces in {:?}", path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn task(name: &str, start: u64, inner: Vec<Trace>, end: u64) -> Trace {
+        Trace {
+            name: name.to_string(),
+            ttype: TraceType::HardwareTask,
+            start,
+            inner,
+            end,
+        }
+    }
+
+    #[test]
+    fn test_diff_traces_with_regression() {
+        let old = vec![
+            task("task1", 0, vec![], 100),
+            task("task1", 200, vec![], 320),
+            task("task2", 0, vec![task("uart0", 10, vec![], 60)], 200),
+        ];
+        let new = vec![
+            task("task1", 0, vec![], 150),
+            task("task2", 0, vec![task("uart0", 10, vec![], 40)], 200),
+            task("task3", 0, vec![], 80),
+        ];
+
+        let diffs = diff_traces(&old, &new).unwrap();
+        let names: Vec<&str> = diffs.iter().map(|diff| diff.name.as_str()).collect();
+        assert_eq!(names, vec!["task1", "task2", "task3", "uart0"]);
+
+        // task1 went from 120 to 150 cycles, 25% slower
+        assert_eq!(diffs[0].old, Some(120));
+        assert_eq!(diffs[0].new, Some(150));
+        assert_eq!(diffs[0].change_percent(), Some(25.0));
+        assert!(diffs[0].is_regression(10.0));
+        assert!(!diffs[0].is_regression(30.0));
+
+        assert!(!diffs[1].is_regression(0.0));
+        assert!(!diffs[1].is_improvement());
+
+        // task3 is only in the new run and cannot be compared
+        assert_eq!(diffs[2].old, None);
+        assert!(!diffs[2].is_regression(0.0));
+
+        assert!(diffs[3].is_improvement());
+        assert_eq!(
+            diff_line(&diffs[0]),
+            "task1: 120 -> 150 cycles (+30, +25.00%)"
+        );
+    }
+}
@@ -0,0 +1,136 @@
+use super::types::{ObjectLocationMap, Subprogram, Subroutine};
+use crate::utils::hash::stable_hash;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+
+pub const DWARF_CACHE_FILE: &str = "dwarf_cache.json";
+/// The version of rauk that wrote the cache. A cache written by another version may
+/// have been parsed differently and is parsed again
+pub const DWARF_CACHE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// The parsed DWARF of a binary. Stored between runs so the DWARF only has to be
+/// parsed again when the binary changes.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DwarfCache {
+    /// The version of rauk that wrote the cache
+    #[serde(default)]
+    pub version: String,
+    /// Hash of the binary the DWARF was parsed from
+    pub hash: u64,
+    /// The start address of the RAM used to find the variables
+    #[serde(default)]
+    pub ram_start: u64,
+    /// A map of the variables stored in flash
+    pub variables: ObjectLocationMap,
+    /// A list of all subprograms
+    pub subprograms: Vec<Subprogram>,
+    /// A list of all inlined subroutines
+    pub subroutines: Vec<Subroutine>,
+}
+
+/// Hashes the contents of a binary. The hash is stable across builds of rauk, so it
+/// can be compared with the one stored in the cache.
+pub fn binary_hash(bytes: &[u8]) -> u64 {
+    stable_hash(bytes)
+}
+
+/// Loads the cache at `path` if it was written by this version of rauk and parsed from a
+/// binary with the given hash and with the same RAM start address. Returns `None` if the
+/// cache is missing, unreadable or stale.
+pub fn load_cache(path: &PathBuf, hash: u64, ram_start: u64) -> Option<DwarfCache> {
+    let data = fs::read_to_string(path).ok()?;
+    let cache: DwarfCache = serde_json::from_str(&data).ok()?;
+    if cache.version == DWARF_CACHE_VERSION && cache.hash == hash && cache.ram_start == ram_start {
+        Some(cache)
+    } else {
+        None
+    }
+}
+
+/// Saves the cache to `path`.
+pub fn save_cache(path: &PathBuf, cache: &DwarfCache) -> Result<()> {
+    let data = serde_json::to_string(cache).context("Failed to serialize DWARF cache")?;
+    fs::write(path, data).with_context(|| format!("Failed to write DWARF cache to {:?}", path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache_for(bytes: &[u8]) -> DwarfCache {
+        let mut variables = ObjectLocationMap::new();
+        variables.insert("app::resources::res1".to_string(), Some(0x2000_0000));
+        DwarfCache {
+            version: DWARF_CACHE_VERSION.to_string(),
+            hash: binary_hash(bytes),
+            ram_start: 0x2000_0000,
+            variables,
+            subprograms: vec![Subprogram {
+                name: "task1".to_string(),
+                linkage_name: "app::task1".to_string(),
+                ranges: vec![(0x100, 0x120)],
+            }],
+            subroutines: vec![Subroutine {
+                name: "vcell::VolatileCell<T>::get".to_string(),
+                ranges: vec![(0x104, 0x108)],
+            }],
+        }
+    }
+
+    #[test]
+    fn test_cache_hit_on_identical_binary() {
+        let dir = std::env::temp_dir().join("rauk-dwarf-cache-hit");
+        let _ = fs::create_dir_all(&dir);
+        let path = dir.join(DWARF_CACHE_FILE);
+
+        let binary = vec![0x7f, b'E', b'L', b'F', 1, 2, 3];
+        let cache = cache_for(&binary);
+        save_cache(&path, &cache).unwrap();
+
+        let same_binary = binary.clone();
+        assert_eq!(
+            load_cache(&path, binary_hash(&same_binary), 0x2000_0000),
+            Some(cache)
+        );
+        assert_eq!(
+            load_cache(&path, binary_hash(&same_binary), 0x1000_0000),
+            None
+        );
+
+        let changed_binary = vec![0x7f, b'E', b'L', b'F', 1, 2, 4];
+        assert_eq!(
+            load_cache(&path, binary_hash(&changed_binary), 0x2000_0000),
+            None
+        );
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_cache_miss_on_other_version() {
+        let dir = std::env::temp_dir().join("rauk-dwarf-cache-version");
+        let _ = fs::create_dir_all(&dir);
+        let path = dir.join(DWARF_CACHE_FILE);
+
+        let binary = vec![0x7f, b'E', b'L', b'F', 1, 2, 3];
+        let mut cache = cache_for(&binary);
+        cache.version = "0.0.0".to_string();
+        save_cache(&path, &cache).unwrap();
+        assert_eq!(load_cache(&path, binary_hash(&binary), 0x2000_0000), None);
+
+        // Caches written before the version was stored are parsed again as well
+        let mut old = serde_json::to_value(&cache).unwrap();
+        old.as_object_mut().unwrap().remove("version");
+        fs::write(&path, old.to_string()).unwrap();
+        assert_eq!(load_cache(&path, binary_hash(&binary), 0x2000_0000), None);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_binary_hash_is_stable() {
+        assert_eq!(binary_hash(b"foobar"), 0x8594_4171_f739_67e8);
+    }
+}
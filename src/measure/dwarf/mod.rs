@@ -1,15 +1,48 @@
+mod cache;
 mod parser;
 mod types;
 
 use anyhow::{anyhow, Context, Result};
+pub use cache::{
+    binary_hash, load_cache, save_cache, DwarfCache, DWARF_CACHE_FILE, DWARF_CACHE_VERSION,
+};
 use gimli::{
     read::{Dwarf, EndianSlice},
     RunTimeEndian,
 };
 use object::{Object, ObjectSection};
+pub(crate) use parser::DEFAULT_RAM_START;
 use std::borrow;
 use std::collections::HashMap;
-pub use types::{ObjectLocationMap, Subprogram, Subroutine};
+pub use types::{ObjectLocation, ObjectLocationMap, Subprogram, Subroutine};
+
+/// The `(prefix, suffix)` surrounding the resource name in the demangled name of an
+/// RTIC resource lock. Each RTIC version implements the `Mutex` trait from a different
+/// path, add new versions here.
+const RTIC_MUTEX_PATTERNS: &[(&str, &str)] = &[
+    // cortex-m-rtic 0.5 and 0.6
+    ("impl rtic_core::Mutex for ", ">::lock"),
+    // rtic 1.0 and later
+    ("impl rtic::Mutex for ", ">::lock"),
+];
+
+/// The name patterns of the subroutines that read a peripheral, used unless others are
+/// given. A `*` matches any characters, see `matches_vcell_pattern`.
+pub const DEFAULT_VCELL_PATTERNS: &[&str] = &[
+    // vcell, also used by volatile-register and svd2rust
+    "vcell*get",
+    "vcell*as_ptr",
+    "VolatileCell*get",
+    // Raw reads with `core::ptr::read_volatile`
+    "read_volatile",
+];
+
+/// The module the `#[rtic::app]` attribute is placed on. The user tasks are
+/// functions declared directly in this module.
+const RTIC_APP_MODULE: &str = "app";
+
+/// Functions in the app module that are not tasks.
+const RTIC_NON_TASK_FUNCTIONS: &[&str] = &["init", "idle", "main"];
 
 /// Loads a DWARF object from file
 ///
@@ -37,15 +70,17 @@ pub fn load_dwarf_from_file(object: object::File) -> Result<Dwarf<borrow::Cow<[u
 /// location addresses.
 ///
 /// * `dwarf` - A DWARF object
+/// * `ram_start` - The start address of the RAM
 pub fn get_replay_addresses(
     dwarf: &Dwarf<EndianSlice<RunTimeEndian>>,
+    ram_start: u64,
 ) -> Result<ObjectLocationMap> {
     let mut objects: ObjectLocationMap = HashMap::new();
     // Iterate over the compilation units.
     let mut iter = dwarf.units();
     while let Some(header) = iter.next()? {
         let unit = dwarf.unit(header)?;
-        let entries = parser::parse_variable_entries(&dwarf, &unit, &header)?;
+        let entries = parser::parse_variable_entries(&dwarf, &unit, &header, ram_start)?;
         for entry in entries {
             objects.insert(entry.name, entry.address);
         }
@@ -57,12 +92,15 @@ pub fn get_replay_addresses(
 ///
 /// * `dwarf` - A DWARF object
 /// * `ignore_reserved` - Ignore reserved subprograms starting with `__`
-pub fn get_subprograms(dwarf: &Dwarf<EndianSlice<RunTimeEndian>>) -> Result<Vec<Subprogram>> {
+pub fn get_subprograms(
+    dwarf: &Dwarf<EndianSlice<RunTimeEndian>>,
+    ignore_reserved: bool,
+) -> Result<Vec<Subprogram>> {
     let mut iter = dwarf.units();
     let mut programs: Vec<Subprogram> = vec![];
     while let Some(header) = iter.next()? {
         let unit = dwarf.unit(header)?;
-        let mut result = parser::parse_subprograms(dwarf, &unit)?;
+        let mut result = parser::parse_subprograms(dwarf, &unit, ignore_reserved)?;
         programs.append(&mut result);
     }
     Ok(programs)
@@ -84,21 +122,70 @@ pub fn get_subprograms_address_in_range(
     Ok(ok)
 }
 
-/// Returns the subprogram in the given list with the shortest range.
+/// Returns the sorted names of the RTIC tasks among the given subprograms. A task is a
+/// function declared directly in the RTIC app module, except `init` and `idle`.
+pub fn get_rtic_task_names(subprograms: &Vec<Subprogram>) -> Vec<String> {
+    let mut tasks: Vec<String> = Vec::new();
+
+    for subprogram in subprograms {
+        let path: Vec<&str> = subprogram.linkage_name.split("::").collect();
+        if let [module, name] = path[..] {
+            if module == RTIC_APP_MODULE && !RTIC_NON_TASK_FUNCTIONS.contains(&name) {
+                tasks.push(subprogram.display_name().to_string());
+            }
+        }
+    }
+
+    tasks.sort();
+    tasks.dedup();
+    tasks
+}
+
+/// Returns the subprogram in the given list with the shortest range containing `address`.
+/// Warns if several subprograms share the shortest range, as the attribution is then ambiguous.
 pub fn get_shortest_range_subprogram(
     subprograms_in_range: &Vec<Subprogram>,
+    address: u64,
 ) -> Result<Option<Subprogram>> {
-    let mut ok: Option<Subprogram> = None;
+    let candidates = get_candidates_for_address(subprograms_in_range, address);
+    if let Some(warning) = ambiguous_attribution_warning(&candidates, address) {
+        warn!("{}", warning);
+    }
+    Ok(candidates.into_iter().next())
+}
+
+/// Returns all subprograms in the given list that share the shortest range containing `address`.
+pub fn get_candidates_for_address(subprograms: &Vec<Subprogram>, address: u64) -> Vec<Subprogram> {
+    let mut candidates: Vec<Subprogram> = vec![];
     let mut shortest_range: u64 = u64::MAX;
 
-    for subprogram in subprograms_in_range {
-        let sp_range = subprogram.high_pc - subprogram.low_pc;
-        if sp_range < shortest_range {
-            shortest_range = sp_range;
-            ok = Some(subprogram.clone());
+    for subprogram in subprograms {
+        if let Some((low, high)) = subprogram.range_from_address(address) {
+            let sp_range = high - low;
+            if sp_range < shortest_range {
+                shortest_range = sp_range;
+                candidates.clear();
+                candidates.push(subprogram.clone());
+            } else if sp_range == shortest_range {
+                candidates.push(subprogram.clone());
+            }
         }
     }
-    Ok(ok)
+    candidates
+}
+
+/// Returns a warning naming all candidates if there is more than one.
+fn ambiguous_attribution_warning(candidates: &[Subprogram], address: u64) -> Option<String> {
+    if candidates.len() < 2 {
+        return None;
+    }
+    let names: Vec<&str> = candidates.iter().map(|c| c.display_name()).collect();
+    Some(format!(
+        "Ambiguous task attribution at address {:#x}. Subprograms {} share the same range, using {}",
+        address,
+        names.join(", "),
+        names[0]
+    ))
 }
 
 /// Reads the DWARF and returns a list of subroutines and their low and high PCs.
@@ -164,7 +251,9 @@ pub fn get_resources_from_subroutines(subroutines: &Vec<Subroutine>) -> Vec<Subr
     let mut resources: Vec<Subroutine> = Vec::new();
 
     for subroutine in subroutines {
-        if let Some(resource_name) = parse_resource_name_from_lock(subroutine.name.clone()) {
+        if let Some(resource_name) =
+            parse_resource_name_from_lock(&subroutine.name, RTIC_MUTEX_PATTERNS)
+        {
             let mut copy = subroutine.clone();
             copy.name = resource_name;
             resources.push(copy);
@@ -175,39 +264,245 @@ pub fn get_resources_from_subroutines(subroutines: &Vec<Subroutine>) -> Vec<Subr
 }
 
 /// Try to parse the name of the RTIC resource from its unmangled name in the DWARF format.
-/// If the name is not an RTIC resource it will return `None`.
-fn parse_resource_name_from_lock(unmangled_name: String) -> Option<String> {
-    // Currently all resource locks implement `rtic_core::Mutex` so we search for it
-    let mut v: Vec<&str> = unmangled_name.split("impl rtic_core::Mutex for ").collect();
-    if v.len() > 1 {
-        match v.pop() {
-            Some(string) => {
-                let newsubstr: Vec<&str> = string.split(">::lock").collect();
-                if newsubstr.is_empty() {
-                    None
-                } else {
-                    Some(newsubstr[0].to_string())
-                }
+/// Each `(prefix, suffix)` pattern is tried in order. If the name does not match any of
+/// the patterns it is not an RTIC resource and `None` is returned.
+fn parse_resource_name_from_lock(
+    unmangled_name: &str,
+    patterns: &[(&str, &str)],
+) -> Option<String> {
+    for (prefix, suffix) in patterns {
+        let mut v: Vec<&str> = unmangled_name.split(*prefix).collect();
+        if v.len() > 1 {
+            if let Some(string) = v.pop() {
+                let newsubstr: Vec<&str> = string.split(*suffix).collect();
+                return Some(newsubstr[0].to_string());
             }
-            None => None,
         }
-    } else {
-        None
     }
+    None
 }
 
 /// From a list of subroutines, returns a list of the subroutines that are hardware
-/// readings. I.e. vcell::get or vcell::as_ptr.
-pub fn get_vcell_from_subroutines(subroutines: &Vec<Subroutine>) -> Vec<Subroutine> {
+/// readings. I.e. vcell::get or vcell::as_ptr. The readings are sorted by their lowest
+/// address, so they are in the same order on every replay.
+///
+/// * `subroutines` - All subroutines of the program
+/// * `patterns` - The name patterns of hardware readings, e.g. `DEFAULT_VCELL_PATTERNS`
+pub fn get_vcell_from_subroutines<S: AsRef<str>>(
+    subroutines: &Vec<Subroutine>,
+    patterns: &[S],
+) -> Vec<Subroutine> {
     let mut vcells: Vec<Subroutine> = Vec::new();
 
     for subroutine in subroutines {
-        if subroutine.name.contains("vcell") {
-            if subroutine.name.contains("get") || subroutine.name.contains("as_ptr") {
-                vcells.push(subroutine.clone());
-            }
+        if patterns
+            .iter()
+            .any(|pattern| matches_vcell_pattern(&subroutine.name, pattern.as_ref()))
+        {
+            vcells.push(subroutine.clone());
         }
     }
+    vcells.sort_by_key(|vcell| {
+        vcell
+            .ranges
+            .iter()
+            .map(|(low_pc, _)| *low_pc)
+            .min()
+            .unwrap_or(u64::MAX)
+    });
 
     vcells
 }
+
+/// Returns true if the name contains the parts of the pattern separated by `*` in order,
+/// e.g. `vcell*get` matches `<vcell::VolatileCell<T>>::get`.
+fn matches_vcell_pattern(name: &str, pattern: &str) -> bool {
+    let mut rest = name;
+    for part in pattern.split('*') {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_rtic_task_names() {
+        let subprogram = |name: &str, linkage_name: &str| Subprogram {
+            name: name.to_string(),
+            linkage_name: linkage_name.to_string(),
+            ranges: vec![(0x100, 0x120)],
+        };
+        let subprograms = vec![
+            subprogram("uart0", "app::uart0"),
+            subprogram("init", "app::init"),
+            subprogram("foo", "app::foo"),
+            subprogram("lock", "app::shared_resources::res1::lock"),
+            subprogram("delay", "cortex_m::asm::delay"),
+        ];
+
+        assert_eq!(
+            get_rtic_task_names(&subprograms),
+            vec!["foo".to_string(), "uart0".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_ambiguous_subprogram_ranges() {
+        let subprogram = |name: &str| Subprogram {
+            name: name.to_string(),
+            linkage_name: String::new(),
+            ranges: vec![(0x100, 0x120)],
+        };
+        let subprograms = vec![
+            subprogram("task1"),
+            subprogram("task1_inlined"),
+            Subprogram {
+                name: "main".to_string(),
+                linkage_name: String::new(),
+                ranges: vec![(0x0, 0x400)],
+            },
+        ];
+
+        let candidates = get_candidates_for_address(&subprograms, 0x110);
+        assert_eq!(candidates.len(), 2);
+
+        let warning = ambiguous_attribution_warning(&candidates, 0x110).unwrap();
+        assert!(warning.contains("task1, task1_inlined"));
+        assert!(warning.contains("0x110"));
+
+        let unique = get_candidates_for_address(&subprograms, 0x200);
+        assert_eq!(ambiguous_attribution_warning(&unique, 0x200), None);
+    }
+
+    #[test]
+    fn test_parse_resource_name_rtic_core_mutex() {
+        let name = "app::<impl rtic_core::Mutex for app::resources::res1>::lock";
+        let resource = parse_resource_name_from_lock(name, RTIC_MUTEX_PATTERNS);
+        assert_eq!(resource, Some("app::resources::res1".to_string()));
+    }
+
+    #[test]
+    fn test_parse_resource_name_rtic_mutex() {
+        let name = "app::<impl rtic::Mutex for app::shared_resources::shared>::lock";
+        let resource = parse_resource_name_from_lock(name, RTIC_MUTEX_PATTERNS);
+        assert_eq!(resource, Some("app::shared_resources::shared".to_string()));
+    }
+
+    #[test]
+    fn test_get_vcell_from_subroutines() {
+        let subroutine = |name: &str| Subroutine {
+            name: name.to_string(),
+            ranges: vec![(0x100, 0x110)],
+        };
+        let subroutines = vec![
+            subroutine("<vcell::VolatileCell<T>>::get"),
+            subroutine("<vcell::VolatileCell<T>>::as_ptr"),
+            subroutine("<vcell::VolatileCell<T>>::set"),
+            subroutine("<volatile_register::RO<T>>::read"),
+            subroutine("<volatile_register::RW<T>>::write"),
+            subroutine("core::ptr::read_volatile<u32>"),
+            subroutine("app::<impl rtic::Mutex for app::shared_resources::shared>::lock"),
+        ];
+        let names = |vcells: Vec<Subroutine>| -> Vec<String> {
+            vcells.into_iter().map(|vcell| vcell.name).collect()
+        };
+
+        assert_eq!(
+            names(get_vcell_from_subroutines(
+                &subroutines,
+                DEFAULT_VCELL_PATTERNS
+            )),
+            vec![
+                "<vcell::VolatileCell<T>>::get",
+                "<vcell::VolatileCell<T>>::as_ptr",
+                "core::ptr::read_volatile<u32>",
+            ]
+        );
+        assert_eq!(
+            names(get_vcell_from_subroutines(
+                &subroutines,
+                &["volatile_register*read"]
+            )),
+            vec!["<volatile_register::RO<T>>::read"]
+        );
+    }
+
+    #[test]
+    fn test_get_vcell_from_subroutines_sorted() {
+        let subroutine = |ranges: Vec<(u64, u64)>| Subroutine {
+            name: "<vcell::VolatileCell<T>>::get".to_string(),
+            ranges,
+        };
+        let subroutines = vec![
+            subroutine(vec![(0x300, 0x304)]),
+            subroutine(vec![]),
+            subroutine(vec![(0x400, 0x404), (0x100, 0x104)]),
+            subroutine(vec![(0x200, 0x204)]),
+        ];
+
+        let starts: Vec<Option<u64>> =
+            get_vcell_from_subroutines(&subroutines, DEFAULT_VCELL_PATTERNS)
+                .iter()
+                .map(|vcell| vcell.ranges.iter().map(|(low_pc, _)| *low_pc).min())
+                .collect();
+        assert_eq!(starts, vec![Some(0x100), Some(0x200), Some(0x300), None]);
+    }
+
+    #[test]
+    fn test_parse_resource_name_no_match() {
+        let name = "<vcell::VolatileCell<T>>::get";
+        let resource = parse_resource_name_from_lock(name, RTIC_MUTEX_PATTERNS);
+        assert_eq!(resource, None);
+    }
+
+    #[test]
+    fn test_get_subprograms_ignore_reserved() {
+        // A compile unit with the subprograms `main` and `__reserved`
+        let abbrev: &[u8] = &[
+            0x01, 0x11, 0x01, 0x00, 0x00, // DW_TAG_compile_unit, has children
+            0x02, 0x2e, 0x00, // DW_TAG_subprogram, no children
+            0x03, 0x0e, // DW_AT_name, DW_FORM_strp
+            0x11, 0x01, // DW_AT_low_pc, DW_FORM_addr
+            0x12, 0x01, // DW_AT_high_pc, DW_FORM_addr
+            0x00, 0x00, 0x00,
+        ];
+        let strings: &[u8] = b"main\0__reserved\0";
+        let info: &[u8] = &[
+            0x23, 0x00, 0x00, 0x00, // unit length
+            0x04, 0x00, // version
+            0x00, 0x00, 0x00, 0x00, // abbrev offset
+            0x04, // address size
+            0x01, // compile unit
+            0x02, 0x00, 0x00, 0x00, 0x00, // main
+            0x00, 0x01, 0x00, 0x08, // 0x0800_0100
+            0x20, 0x01, 0x00, 0x08, // 0x0800_0120
+            0x02, 0x05, 0x00, 0x00, 0x00, // __reserved
+            0x00, 0x02, 0x00, 0x08, // 0x0800_0200
+            0x20, 0x02, 0x00, 0x08, // 0x0800_0220
+            0x00, // end of children
+        ];
+        let mut dwarf: Dwarf<EndianSlice<RunTimeEndian>> = Dwarf::default();
+        dwarf.debug_abbrev = gimli::DebugAbbrev::new(abbrev, RunTimeEndian::Little);
+        dwarf.debug_info = gimli::DebugInfo::new(info, RunTimeEndian::Little);
+        dwarf.debug_str = gimli::DebugStr::new(strings, RunTimeEndian::Little);
+        let names = |ignore_reserved: bool| -> Vec<String> {
+            get_subprograms(&dwarf, ignore_reserved)
+                .unwrap()
+                .into_iter()
+                .map(|subprogram| subprogram.name)
+                .collect()
+        };
+
+        assert_eq!(names(true), vec!["main".to_string()]);
+        assert_eq!(
+            names(false),
+            vec!["main".to_string(), "__reserved".to_string()]
+        );
+    }
+}
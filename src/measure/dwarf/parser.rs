@@ -5,21 +5,25 @@ use gimli::{
         AttributeValue, DebuggingInformationEntry, Dwarf, EndianSlice, EvaluationResult, Location,
         Unit,
     },
-    Expression, RunTimeEndian, UnitHeader,
+    DebugStrOffset, Encoding, Expression, RunTimeEndian, UnitHeader, UnitOffset,
 };
 use rustc_demangle::demangle;
 
-const FLASH_ADDRESS_START: u64 = 0x2000_0000;
+/// Start of the RAM on most Cortex-M chips. Values of variable locations below this
+/// address are not considered to be addresses.
+pub(crate) const DEFAULT_RAM_START: u64 = 0x2000_0000;
 
 /// Parses all `DW_AT_variable`s in the current DWARF unit if there are any.
 ///
 /// * `dwarf` -The DWARF object
 /// * `unit`- The current unit
 /// * `header` - The current header
+/// * `ram_start` - The start address of the RAM
 pub fn parse_variable_entries(
     dwarf: &Dwarf<EndianSlice<RunTimeEndian>>,
     unit: &Unit<EndianSlice<RunTimeEndian>>,
     header: &UnitHeader<EndianSlice<RunTimeEndian>>,
+    ram_start: u64,
 ) -> Result<Vec<ObjectLocation>> {
     let mut objects: Vec<ObjectLocation> = vec![];
     // Iterate over the Debugging Information Entries (DIEs) in the unit.
@@ -27,7 +31,7 @@ pub fn parse_variable_entries(
     while let Some((_, entry)) = entries.next_dfs()? {
         // Iterate over the variables in the DIE.
         if entry.tag() == gimli::DW_TAG_variable {
-            match parse_object_location(&unit, &entry, &dwarf, &header)? {
+            match parse_object_location(&unit, &entry, &dwarf, &header, ram_start)? {
                 Some(variable) => objects.push(variable),
                 None => (),
             }
@@ -43,6 +47,7 @@ fn parse_object_location(
     entry: &DebuggingInformationEntry<EndianSlice<RunTimeEndian>>,
     dwarf: &Dwarf<EndianSlice<RunTimeEndian>>,
     header: &UnitHeader<EndianSlice<RunTimeEndian>>,
+    ram_start: u64,
 ) -> Result<Option<ObjectLocation>> {
     let mut attrs = entry.attrs();
     let mut name: String = String::new();
@@ -51,26 +56,23 @@ fn parse_object_location(
         if attr.name() == gimli::constants::DW_AT_name {
             match attr.value() {
                 AttributeValue::DebugStrRef(offset) => {
-                    name = dwarf
-                        .string(offset)
-                        .unwrap()
-                        .to_string()
-                        .unwrap()
-                        .to_string();
+                    name = read_string(dwarf, offset, entry.offset())?;
                 }
                 _ => (),
             }
         } else if attr.name() == gimli::constants::DW_AT_location {
             match attr.value() {
                 AttributeValue::Exprloc(e) => {
-                    if let Some(loc) = location_from_expr(header, e)? {
+                    if let Some(loc) = location_from_expr(header.encoding(), e, ram_start)? {
                         location = Some(loc);
                     }
                 }
                 AttributeValue::LocationListsRef(offset) => {
                     let mut locations = dwarf.locations(unit, offset)?;
                     while let Some(loc) = locations.next()? {
-                        if let Some(loc) = location_from_expr(header, loc.data)? {
+                        if let Some(loc) =
+                            location_from_expr(header.encoding(), loc.data, ram_start)?
+                        {
                             location = Some(loc);
                             break 'outer;
                         }
@@ -94,12 +96,15 @@ fn parse_object_location(
     Ok(None)
 }
 
+/// Evaluates a location expression. Returns the address of the location, or `None` if
+/// the location is not in memory. Values below `ram_start` are not addresses.
 fn location_from_expr(
-    header: &UnitHeader<EndianSlice<RunTimeEndian>>,
+    encoding: Encoding,
     expr: Expression<EndianSlice<RunTimeEndian>>,
+    ram_start: u64,
 ) -> Result<Option<u64>> {
     let mut location: Option<u64> = None;
-    let mut eval = expr.evaluation(header.encoding());
+    let mut eval = expr.evaluation(encoding);
     let mut result = eval.evaluate()?;
     loop {
         match result {
@@ -131,7 +136,7 @@ fn location_from_expr(
             Location::Address { address: a } => location = Some(a),
             Location::Value { value } => {
                 let v = value.to_u64(u64::MAX)?;
-                if v >= FLASH_ADDRESS_START {
+                if v >= ram_start {
                     location = Some(v);
                 }
             }
@@ -142,19 +147,73 @@ fn location_from_expr(
     Ok(location)
 }
 
+/// Reads a string attribute from the `.debug_str` section.
+///
+/// * `dwarf` - The DWARF object
+/// * `offset` - The offset of the string
+/// * `entry_offset` - The offset of the DIE the attribute belongs to
+fn read_string(
+    dwarf: &Dwarf<EndianSlice<RunTimeEndian>>,
+    offset: DebugStrOffset<usize>,
+    entry_offset: UnitOffset<usize>,
+) -> Result<String> {
+    let string = dwarf.string(offset).with_context(|| {
+        format!(
+            "Could not read string at offset {:#x} for the DIE at {:#x}",
+            offset.0, entry_offset.0
+        )
+    })?;
+    let string = string.to_string().with_context(|| {
+        format!(
+            "String at offset {:#x} for the DIE at {:#x} is not valid UTF-8",
+            offset.0, entry_offset.0
+        )
+    })?;
+    Ok(string.to_string())
+}
+
+/// The value of a `DW_AT_high_pc` attribute. Depending on its form it is
+/// either an offset from `DW_AT_low_pc` or an absolute address.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum HighPc {
+    Offset(u64),
+    Address(u64),
+}
+
+impl HighPc {
+    /// Returns the absolute high address given the low address.
+    fn resolve(&self, low_pc: u64) -> u64 {
+        match self {
+            HighPc::Offset(offset) => low_pc + offset,
+            HighPc::Address(address) => *address,
+        }
+    }
+}
+
+/// Parses the value of a `DW_AT_high_pc` attribute.
+fn parse_high_pc(value: AttributeValue<EndianSlice<RunTimeEndian>>) -> Option<HighPc> {
+    match value {
+        AttributeValue::Addr(a) => Some(HighPc::Address(a)),
+        AttributeValue::Udata(a) => Some(HighPc::Offset(a)),
+        _ => None,
+    }
+}
+
 /// Parses the `DW_AT_subprogram`s in the current DWARF unit if there are any.
 ///
 /// * `dwarf` - The DWARF object
 /// * `unit` - The current unit
+/// * `ignore_reserved` - Ignore reserved subprograms starting with `__`
 pub fn parse_subprograms(
     dwarf: &Dwarf<EndianSlice<RunTimeEndian>>,
     unit: &Unit<EndianSlice<RunTimeEndian>>,
+    ignore_reserved: bool,
 ) -> Result<Vec<Subprogram>> {
     let mut entries = unit.entries();
     let mut programs: Vec<Subprogram> = vec![];
     while let Some((_depth, entry)) = entries.next_dfs()? {
         if entry.tag() == gimli::DW_TAG_subprogram {
-            let res = parse_subprogram(dwarf, entry)?;
+            let res = parse_subprogram(dwarf, unit, entry, ignore_reserved)?;
             match res {
                 Some(program) => programs.push(program),
                 None => (),
@@ -168,7 +227,9 @@ pub fn parse_subprograms(
 /// If the current entry is not a subprogram it will simply return `None`.
 fn parse_subprogram(
     dwarf: &Dwarf<EndianSlice<RunTimeEndian>>,
+    unit: &Unit<EndianSlice<RunTimeEndian>>,
     entry: &DebuggingInformationEntry<EndianSlice<RunTimeEndian>>,
+    ignore_reserved: bool,
 ) -> Result<Option<Subprogram>> {
     let mut attrs = entry.attrs();
 
@@ -176,7 +237,8 @@ fn parse_subprogram(
     let mut linkage_name: String = String::from("");
     let mut name: Option<String> = None;
     let mut low_pc: Option<u64> = None;
-    let mut high_pc: Option<u64> = None;
+    let mut high_pc: Option<HighPc> = None;
+    let mut ranges: Vec<(u64, u64)> = vec![];
 
     while let Some(attr) = attrs.next()? {
         if attr.name() == gimli::constants::DW_AT_low_pc {
@@ -185,54 +247,57 @@ fn parse_subprogram(
                 _ => (),
             }
         } else if attr.name() == gimli::constants::DW_AT_high_pc {
-            match attr.value() {
-                AttributeValue::Udata(a) => high_pc = Some(a),
-                _ => (),
-            }
+            high_pc = parse_high_pc(attr.value());
         } else if attr.name() == gimli::constants::DW_AT_name {
             match attr.value() {
                 AttributeValue::DebugStrRef(offset) => {
-                    let sub_name = dwarf
-                        .string(offset)
-                        .unwrap()
-                        .to_string()
-                        .unwrap()
-                        .to_string();
-                    // Ignore reserved functions
-                    if !sub_name.starts_with("__") {
-                        name = Some(sub_name);
+                    let sub_name = read_string(dwarf, offset, entry.offset())?;
+                    if ignore_reserved && sub_name.starts_with("__") {
+                        return Ok(None);
                     }
+                    name = Some(sub_name);
                 }
                 _ => (),
             }
         } else if attr.name() == gimli::constants::DW_AT_linkage_name {
             match attr.value() {
                 AttributeValue::DebugStrRef(offset) => {
-                    let sub_name = dwarf
-                        .string(offset)
-                        .unwrap()
-                        .to_string()
-                        .unwrap()
-                        .to_string();
+                    let sub_name = read_string(dwarf, offset, entry.offset())?;
                     linkage_name = demangle(&sub_name).to_string();
                 }
                 _ => (),
             }
+        } else if attr.name() == gimli::constants::DW_AT_ranges {
+            match attr.value() {
+                AttributeValue::RangeListsRef(offset) => {
+                    let mut rngs = dwarf
+                        .ranges(unit, offset)
+                        .context("Could not get range for subprogram")?;
+                    while let Some(r) = rngs.next()? {
+                        ranges.push((r.begin, r.end));
+                    }
+                }
+                _ => (),
+            }
         }
     }
 
-    match (name, low_pc, high_pc) {
-        (Some(name), Some(low), Some(high)) => {
-            subprogram = Some(Subprogram {
-                name,
-                linkage_name,
-                low_pc: low,
-                high_pc: low + high,
-            })
+    match (low_pc, high_pc) {
+        (Some(low), Some(high)) => {
+            ranges.push((low, high.resolve(low)));
         }
         _ => (),
     }
 
+    // Keep subprograms without a `DW_AT_name` if they can be named by their linkage name
+    if (name.is_some() || !linkage_name.is_empty()) && !ranges.is_empty() {
+        subprogram = Some(Subprogram {
+            name: name.unwrap_or_default(),
+            linkage_name,
+            ranges,
+        })
+    }
+
     Ok(subprogram)
 }
 
@@ -278,7 +343,7 @@ fn parse_inlined_subroutine(
 
     let mut name: Option<String> = None;
     let mut low_pc: Option<u64> = None;
-    let mut high_pc: Option<u64> = None;
+    let mut high_pc: Option<HighPc> = None;
     let mut ranges: Vec<(u64, u64)> = vec![];
 
     while let Some(attr) = attrs.next()? {
@@ -299,10 +364,7 @@ fn parse_inlined_subroutine(
                 _ => (),
             }
         } else if attr.name() == gimli::constants::DW_AT_high_pc {
-            match attr.value() {
-                AttributeValue::Udata(a) => high_pc = Some(a),
-                _ => (),
-            }
+            high_pc = parse_high_pc(attr.value());
         } else if attr.name() == gimli::constants::DW_AT_ranges {
             match attr.value() {
                 AttributeValue::RangeListsRef(offset) => {
@@ -320,7 +382,7 @@ fn parse_inlined_subroutine(
 
     match (low_pc, high_pc) {
         (Some(low), Some(high)) => {
-            ranges.push((low, low + high));
+            ranges.push((low, high.resolve(low)));
         }
         _ => (),
     }
@@ -352,12 +414,7 @@ fn parse_abstract_origin(
         if attr.name() == gimli::constants::DW_AT_linkage_name {
             match attr.value() {
                 AttributeValue::DebugStrRef(offset) => {
-                    let origin_name = dwarf
-                        .string(offset)
-                        .unwrap()
-                        .to_string()
-                        .unwrap()
-                        .to_string();
+                    let origin_name = read_string(dwarf, offset, entry.offset())?;
                     name = demangle(&origin_name).to_string();
                 }
                 _ => (),
@@ -367,3 +424,57 @@ fn parse_abstract_origin(
 
     Ok(name)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dangling_string_offset() {
+        // An empty `.debug_str` section, as in a binary with stripped debug info
+        let dwarf: Dwarf<EndianSlice<RunTimeEndian>> = Dwarf::default();
+
+        let err = read_string(&dwarf, DebugStrOffset(0x40), UnitOffset(0x2b)).unwrap_err();
+        assert!(format!("{:?}", err).contains("offset 0x40 for the DIE at 0x2b"));
+    }
+
+    #[test]
+    fn test_location_value_ram_start() {
+        let encoding = Encoding {
+            format: gimli::Format::Dwarf32,
+            version: 4,
+            address_size: 4,
+        };
+        // DW_OP_const4u 0x1000_0400, DW_OP_stack_value
+        let bytes = [0x0c, 0x00, 0x04, 0x00, 0x10, 0x9f];
+        let expr = Expression(EndianSlice::new(&bytes, RunTimeEndian::Little));
+
+        assert_eq!(
+            location_from_expr(encoding, expr, DEFAULT_RAM_START).unwrap(),
+            None
+        );
+        assert_eq!(
+            location_from_expr(encoding, expr, 0x1000_0000).unwrap(),
+            Some(0x1000_0400)
+        );
+        assert_eq!(
+            location_from_expr(encoding, expr, 0x1000_0800).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_high_pc_offset_and_address() {
+        let low_pc = 0x0800_0100;
+
+        let offset = parse_high_pc(AttributeValue::Udata(0x20)).unwrap();
+        assert_eq!(offset, HighPc::Offset(0x20));
+        assert_eq!(offset.resolve(low_pc), 0x0800_0120);
+
+        let address = parse_high_pc(AttributeValue::Addr(0x0800_0140)).unwrap();
+        assert_eq!(address, HighPc::Address(0x0800_0140));
+        assert_eq!(address.resolve(low_pc), 0x0800_0140);
+
+        assert_eq!(parse_high_pc(AttributeValue::Flag(true)), None);
+    }
+}
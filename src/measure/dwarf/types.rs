@@ -1,3 +1,4 @@
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
 type Name = String;
@@ -7,7 +8,7 @@ type MemoryLocation = Option<u64>;
 pub type ObjectLocationMap = HashMap<Name, MemoryLocation>;
 
 /// A DWARF subroutine containing the useful values for Rauk analysis
-#[derive(Debug, Clone, Eq, Ord, PartialEq, PartialOrd)]
+#[derive(Debug, Clone, Eq, Ord, PartialEq, PartialOrd, Serialize, Deserialize)]
 pub struct Subroutine {
     /// The demangled name of the subroutine
     pub name: String,
@@ -31,7 +32,7 @@ impl Subroutine {
 }
 
 /// Details about a resource object and its location in RAM
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ObjectLocation {
     /// The name of the object.
     pub name: String,
@@ -40,21 +41,71 @@ pub struct ObjectLocation {
 }
 
 /// A DWARF subprogram containing the useful value for Rauk analysis
-#[derive(Debug, Clone, Eq, Ord, PartialEq, PartialOrd)]
+#[derive(Debug, Clone, Eq, Ord, PartialEq, PartialOrd, Serialize, Deserialize)]
 pub struct Subprogram {
     /// The demangled name of the subprogram
     pub name: String,
     /// The demangled linkage name of this subprogram
     pub linkage_name: String,
-    /// The starting address of this subprogram
-    pub low_pc: u64,
-    /// The ending address of this subprogram
-    pub high_pc: u64,
+    /// List of ranges of starting and ending addresses of this
+    /// subprogram (low_pc, high_pc)
+    pub ranges: Vec<(u64, u64)>,
 }
 
 impl Subprogram {
-    /// Checks if `address` is inside this subprogram's range.
+    /// Returns the human readable `DW_AT_name` if there is one, otherwise the
+    /// demangled linkage name.
+    pub fn display_name(&self) -> &str {
+        if self.name.is_empty() {
+            &self.linkage_name
+        } else {
+            &self.name
+        }
+    }
+
+    /// Checks if `address` is inside any of this subprogram's ranges.
     pub fn address_in_range(&self, address: u64) -> bool {
-        (self.low_pc <= address) && (address <= self.high_pc)
+        self.range_from_address(address).is_some()
+    }
+
+    /// Checks if `address` is inside this subprogram's ranges. Returns
+    /// the range if that is the case.
+    pub fn range_from_address(&self, address: u64) -> Option<(u64, u64)> {
+        self.ranges
+            .iter()
+            .find(|(low_pc, high_pc)| (*low_pc <= address) && (address <= *high_pc))
+            .copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_subprogram_display_name() {
+        let mut subprogram = Subprogram {
+            name: "my_task".to_string(),
+            linkage_name: "app::my_task::<app::Context>".to_string(),
+            ranges: vec![(0x100, 0x120)],
+        };
+        assert_eq!(subprogram.display_name(), "my_task");
+
+        subprogram.name = String::new();
+        assert_eq!(subprogram.display_name(), "app::my_task::<app::Context>");
+    }
+
+    #[test]
+    fn test_subprogram_address_in_second_range() {
+        let subprogram = Subprogram {
+            name: "task1".to_string(),
+            linkage_name: "app::task1".to_string(),
+            ranges: vec![(0x100, 0x120), (0x400, 0x440)],
+        };
+
+        assert!(subprogram.address_in_range(0x110));
+        assert!(subprogram.address_in_range(0x420));
+        assert_eq!(subprogram.range_from_address(0x420), Some((0x400, 0x440)));
+        assert!(!subprogram.address_in_range(0x200));
     }
 }
@@ -1,21 +1,142 @@
-use super::breakpoints::{Breakpoint, OtherBreakpoint};
+use super::breakpoints::{Breakpoint, EntryBreakpoint, OtherBreakpoint};
 use super::dwarf::{self, ObjectLocationMap, Subprogram, Subroutine};
-use super::klee::get_vcell_ktestobjects;
+use super::klee::{self, get_resource_ktestobjects, get_vcell_ktestobjects};
+use super::trace::cycle_difference;
 use super::AppInfo;
 use crate::cli::MeasureInput;
-use crate::utils::core;
+use crate::utils::core::{self, CoreAccess, CycleSource};
 use anyhow::{anyhow, Context, Result};
+use gimli::RunTimeEndian;
 use ktest_parser::{KTest, KTestObject};
-use probe_rs::{Core, CoreRegisterAddress, MemoryInterface};
+use log::{Level, Log, Record};
+use probe_rs::{Core, CoreRegisterAddress};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
 
 pub const BKPT_UNKNOWN_NAME: &str = "<unknown>";
-const DEFAULT_HALT_TIMEOUT_SECONDS: u64 = 10;
+/// The file in the output directory the raw breakpoints are appended to
+pub const BREAKPOINT_LOG_FILE: &str = "breakpoints.log";
+pub(crate) const DEFAULT_HALT_TIMEOUT_SECONDS: u64 = 10;
 
-type ObjectName = String;
-type CycleCount = u32;
-/// Result of measuring on hardware. Containing the Breakpoint type and the name of the object
-/// (such as a Task name or resources name) and the cycle count at that breakpoint.
-pub type MeasurementResult = (Breakpoint, ObjectName, CycleCount);
+/// The subprograms the core ends up in when the application panics or faults, as
+/// `(name, fault)`. Matched against both the name and the linkage name.
+const FAULT_HANDLERS: &[(&str, &str)] = &[
+    // The `#[panic_handler]` of any panic crate
+    ("rust_begin_unwind", "panic"),
+    ("core::panicking::panic_fmt", "panic"),
+    // cortex-m-rt exception handlers, user defined and default
+    ("HardFault", "HardFault"),
+    ("HardFault_", "HardFault"),
+    ("DefaultHandler", "unhandled exception"),
+    ("DefaultHandler_", "unhandled exception"),
+];
+
+type CycleCount = u64;
+
+/// Result of measuring a breakpoint on hardware. Contains the Breakpoint type, the name of
+/// the object (such as a Task name or resources name), the cycle count at that breakpoint
+/// and the address of the breakpoint instruction if known.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Measurement {
+    breakpoint: Breakpoint,
+    name: String,
+    cycles: CycleCount,
+    pc: Option<u32>,
+}
+
+impl Measurement {
+    pub fn new(breakpoint: Breakpoint, name: String, cycles: CycleCount) -> Measurement {
+        Measurement {
+            breakpoint,
+            name,
+            cycles,
+            pc: None,
+        }
+    }
+
+    /// Sets the address of the breakpoint instruction.
+    pub fn with_pc(mut self, pc: u32) -> Measurement {
+        self.pc = Some(pc);
+        self
+    }
+
+    /// The breakpoint that was hit
+    pub fn breakpoint(&self) -> &Breakpoint {
+        &self.breakpoint
+    }
+
+    /// The name of the task or resource of the breakpoint
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The cycle count at the breakpoint
+    pub fn cycles(&self) -> CycleCount {
+        self.cycles
+    }
+
+    /// The address of the breakpoint instruction
+    pub fn pc(&self) -> Option<u32> {
+        self.pc
+    }
+}
+
+impl fmt::Display for Measurement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} '{}'", self.breakpoint, self.name)?;
+        if let Some(pc) = self.pc {
+            write!(f, " at {:#010x}", pc)?;
+        }
+        Ok(())
+    }
+}
+
+/// Converts the `(Breakpoint, Name, CYCCNT)` tuples measurements used to be stored as.
+impl From<(Breakpoint, String, CycleCount)> for Measurement {
+    fn from((breakpoint, name, cycles): (Breakpoint, String, CycleCount)) -> Measurement {
+        Measurement::new(breakpoint, name, cycles)
+    }
+}
+
+/// Extends the readings of the cycle counter to a 64-bit running count. The counter
+/// has wrapped whenever a reading is smaller than the previous one.
+#[derive(Debug)]
+struct CycleCounter {
+    period: u64,
+    previous: u32,
+    wraps: u64,
+}
+
+impl CycleCounter {
+    /// Creates a running count for a counter that wraps after `period` cycles.
+    fn new(period: u64) -> CycleCounter {
+        CycleCounter {
+            period,
+            previous: 0,
+            wraps: 0,
+        }
+    }
+
+    /// Returns the 64-bit cycle count of the given reading of the cycle counter.
+    fn extend(&mut self, cyccnt: u32) -> CycleCount {
+        if cyccnt < self.previous {
+            self.wraps += 1;
+        }
+        self.previous = cyccnt;
+        self.wraps * self.period + cyccnt as u64
+    }
+}
+
+/// How many of the vcell test vectors of a KTest were written during a replay.
+/// Remaining test vectors mean the replay read fewer peripherals than KLEE expected,
+/// i.e. it took a different path than the test vector was generated for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct VcellUsage {
+    consumed: usize,
+    remaining: usize,
+}
 
 enum LoopAction {
     Break,
@@ -23,38 +144,288 @@ enum LoopAction {
     Nothing,
 }
 
-/// Runs the replay harness and measures the clock cycles.
+/// The core did not halt within the timeout while replaying a test vector.
+#[derive(Debug)]
+struct HaltTimeout;
+
+impl fmt::Display for HaltTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Core does not halt. Your application might be stuck in a non-terminating loop?"
+        )
+    }
+}
+
+impl std::error::Error for HaltTimeout {}
+
+/// Log of the raw breakpoints the core halts at, for debugging the measurement. Each
+/// breakpoint is a line with its PC, immediate and cycle count. Breakpoints that are not
+/// measured, such as ReplayStart, have `-` as the cycle count.
+pub struct BreakpointLog {
+    file: File,
+}
+
+impl BreakpointLog {
+    /// Opens the log at `path`, appending to it if it exists.
+    pub fn open(path: &PathBuf) -> Result<BreakpointLog> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Could not open the breakpoint log {:?}", path))?;
+        Ok(BreakpointLog { file })
+    }
+
+    /// Marks the start of the replay of a test vector.
+    fn start_test_vector(&mut self, number: usize) -> Result<()> {
+        writeln!(self.file, "# test vector {}", number)?;
+        Ok(())
+    }
+
+    /// Appends a breakpoint to the log.
+    fn record(&mut self, pc: u32, imm: u8, cycles: Option<CycleCount>) -> Result<()> {
+        match cycles {
+            Some(cycles) => writeln!(self.file, "{:#010x} {} {}", pc, imm, cycles)?,
+            None => writeln!(self.file, "{:#010x} {} -", pc, imm)?,
+        }
+        Ok(())
+    }
+}
+
+/// Runs the replay harness and measures the clock cycles. Returns the number of each
+/// measured test vector, starting from 1, with its measurement.
 ///
 /// * `core` - A connected probe-rs _core_
 /// * `ktests` - The generated test vectors
 /// * `app` - Relevant information of the replay binary
+/// * `breakpoint_log` - Log to append the raw breakpoints to, if any
 pub(super) fn measure_replay_harness(
     input: &MeasureInput,
     core: &mut Core,
     ktests: &Vec<KTest>,
     app: &AppInfo,
-) -> Result<Vec<Vec<MeasurementResult>>> {
-    let mut measurements: Vec<Vec<MeasurementResult>> = Vec::new();
+    mut breakpoint_log: Option<&mut BreakpointLog>,
+) -> Result<Vec<(usize, Vec<Measurement>)>> {
     let halt_timeout = input.halt_timeout.unwrap_or(DEFAULT_HALT_TIMEOUT_SECONDS);
+    let cycle_source = core::cycle_source(input.timer);
+    cycle_source
+        .setup(core)
+        .context("Could not set up the cycle counter")?;
+    let sample_count = input.samples.max(1);
 
     // Measure the replay harness using all generated test vectors
-    for ktest in ktests {
-        // Continue until reaching BKPT 255 (replaystart)
-        run_to_replay_start(core, halt_timeout)
-            .context("Could not continue to the ReplayStart breakpoint")?;
-        write_replay_objects(core, &app.variables, &ktest)
-            .with_context(|| format!("Could not write to memory with KTest: {:?}", &ktest))?;
+    let (measurements, skipped) = replay_test_vectors(
+        core,
+        ktests.len(),
+        input.recover,
+        log::logger(),
+        |core, index| {
+            let ktest = &ktests[index];
+            if input.progress {
+                eprintln!("{}", progress_message(index + 1, ktests.len()));
+            }
+            let mut samples: Vec<Vec<Measurement>> = Vec::new();
+            for _ in 0..sample_count {
+                if let Some(log) = breakpoint_log.as_mut() {
+                    log.start_test_vector(index + 1)?;
+                }
+                // Continue until reaching BKPT 255 (replaystart)
+                run_to_replay_start(core, halt_timeout)
+                    .context("Could not continue to the ReplayStart breakpoint")?;
+                write_replay_objects(core, &app.variables, &ktest, &input.ignore_objects)
+                    .with_context(|| {
+                        format!("Could not write to memory with KTest: {:?}", &ktest)
+                    })?;
+
+                let (bkpts, vcells) = read_breakpoints(
+                    core,
+                    cycle_source.as_ref(),
+                    &ktest,
+                    app,
+                    halt_timeout,
+                    input.strict_names,
+                    breakpoint_log.as_deref_mut(),
+                )?;
+                log_vcell_usage(index + 1, vcells);
+                samples.push(bkpts);
+            }
+
+            let (bkpts, spread) = max_of_samples(samples)
+                .with_context(|| format!("Could not combine the samples of KTest: {:?}", &ktest))?;
+            if sample_count > 1 {
+                info!(
+                    "Test vector {}: the spans of {} samples differ by up to {} cycles",
+                    index + 1,
+                    sample_count,
+                    spread
+                );
+            }
+            Ok(bkpts)
+        },
+    )?;
+
+    if !skipped.is_empty() {
+        let numbers: Vec<String> = skipped
+            .iter()
+            .map(|index| (index + 1).to_string())
+            .collect();
+        warn!(
+            "Skipped {} of {} test vector(s) that did not halt in time: {}",
+            skipped.len(),
+            ktests.len(),
+            numbers.join(", ")
+        );
+    }
+
+    let numbers = (1..=ktests.len()).filter(|number| !skipped.contains(&(number - 1)));
+    Ok(numbers.zip(measurements).collect())
+}
+
+/// Replays `count` test vectors in order using `replay`. With `recover` set, a test
+/// vector that does not halt in time is skipped after resetting the target, instead of
+/// stopping the measurement. Returns the measurements and the indices of the skipped
+/// test vectors.
+///
+/// * `core` - A connected probe-rs _core_
+/// * `count` - The number of test vectors
+/// * `recover` - Reset the target and continue if a replay does not halt
+/// * `logger` - The logger the skipped test vectors are reported to
+/// * `replay` - Replays the test vector with the given index
+fn replay_test_vectors<C, F>(
+    core: &mut C,
+    count: usize,
+    recover: bool,
+    logger: &dyn Log,
+    mut replay: F,
+) -> Result<(Vec<Vec<Measurement>>, Vec<usize>)>
+where
+    C: CoreAccess,
+    F: FnMut(&mut C, usize) -> Result<Vec<Measurement>>,
+{
+    let mut measurements: Vec<Vec<Measurement>> = Vec::new();
+    let mut skipped: Vec<usize> = Vec::new();
+
+    for index in 0..count {
+        match replay(core, index) {
+            Ok(bkpts) => measurements.push(bkpts),
+            Err(e) if recover && e.downcast_ref::<HaltTimeout>().is_some() => {
+                logger.log(
+                    &Record::builder()
+                        .args(format_args!(
+                            "Test vector {} did not halt in time. Resetting the target and skipping it",
+                            index + 1
+                        ))
+                        .level(Level::Warn)
+                        .target(module_path!())
+                        .build(),
+                );
+                // The harness starts over and halts at the next ReplayStart breakpoint
+                core.reset_and_halt(std::time::Duration::from_secs(1))
+                    .context("Could not reset the target after it stopped halting")?;
+                core.run()?;
+                skipped.push(index);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok((measurements, skipped))
+}
+
+/// Combines the measurements of several replays of the same test vector by keeping, for
+/// each breakpoint, the maximum number of cycles any replay took to reach it from the
+/// previous breakpoint. The combined cycle counts start at the first breakpoint of the
+/// first replay. As every step is a maximum, the time between any two breakpoints is at
+/// least as long as in each of the replays, so no task is under-reported. Returns the
+/// combined measurements and the difference between the longest and the shortest span
+/// from the first to the last breakpoint. A single sample is returned as is.
+///
+/// * `samples` - The measurements of each replay
+fn max_of_samples(mut samples: Vec<Vec<Measurement>>) -> Result<(Vec<Measurement>, CycleCount)> {
+    if samples.len() <= 1 {
+        return Ok((samples.pop().unwrap_or_default(), 0));
+    }
+
+    let expected = &samples[0];
+    if samples.iter().any(|sample| sample.len() != expected.len()) {
+        return Err(anyhow!(
+            "The replays reached a different number of breakpoints"
+        ));
+    }
+    for sample in &samples[1..] {
+        for (index, (measurement, m)) in expected.iter().zip(sample).enumerate() {
+            if m.breakpoint != measurement.breakpoint || m.name != measurement.name {
+                return Err(anyhow!(
+                    "The replays reached different breakpoints. Expected {} '{}' at position {}",
+                    measurement.breakpoint,
+                    measurement.name,
+                    index
+                ));
+            }
+        }
+    }
 
-        let bkpts = read_breakpoints(core, &ktest, app, halt_timeout)?;
-        measurements.push(bkpts);
+    let mut spans: Vec<CycleCount> = Vec::with_capacity(samples.len());
+    for sample in &samples {
+        let span = match (sample.first(), sample.last()) {
+            (Some(first), Some(last)) => cycle_difference(first.cycles, last.cycles)?,
+            _ => 0,
+        };
+        spans.push(span);
+    }
+    let longest = spans.iter().copied().max().unwrap_or(0);
+    let shortest = spans.iter().copied().min().unwrap_or(0);
+
+    let mut combined = samples.swap_remove(0);
+    let mut previous = combined.first().map(|m| m.cycles).unwrap_or(0);
+    let mut cycles = previous;
+    for index in 1..combined.len() {
+        let mut step = cycle_difference(previous, combined[index].cycles)?;
+        for sample in &samples {
+            step = step.max(cycle_difference(
+                sample[index - 1].cycles,
+                sample[index].cycles,
+            )?);
+        }
+        previous = combined[index].cycles;
+        cycles += step;
+        combined[index].cycles = cycles;
     }
 
-    Ok(measurements)
+    Ok((combined, longest - shortest))
+}
+
+/// Logs how many vcell test vectors a replay consumed and warns if any remain.
+///
+/// * `test_vector` - The number of the test vector, starting from 1
+/// * `usage` - The vcell test vectors used by the replay
+fn log_vcell_usage(test_vector: usize, usage: VcellUsage) {
+    info!(
+        "Test vector {}: {} vcell test vector(s) consumed, {} remaining",
+        test_vector, usage.consumed, usage.remaining
+    );
+    if usage.remaining > 0 {
+        warn!(
+            "Test vector {}: {} vcell test vector(s) were never written. The replay read fewer peripherals than KLEE expected and might have taken a different path",
+            test_vector, usage.remaining
+        );
+    }
+}
+
+/// Returns the progress of the measurement, e.g. `Measuring test vector 3/12 (25%)`.
+fn progress_message(current: usize, total: usize) -> String {
+    let percent = if total == 0 {
+        100
+    } else {
+        current * 100 / total
+    };
+    format!("Measuring test vector {}/{} ({}%)", current, total, percent)
 }
 
 /// Runs to where the replay harness starts. Also runs past any other breakpoints
 /// on the way, should there be any.
-fn run_to_replay_start(core: &mut Core, timeout: u64) -> Result<()> {
+fn run_to_replay_start<C: CoreAccess>(core: &mut C, timeout: u64) -> Result<()> {
     // Wait for core to halt on a breakpoint. If it doesn't something is wrong.
     core.wait_for_core_halted(std::time::Duration::from_secs(timeout))?;
     loop {
@@ -71,17 +442,30 @@ fn run_to_replay_start(core: &mut Core, timeout: u64) -> Result<()> {
 
 /// Writes the replay contents of the KTEST file to the objects memory addresses.
 /// If no memory address was found for the specific KTEST, it will ignore writing
-/// anything to it.
+/// anything to it. The vcell objects are written to registers when reading the
+/// breakpoints instead. Objects whose name starts with one of the `ignored` prefixes, or
+/// KLEE's bookkeeping objects if none are given, are skipped.
 ///
 /// * `core` - A connected probe-rs _core_
 /// * `locations` - A map of RTIC resource names and their memory addresses
 /// * `ktest` - The test vector to write to its corresponding memory address
-fn write_replay_objects(
-    core: &mut Core,
+/// * `ignored` - Name prefixes of the objects not to write
+fn write_replay_objects<C: CoreAccess>(
+    core: &mut C,
     locations: &ObjectLocationMap,
     ktest: &KTest,
+    ignored: &[String],
 ) -> Result<()> {
-    for test in &ktest.objects {
+    for test in &get_resource_ktestobjects(ktest) {
+        let skip = if ignored.is_empty() {
+            klee::is_ignored_object(&test.name, klee::DEFAULT_IGNORED_OBJECTS)
+        } else {
+            klee::is_ignored_object(&test.name, ignored)
+        };
+        if skip {
+            debug!("Not writing the KTest object '{}'", test.name);
+            continue;
+        }
         let location = locations.get(&test.name);
         match location {
             Some(addr) => {
@@ -106,30 +490,42 @@ fn write_replay_objects(
 /// Read all breakpoints and the cycle counter at their positions from the start of
 /// a ReplayStart breakpoint until the next ReplayStart breakpoint. Also writes the
 /// generated test vector for a hardware read one at a time in order whenever applicable.
-/// Return the measurement result as a list.
+/// Return the measurement result as a list and how many vcell test vectors were written.
 ///
 /// * `core` - A connected probe-rs _core_
+/// * `cycle_source` - The counter to read the clock cycles from
 /// * `ktest` - The test to replay
 /// * `app` - Relevant information of the replay binary
-fn read_breakpoints(
-    core: &mut Core,
+/// * `timeout` - How many seconds to wait for the core to halt
+/// * `strict_names` - Return an error if the name of a task or lock can't be resolved
+/// * `breakpoint_log` - Log to append the raw breakpoints to, if any
+fn read_breakpoints<C: CoreAccess>(
+    core: &mut C,
+    cycle_source: &dyn CycleSource,
     ktest: &KTest,
     app: &AppInfo,
     timeout: u64,
-) -> Result<Vec<MeasurementResult>> {
-    let mut measurements: Vec<MeasurementResult> = Vec::new();
+    strict_names: bool,
+    mut breakpoint_log: Option<&mut BreakpointLog>,
+) -> Result<(Vec<Measurement>, VcellUsage)> {
+    let mut measurements: Vec<Measurement> = Vec::new();
     let name = BKPT_UNKNOWN_NAME.to_string();
     let mut current_hw_bkpt: u32 = 0;
+    let mut cycle_counter = CycleCounter::new(cycle_source.period());
     let mut vcell_test_vectors = get_vcell_ktestobjects(ktest);
+    let vcell_count = vcell_test_vectors.len();
     vcell_test_vectors.reverse();
 
     // Loop from breakpoints until the next
     loop {
         core::run(core).context("Could not continue from the ReplayStart breakpoint")?;
-        core.wait_for_core_halted(std::time::Duration::from_secs(timeout))
-            .context(
-                "Core does not halt. Your application might be stuck in a non-terminating loop?",
-            )?;
+        if let Err(e) = core.wait_for_core_halted(std::time::Duration::from_secs(timeout)) {
+            // A panic or fault handler usually loops forever, halt to see if the core is in one
+            if core.halt(std::time::Duration::from_secs(1)).is_ok() {
+                check_for_fault(core, &app.subprograms, &measurements)?;
+            }
+            return Err(e.context(HaltTimeout));
+        }
 
         let current_pc = core::current_pc(core)?;
 
@@ -142,31 +538,118 @@ fn read_breakpoints(
 
             // It is assumed vcells occur in order so just pop the first test
             if let Some(test) = vcell_test_vectors.pop() {
-                write_vcell_test_to_register(core, reg, &test)?;
+                write_vcell_test_to_register(core, reg, &test, app.endian)?;
             }
         // Catch halts that are not breakpoints because that should not happen
         } else if !core::breakpoint_at_pc(core)? {
+            check_for_fault(core, &app.subprograms, &measurements)?;
             return Err(anyhow!(
                 "Core halted, but not due to a breakpoint. Can't continue with analysis. Core status: {:?}", core.status()?
             ));
         // Measure breakpoints and
         } else {
             let bkpt_val = core::read_breakpoint_value(core)?;
-            let bkpt = Breakpoint::from(bkpt_val);
+            let bkpt = parse_breakpoint(bkpt_val, current_pc)?;
+            debug!("Hit breakpoint {} at {:#010x}", &bkpt, current_pc);
 
-            match handle_breakpoint(&bkpt, core, &mut measurements, &mut current_hw_bkpt, app)? {
+            let action = handle_breakpoint(
+                &bkpt,
+                core,
+                &mut measurements,
+                &mut current_hw_bkpt,
+                app,
+                strict_names,
+            )?;
+            if let LoopAction::Break | LoopAction::Continue = action {
+                if let Some(log) = breakpoint_log.as_mut() {
+                    log.record(current_pc, bkpt_val, None)?;
+                }
+            }
+            match action {
                 LoopAction::Break => break,
                 LoopAction::Continue => continue,
                 LoopAction::Nothing => (),
             }
 
             // Save the result onto the stack
-            let cyccnt = core::read_cycle_counter(core)?;
-            measurements.push((bkpt, name.clone(), cyccnt));
+            let cyccnt = cycle_counter.extend(cycle_source.read(core)?);
+            if let Some(log) = breakpoint_log.as_mut() {
+                log.record(current_pc, bkpt_val, Some(cyccnt))?;
+            }
+            measurements.push(Measurement::new(bkpt, name.clone(), cyccnt).with_pc(current_pc));
         }
     }
 
-    Ok(measurements)
+    let usage = VcellUsage {
+        consumed: vcell_count - vcell_test_vectors.len(),
+        remaining: vcell_test_vectors.len(),
+    };
+    Ok((measurements, usage))
+}
+
+/// Returns an error naming the fault and the last started task if the core is halted
+/// inside a panic or fault handler.
+///
+/// * `core` - A connected probe-rs _core_
+/// * `subprograms` - A list of the all the subprograms of the running program
+/// * `measurements` - The breakpoints measured so far in this replay
+fn check_for_fault<C: CoreAccess>(
+    core: &mut C,
+    subprograms: &Vec<Subprogram>,
+    measurements: &[Measurement],
+) -> Result<()> {
+    let pc = core::current_pc(core)?;
+    if let Some((fault, handler)) = fault_handler_at(pc, subprograms) {
+        let task = measurements
+            .iter()
+            .rev()
+            .find(|m| match m.breakpoint {
+                Breakpoint::Entry(EntryBreakpoint::HardwareTaskStart)
+                | Breakpoint::Entry(EntryBreakpoint::SoftwareTaskStart) => true,
+                _ => false,
+            })
+            .map(|m| m.name.as_str())
+            .unwrap_or(BKPT_UNKNOWN_NAME);
+        return Err(anyhow!(
+            "The application halted in a {} ('{}' at {:#010x}) during task '{}'",
+            fault,
+            handler,
+            pc,
+            task
+        ));
+    }
+    Ok(())
+}
+
+/// Returns the fault and the name of the handler if the address is inside one of the
+/// `FAULT_HANDLERS`.
+fn fault_handler_at(pc: u32, subprograms: &Vec<Subprogram>) -> Option<(&'static str, String)> {
+    subprograms
+        .iter()
+        .filter(|subprogram| subprogram.address_in_range(pc as u64))
+        .find_map(|subprogram| {
+            FAULT_HANDLERS
+                .iter()
+                .find(|(name, _)| *name == subprogram.name || *name == subprogram.linkage_name)
+                .map(|(_, fault)| (*fault, subprogram.display_name().to_string()))
+        })
+}
+
+/// Converts the immediate value of a breakpoint instruction to its breakpoint type.
+/// Returns an error for unknown immediates, which means the instrumentation of the
+/// replay harness does not match rauk.
+///
+/// * `value` - The immediate value of the breakpoint instruction
+/// * `pc` - The address of the breakpoint instruction
+fn parse_breakpoint(value: u8, pc: u32) -> Result<Breakpoint> {
+    match Breakpoint::from(value) {
+        Breakpoint::Other(OtherBreakpoint::Invalid) => Err(anyhow!(
+            "Unknown breakpoint immediate {} at {:#010x}. The instrumentation of the replay harness is inconsistent",
+            value,
+            pc
+        )),
+        bkpt => Ok(bkpt),
+    }
 }
 
 /// Tries to get the output/load register from the previous instruction of the current breakpoint
@@ -215,10 +698,15 @@ fn parse_reg_from_load_instruction(instruction: &String) -> Option<u16> {
 }
 
 /// Writes a test vector for a vcell reading to the given register
-fn write_vcell_test_to_register(core: &mut Core, register: u16, test: &KTestObject) -> Result<()> {
+fn write_vcell_test_to_register<C: CoreAccess>(
+    core: &mut C,
+    register: u16,
+    test: &KTestObject,
+    endian: RunTimeEndian,
+) -> Result<()> {
     if test.num_bytes == 4 {
         let bytes: [u8; 4] = [test.bytes[0], test.bytes[1], test.bytes[2], test.bytes[3]];
-        let data = u32::from_le_bytes(bytes);
+        let data = register_value(bytes, endian);
         core.write_core_reg(CoreRegisterAddress(register), data)
             .with_context(|| {
                 format!(
@@ -235,15 +723,25 @@ fn write_vcell_test_to_register(core: &mut Core, register: u16, test: &KTestObje
     Ok(())
 }
 
+/// Converts the bytes of a test vector to the value of a register using the byte order
+/// of the target.
+fn register_value(bytes: [u8; 4], endian: RunTimeEndian) -> u32 {
+    match endian {
+        RunTimeEndian::Little => u32::from_le_bytes(bytes),
+        RunTimeEndian::Big => u32::from_be_bytes(bytes),
+    }
+}
+
 /// Executes the necessary actions for each valid breakpoint. Measures the cycle count and gets the
 /// name for all breakpoints and stores the result. Also sets a HW breakpoint if inside a hardware
 /// read.
-fn handle_breakpoint(
+fn handle_breakpoint<C: CoreAccess>(
     bkpt: &Breakpoint,
-    core: &mut Core,
-    measurements: &mut Vec<MeasurementResult>,
+    core: &mut C,
+    measurements: &mut Vec<Measurement>,
     current_hw_bkpt: &mut u32,
     app: &AppInfo,
+    strict_names: bool,
 ) -> Result<LoopAction> {
     let status = match bkpt {
         // On ReplayStart the loop is complete
@@ -251,16 +749,24 @@ fn handle_breakpoint(
         // Save the name and continue to the next loop iteration
         Breakpoint::Other(OtherBreakpoint::InsideTask) => {
             let name = read_breakpoint_task_name(core, &app.subprograms)?;
-            let (b, _, u) = measurements.pop().unwrap();
-            measurements.push((b, name, u));
+            if strict_names {
+                check_name_resolved(core, &name, "task")?;
+            }
+            if let Some(measurement) = measurements.last_mut() {
+                measurement.name = name;
+            }
 
             LoopAction::Continue
         }
         // Save the name and continue to the next loop iteration
         Breakpoint::Other(OtherBreakpoint::InsideLock) => {
             let name = read_breakpoint_lock_name(core, &app.resource_locks)?;
-            let (b, _, u) = measurements.pop().unwrap();
-            measurements.push((b, name, u));
+            if strict_names {
+                check_name_resolved(core, &name, "resource lock")?;
+            }
+            if let Some(measurement) = measurements.last_mut() {
+                measurement.name = name;
+            }
 
             LoopAction::Continue
         }
@@ -284,15 +790,38 @@ fn handle_breakpoint(
     Ok(status)
 }
 
+/// Returns an error pointing at the PC and LR if the name of a task or lock could not
+/// be resolved.
+///
+/// * `core` - A connected probe-rs _core_
+/// * `name` - The resolved name
+/// * `kind` - What the name belongs to, used in the error message
+fn check_name_resolved<C: CoreAccess>(core: &mut C, name: &str, kind: &str) -> Result<()> {
+    if name == BKPT_UNKNOWN_NAME {
+        let pc = core::current_pc(core)?;
+        let lr = core.read_core_reg(core.return_address())?;
+        return Err(anyhow!(
+            "Could not resolve the name of the {} at PC {:#010x} with LR {:#010x}. Is the binary built with debug info?",
+            kind,
+            pc,
+            lr
+        ));
+    }
+    Ok(())
+}
+
 /// Tries to read the name of the current task from the Subprograms.
 ///
 /// * `core` - A connected probe-rs _core_
 /// * `subprograms` - A list of the all the subprograms of the running program
-pub fn read_breakpoint_task_name(core: &mut Core, subprograms: &Vec<Subprogram>) -> Result<String> {
+pub fn read_breakpoint_task_name<C: CoreAccess>(
+    core: &mut C,
+    subprograms: &Vec<Subprogram>,
+) -> Result<String> {
     let optimal = get_current_task_from_lr(core, subprograms)?;
 
     let name = match optimal {
-        Some(s) => s.name,
+        Some(s) => s.display_name().to_string(),
         None => BKPT_UNKNOWN_NAME.to_string(),
     };
     Ok(name)
@@ -302,12 +831,12 @@ pub fn read_breakpoint_task_name(core: &mut Core, subprograms: &Vec<Subprogram>)
 ///
 /// * `core` - A connected probe-rs _core_
 /// * `vcells` - A list of all the vcell readings in the program
-pub fn get_current_vcell_from_lr(
-    core: &mut Core,
+pub fn get_current_vcell_from_lr<C: CoreAccess>(
+    core: &mut C,
     vcells: &Vec<Subroutine>,
 ) -> Result<Option<Subroutine>> {
     // We read the link register to check where to return after the breakpoint
-    let lr = core.registers().return_address();
+    let lr = core.return_address();
     // Decrement with 1 because otherwise it will point outside the vcell reading
     let lr_val = core.read_core_reg(lr)? - 1;
 
@@ -322,17 +851,17 @@ pub fn get_current_vcell_from_lr(
 ///
 /// * `core` - A connected probe-rs _core_
 /// * `subprograms` - A list of the all the subprograms of the running program
-pub fn get_current_task_from_lr(
-    core: &mut Core,
+pub fn get_current_task_from_lr<C: CoreAccess>(
+    core: &mut C,
     subprograms: &Vec<Subprogram>,
 ) -> Result<Option<Subprogram>> {
     // We read the link register to check where to return after the breakpoint
-    let lr = core.registers().return_address();
+    let lr = core.return_address();
     // This returns a PC inside the task we want to find the name for
     let lr_val = core.read_core_reg(lr)?;
 
     let in_range = dwarf::get_subprograms_address_in_range(subprograms, lr_val as u64)?;
-    let optimal = dwarf::get_shortest_range_subprogram(&in_range)?;
+    let optimal = dwarf::get_shortest_range_subprogram(&in_range, lr_val as u64)?;
 
     Ok(optimal)
 }
@@ -341,8 +870,8 @@ pub fn get_current_task_from_lr(
 ///
 /// * `core` - A connected probe-rs _core_
 /// * `resource_locks` - A lsit of all resource locks
-pub fn read_breakpoint_lock_name(
-    core: &mut Core,
+pub fn read_breakpoint_lock_name<C: CoreAccess>(
+    core: &mut C,
     resource_locks: &Vec<Subroutine>,
 ) -> Result<String> {
     let optimal = get_current_resource_lock(core, resource_locks)?;
@@ -359,12 +888,12 @@ pub fn read_breakpoint_lock_name(
 ///
 /// * `core` - A connected probe-rs _core_
 /// * `resource_locks` - A lsit of all resource locks
-pub fn get_current_resource_lock(
-    core: &mut Core,
+pub fn get_current_resource_lock<C: CoreAccess>(
+    core: &mut C,
     resource_locks: &Vec<Subroutine>,
 ) -> Result<Option<Subroutine>> {
     // We read the link register to check where to return after the breakpoint
-    let lr = core.registers().return_address();
+    let lr = core.return_address();
     // This returns a PC inside the task we want to find the name for
     let lr_val = core.read_core_reg(lr)?;
 
@@ -373,3 +902,641 @@ pub fn get_current_resource_lock(
 
     Ok(optimal)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::super::breakpoints::{EntryBreakpoint, ExitBreakpoint};
+    use super::super::objdump::Objdump;
+    use super::*;
+    use crate::cli::LogFormat;
+    use crate::logger;
+    use crate::utils::core::DwtCycleCounter;
+    use probe_rs::CoreStatus;
+    use simplelog::SharedLogger;
+    use std::collections::HashMap;
+    use std::io::Write;
+    use std::time::Duration;
+
+    const PC: u16 = 15;
+    const LR: u16 = 14;
+
+    /// A core that halts at a scripted sequence of breakpoints.
+    #[derive(Default)]
+    struct MockCore {
+        /// The `(pc, lr, cycle count)` of the upcoming halts, the next one last
+        halts: Vec<(u32, u32, u32)>,
+        memory: HashMap<u32, u8>,
+        registers: HashMap<u16, u32>,
+        cyccnt: u32,
+        resets: usize,
+    }
+
+    impl MockCore {
+        /// Creates a core that halts at the given breakpoints in order. Each breakpoint
+        /// is given as `(pc, immediate, lr, cycle count)`.
+        fn new(breakpoints: &[(u32, u8, u32, u32)]) -> MockCore {
+            let mut core = MockCore::default();
+            for (pc, imm, lr, cyccnt) in breakpoints.iter().rev() {
+                core.memory.insert(*pc, *imm);
+                core.memory.insert(pc + 1, 0b10111110);
+                core.halts.push((*pc, *lr, *cyccnt));
+            }
+            core
+        }
+    }
+
+    impl CoreAccess for MockCore {
+        fn run(&mut self) -> Result<()> {
+            let (pc, lr, cyccnt) = self.halts.pop().ok_or(anyhow!("No more scripted halts"))?;
+            self.registers.insert(PC, pc);
+            self.registers.insert(LR, lr);
+            self.cyccnt = cyccnt;
+            Ok(())
+        }
+
+        fn step(&mut self) -> Result<()> {
+            Ok(())
+        }
+
+        fn halt(&mut self, _timeout: Duration) -> Result<()> {
+            Ok(())
+        }
+
+        fn reset_and_halt(&mut self, _timeout: Duration) -> Result<()> {
+            self.resets += 1;
+            Ok(())
+        }
+
+        fn core_halted(&mut self) -> Result<bool> {
+            Ok(true)
+        }
+
+        fn wait_for_core_halted(&mut self, _timeout: Duration) -> Result<()> {
+            Ok(())
+        }
+
+        fn status(&mut self) -> Result<CoreStatus> {
+            Ok(CoreStatus::Unknown)
+        }
+
+        fn program_counter(&self) -> CoreRegisterAddress {
+            CoreRegisterAddress(PC)
+        }
+
+        fn return_address(&self) -> CoreRegisterAddress {
+            CoreRegisterAddress(LR)
+        }
+
+        fn read_core_reg(&mut self, address: CoreRegisterAddress) -> Result<u32> {
+            Ok(*self.registers.get(&address.0).unwrap_or(&0))
+        }
+
+        fn write_core_reg(&mut self, address: CoreRegisterAddress, value: u32) -> Result<()> {
+            self.registers.insert(address.0, value);
+            Ok(())
+        }
+
+        fn read_8(&mut self, address: u32, data: &mut [u8]) -> Result<()> {
+            for (offset, byte) in data.iter_mut().enumerate() {
+                *byte = *self.memory.get(&(address + offset as u32)).unwrap_or(&0);
+            }
+            Ok(())
+        }
+
+        fn read_32(&mut self, _address: u32, data: &mut [u32]) -> Result<()> {
+            data[0] = self.cyccnt;
+            Ok(())
+        }
+
+        fn write_word_32(&mut self, _address: u32, _data: u32) -> Result<()> {
+            Ok(())
+        }
+
+        fn write_8(&mut self, address: u32, data: &[u8]) -> Result<()> {
+            for (offset, byte) in data.iter().enumerate() {
+                self.memory.insert(address + offset as u32, *byte);
+            }
+            Ok(())
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+
+        fn set_hw_breakpoint(&mut self, _address: u32) -> Result<()> {
+            Ok(())
+        }
+
+        fn clear_hw_breakpoint(&mut self, _address: u32) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    fn app() -> AppInfo {
+        AppInfo {
+            subprograms: vec![Subprogram {
+                name: "uart0".to_string(),
+                linkage_name: "app::uart0".to_string(),
+                ranges: vec![(0x0800_0200, 0x0800_0220)],
+            }],
+            resource_locks: vec![],
+            variables: ObjectLocationMap::new(),
+            vcells: vec![],
+            objdump: Objdump::default(),
+            release: true,
+            endian: RunTimeEndian::Little,
+        }
+    }
+
+    /// A KTest file with the given objects in the binary format KLEE writes
+    fn ktest(objects: &[(&str, &[u8])]) -> KTest {
+        let mut data = b"KTEST".to_vec();
+        // Version 3 without arguments
+        for value in &[3u32, 0, 0, 0, objects.len() as u32] {
+            data.extend_from_slice(&value.to_be_bytes());
+        }
+        for (name, bytes) in objects {
+            data.extend_from_slice(&(name.len() as u32).to_be_bytes());
+            data.extend_from_slice(name.as_bytes());
+            data.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+            data.extend_from_slice(bytes);
+        }
+        ktest_parser::parse_ktest(&data).unwrap()
+    }
+
+    #[test]
+    fn test_breakpoint_log() {
+        let path = std::env::temp_dir().join("rauk-hardware-breakpoints.log");
+        let _ = std::fs::remove_file(&path);
+        let mut core = MockCore::new(&[
+            (0x0800_0100, 2, 0, 1000),
+            (0x0800_0104, 1, 0x0800_0210, 1100),
+            (0x0800_0108, 253, 0, 1500),
+            (0x0800_010c, 255, 0, 1600),
+        ]);
+
+        let mut log = BreakpointLog::open(&path).unwrap();
+        log.start_test_vector(1).unwrap();
+        read_breakpoints(
+            &mut core,
+            &DwtCycleCounter,
+            &ktest(&[]),
+            &app(),
+            1,
+            false,
+            Some(&mut log),
+        )
+        .unwrap();
+        drop(log);
+
+        let lines = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = lines.lines().collect();
+        assert_eq!(
+            lines,
+            vec![
+                "# test vector 1",
+                "0x08000100 2 1000",
+                "0x08000104 1 -",
+                "0x08000108 253 1500",
+                "0x0800010c 255 -",
+            ]
+        );
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_read_breakpoints() {
+        let mut core = MockCore::new(&[
+            (0x0800_0100, 2, 0, 1000),
+            (0x0800_0104, 1, 0x0800_0210, 1100),
+            (0x0800_0108, 253, 0, 1500),
+            (0x0800_010c, 255, 0, 1600),
+        ]);
+
+        let (measurements, _) = read_breakpoints(
+            &mut core,
+            &DwtCycleCounter,
+            &ktest(&[]),
+            &app(),
+            1,
+            false,
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            measurements,
+            vec![
+                Measurement::new(
+                    Breakpoint::Entry(EntryBreakpoint::HardwareTaskStart),
+                    "uart0".to_string(),
+                    1000
+                )
+                .with_pc(0x0800_0100),
+                Measurement::new(
+                    Breakpoint::Exit(ExitBreakpoint::HardwareTaskEnd),
+                    BKPT_UNKNOWN_NAME.to_string(),
+                    1500
+                )
+                .with_pc(0x0800_0108),
+            ]
+        );
+        assert!(core.halts.is_empty());
+    }
+
+    #[test]
+    fn test_read_breakpoints_strict_names() {
+        let breakpoints = [
+            (0x0800_0100, 2, 0, 1000),
+            // The LR is outside of all known tasks
+            (0x0800_0104, 1, 0x0800_0400, 1100),
+            (0x0800_0108, 253, 0, 1500),
+            (0x0800_010c, 255, 0, 1600),
+        ];
+
+        let mut core = MockCore::new(&breakpoints);
+        let (measurements, _) = read_breakpoints(
+            &mut core,
+            &DwtCycleCounter,
+            &ktest(&[]),
+            &app(),
+            1,
+            false,
+            None,
+        )
+        .unwrap();
+        assert_eq!(measurements[0].name(), BKPT_UNKNOWN_NAME);
+
+        let mut core = MockCore::new(&breakpoints);
+        let err = read_breakpoints(
+            &mut core,
+            &DwtCycleCounter,
+            &ktest(&[]),
+            &app(),
+            1,
+            true,
+            None,
+        )
+        .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("task at PC 0x08000104 with LR 0x08000400"));
+    }
+
+    #[test]
+    fn test_read_breakpoints_cycle_counter_wraps() {
+        let mut core = MockCore::new(&[
+            (0x0800_0100, 2, 0, u32::MAX - 5),
+            (0x0800_0108, 253, 0, 10),
+            (0x0800_010c, 255, 0, 20),
+        ]);
+
+        let (measurements, _) = read_breakpoints(
+            &mut core,
+            &DwtCycleCounter,
+            &ktest(&[]),
+            &app(),
+            1,
+            false,
+            None,
+        )
+        .unwrap();
+        assert_eq!(measurements[0].cycles(), u32::MAX as u64 - 5);
+        assert_eq!(measurements[1].cycles(), (1 << 32) + 10);
+    }
+
+    #[test]
+    fn test_analysis_error_contains_pc() {
+        // A hardware task that ends with the exit of a software task
+        let mut core = MockCore::new(&[
+            (0x0800_0100, 2, 0, 1000),
+            (0x0800_0104, 1, 0x0800_0210, 1100),
+            (0x0800_0118, 251, 0, 1500),
+            (0x0800_010c, 255, 0, 1600),
+        ]);
+
+        let (measurements, _) = read_breakpoints(
+            &mut core,
+            &DwtCycleCounter,
+            &ktest(&[]),
+            &app(),
+            1,
+            false,
+            None,
+        )
+        .unwrap();
+        assert_eq!(measurements[1].pc(), Some(0x0800_0118));
+
+        let err = super::super::trace::wcet_analysis(measurements).unwrap_err();
+        let message = format!("{:#}", err);
+        assert!(message.contains("scope not matching"));
+        assert!(message.contains("at 0x08000118"));
+    }
+
+    #[test]
+    fn test_write_replay_objects_skips_ignored() {
+        let mut locations = ObjectLocationMap::new();
+        locations.insert("res1".to_string(), Some(0x2000_0000));
+        locations.insert("model_version".to_string(), Some(0x2000_0010));
+        locations.insert("debug_counter".to_string(), Some(0x2000_0020));
+        let ktest = ktest(&[
+            ("model_version", &[1, 0, 0, 0]),
+            ("res1", &[0x2a, 0, 0, 0]),
+            ("debug_counter", &[7, 0, 0, 0]),
+            ("vcell", &[9, 0, 0, 0]),
+        ]);
+        // A vcell reading is never written to RAM, even if a variable has its name
+        locations.insert("vcell".to_string(), Some(0x2000_0030));
+
+        let mut core = MockCore::default();
+        write_replay_objects(&mut core, &locations, &ktest, &[]).unwrap();
+        assert_eq!(core.memory.get(&0x2000_0000), Some(&0x2a));
+        assert_eq!(core.memory.get(&0x2000_0010), None);
+        assert_eq!(core.memory.get(&0x2000_0020), Some(&7));
+        assert_eq!(core.memory.get(&0x2000_0030), None);
+
+        // A custom list replaces the default
+        let mut core = MockCore::default();
+        write_replay_objects(&mut core, &locations, &ktest, &["debug_".to_string()]).unwrap();
+        assert_eq!(core.memory.get(&0x2000_0000), Some(&0x2a));
+        assert_eq!(core.memory.get(&0x2000_0010), Some(&1));
+        assert_eq!(core.memory.get(&0x2000_0020), None);
+    }
+
+    #[test]
+    fn test_read_breakpoints_halt_without_breakpoint() {
+        let mut core = MockCore::new(&[(0x0800_0100, 2, 0, 1000)]);
+        // Halts at an address without a breakpoint instruction
+        core.halts.insert(0, (0x0800_0200, 0, 1100));
+
+        let err = read_breakpoints(
+            &mut core,
+            &DwtCycleCounter,
+            &ktest(&[]),
+            &app(),
+            1,
+            false,
+            None,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("not due to a breakpoint"));
+    }
+
+    #[test]
+    fn test_read_breakpoints_vcell_usage() {
+        let mut app = app();
+        app.release = false;
+        app.vcells = vec![Subroutine {
+            name: "vcell::VolatileCell<u32>::get".to_string(),
+            ranges: vec![(0x0800_0210, 0x0800_0214)],
+        }];
+        let ktest = ktest(&[
+            ("vcell", &[0x78, 0x56, 0x34, 0x12]),
+            ("vcell", &[0x01, 0x00, 0x00, 0x00]),
+        ]);
+        let mut core = MockCore::new(&[
+            (0x0800_0100, 2, 0, 1000),
+            (0x0800_0104, 1, 0x0800_0210, 1100),
+            (0x0800_0110, 5, 0x0800_0211, 1200),
+            // The hardware breakpoint at the end of the vcell reading
+            (0x0800_0214, 0, 0, 1250),
+            (0x0800_0108, 253, 0, 1500),
+            (0x0800_010c, 255, 0, 1600),
+        ]);
+
+        let (_, usage) =
+            read_breakpoints(&mut core, &DwtCycleCounter, &ktest, &app, 1, false, None).unwrap();
+        assert_eq!(
+            usage,
+            VcellUsage {
+                consumed: 1,
+                remaining: 1
+            }
+        );
+        assert_eq!(core.registers.get(&0), Some(&0x1234_5678));
+    }
+
+    #[test]
+    fn test_read_breakpoints_hard_fault() {
+        let mut app = app();
+        app.subprograms.push(Subprogram {
+            name: "HardFault_".to_string(),
+            linkage_name: "HardFault_".to_string(),
+            ranges: vec![(0x0800_0500, 0x0800_0510)],
+        });
+        let mut core = MockCore::new(&[
+            (0x0800_0100, 2, 0, 1000),
+            (0x0800_0104, 1, 0x0800_0210, 1100),
+        ]);
+        // Faults inside the task and halts in the handler
+        core.halts.insert(0, (0x0800_0508, 0, 1200));
+
+        let err = read_breakpoints(
+            &mut core,
+            &DwtCycleCounter,
+            &ktest(&[]),
+            &app,
+            1,
+            false,
+            None,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "The application halted in a HardFault ('HardFault_' at 0x08000508) during task 'uart0'"
+        );
+    }
+
+    #[test]
+    fn test_fault_handler_at() {
+        let subprograms = vec![Subprogram {
+            name: "panic".to_string(),
+            linkage_name: "rust_begin_unwind".to_string(),
+            ranges: vec![(0x0800_0600, 0x0800_0620)],
+        }];
+        assert_eq!(
+            fault_handler_at(0x0800_0610, &subprograms),
+            Some(("panic", "panic".to_string()))
+        );
+        assert_eq!(fault_handler_at(0x0800_0700, &subprograms), None);
+        assert_eq!(fault_handler_at(0x0800_0210, &app().subprograms), None);
+    }
+
+    /// Writer that keeps everything the log file logger writes to it
+    #[derive(Clone, Default)]
+    struct LogSink(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl Write for LogSink {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_measurement_warnings_reach_log_file() {
+        let sink = LogSink::default();
+        let file_logger = logger::file_logger(LogFormat::Human, sink.clone()).as_log();
+
+        let replay = |_: &mut MockCore, _: usize| -> Result<Vec<Measurement>> {
+            Err(anyhow!("Timed out").context(HaltTimeout))
+        };
+        let mut core = MockCore::new(&[(0x0800_010c, 255, 0, 0)]);
+        replay_test_vectors(&mut core, 1, true, file_logger.as_ref(), replay).unwrap();
+
+        let output = String::from_utf8(sink.0.lock().unwrap().clone()).unwrap();
+        assert!(output.contains("Test vector 1 did not halt in time"));
+    }
+
+    #[test]
+    fn test_replay_test_vectors_skips_hung_vector() {
+        let replay = |_: &mut MockCore, index: usize| -> Result<Vec<Measurement>> {
+            if index == 1 {
+                Err(anyhow!("Timed out").context(HaltTimeout))
+            } else {
+                let entry = Breakpoint::Entry(EntryBreakpoint::HardwareTaskStart);
+                Ok(vec![Measurement::new(entry, format!("task{}", index), 0)])
+            }
+        };
+
+        // Starts over at the ReplayStart breakpoint after the reset
+        let mut core = MockCore::new(&[(0x0800_010c, 255, 0, 0)]);
+        let (measurements, skipped) =
+            replay_test_vectors(&mut core, 3, true, log::logger(), replay).unwrap();
+        let names: Vec<&str> = measurements.iter().map(|m| m[0].name()).collect();
+        assert_eq!(names, vec!["task0", "task2"]);
+        assert_eq!(skipped, vec![1]);
+        assert_eq!(core.resets, 1);
+        assert!(core.halts.is_empty());
+
+        // Without recovery the measurement stops
+        let mut core = MockCore::default();
+        let err = replay_test_vectors(&mut core, 3, false, log::logger(), replay).unwrap_err();
+        assert!(err.downcast_ref::<HaltTimeout>().is_some());
+        assert_eq!(core.resets, 0);
+    }
+
+    #[test]
+    fn test_cycle_counter_overflow() {
+        let mut counter = CycleCounter::new(1 << 32);
+        let readings = [100, 5_000, u32::MAX - 10, 20, 3_000, 10];
+        let counts: Vec<u64> = readings.iter().map(|c| counter.extend(*c)).collect();
+        assert_eq!(
+            counts,
+            vec![
+                100,
+                5_000,
+                u32::MAX as u64 - 10,
+                (1 << 32) + 20,
+                (1 << 32) + 3_000,
+                (2 << 32) + 10,
+            ]
+        );
+        // Durations across the wraps are corrected
+        assert_eq!(counts[3] - counts[2], 31);
+        assert_eq!(counts[5] - counts[4], u32::MAX as u64 + 1 - 2_990);
+    }
+
+    #[test]
+    fn test_parse_breakpoint() {
+        assert_eq!(
+            parse_breakpoint(2, 0x0800_0100).unwrap(),
+            Breakpoint::from(2)
+        );
+
+        let err = parse_breakpoint(42, 0x0800_0100).unwrap_err();
+        assert!(err.to_string().contains("immediate 42 at 0x08000100"));
+    }
+
+    #[test]
+    fn test_max_of_samples() {
+        let entry = Breakpoint::Entry(EntryBreakpoint::HardwareTaskStart);
+        let exit = Breakpoint::Exit(ExitBreakpoint::HardwareTaskEnd);
+        let sample = |start: u64, lengths: (u64, u64)| {
+            vec![
+                Measurement::new(entry.clone(), "uart0".to_string(), start),
+                Measurement::new(entry.clone(), "res1".to_string(), start + lengths.0),
+                Measurement::new(exit.clone(), "uart0".to_string(), start + lengths.1),
+            ]
+        };
+        // The longest step to the lock and the longest step after it come from different
+        // samples. The combined task is at least as long as in either sample.
+        let samples = vec![sample(5000, (50, 90)), sample(1000, (10, 100))];
+
+        let (combined, spread) = max_of_samples(samples).unwrap();
+        assert_eq!(combined, sample(5000, (50, 140)));
+        assert_eq!(spread, 10);
+
+        // A sample whose cycle counts go backwards is reported instead of wrapping
+        let mut backwards = sample(1000, (10, 100));
+        backwards[2].cycles = 900;
+        let err = max_of_samples(vec![sample(5000, (50, 90)), backwards]).unwrap_err();
+        assert!(err.to_string().contains("before the cycle count"));
+
+        // Different paths can't be combined
+        let mut diverged = sample(5000, (12, 96));
+        diverged[1].name = "res2".to_string();
+        let err = max_of_samples(vec![sample(1000, (10, 100)), diverged]).unwrap_err();
+        assert!(err.to_string().contains("different breakpoints"));
+
+        // A shorter replay is reported as such
+        let mut shorter = sample(5000, (12, 96));
+        shorter.pop();
+        let err = max_of_samples(vec![sample(1000, (10, 100)), shorter]).unwrap_err();
+        assert!(err.to_string().contains("different number of breakpoints"));
+
+        // A single sample is kept as measured
+        let (single, spread) = max_of_samples(vec![sample(1000, (10, 100))]).unwrap();
+        assert_eq!(single, sample(1000, (10, 100)));
+        assert_eq!(spread, 0);
+    }
+
+    #[test]
+    fn test_register_value_endianness() {
+        let bytes = [0x78, 0x56, 0x34, 0x12];
+        assert_eq!(register_value(bytes, RunTimeEndian::Little), 0x1234_5678);
+        assert_eq!(register_value(bytes, RunTimeEndian::Big), 0x7856_3412);
+    }
+
+    #[test]
+    fn test_progress_message() {
+        assert_eq!(progress_message(1, 4), "Measuring test vector 1/4 (25%)");
+        assert_eq!(progress_message(3, 12), "Measuring test vector 3/12 (25%)");
+        assert_eq!(progress_message(2, 3), "Measuring test vector 2/3 (66%)");
+        assert_eq!(
+            progress_message(12, 12),
+            "Measuring test vector 12/12 (100%)"
+        );
+    }
+
+    #[test]
+    fn test_read_breakpoint_task_name_interrupt_handler() {
+        let mut subprograms = app().subprograms;
+        // The DWARF name of the handler is reserved, so it is only known from the vector table
+        let handler = Subprogram {
+            name: "UART1".to_string(),
+            linkage_name: "UART1".to_string(),
+            ranges: vec![(0x0800_0300, 0x0800_0340)],
+        };
+        super::super::vector_table::add_interrupt_handlers(&mut subprograms, vec![handler]);
+        let mut core = MockCore::default();
+
+        core.registers.insert(LR, 0x0800_0311);
+        assert_eq!(
+            read_breakpoint_task_name(&mut core, &subprograms).unwrap(),
+            "UART1"
+        );
+        core.registers.insert(LR, 0x0800_0211);
+        assert_eq!(
+            read_breakpoint_task_name(&mut core, &subprograms).unwrap(),
+            "uart0"
+        );
+        core.registers.insert(LR, 0x0800_0411);
+        assert_eq!(
+            read_breakpoint_task_name(&mut core, &subprograms).unwrap(),
+            BKPT_UNKNOWN_NAME
+        );
+    }
+}
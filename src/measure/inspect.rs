@@ -0,0 +1,150 @@
+use super::dwarf::{self, DwarfCache, ObjectLocation, Subprogram, Subroutine};
+use super::DEFAULT_RAM_START;
+use crate::cli::{InspectInput, OutputFormat};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+
+/// What rauk finds in the DWARF of a binary.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InspectOutput {
+    /// The variables and their addresses, sorted by name
+    pub objects: Vec<ObjectLocation>,
+    /// The subprograms, sorted by name
+    pub subprograms: Vec<Subprogram>,
+    /// The inlined subroutines, sorted by name
+    pub subroutines: Vec<Subroutine>,
+}
+
+impl From<DwarfCache> for InspectOutput {
+    fn from(cache: DwarfCache) -> InspectOutput {
+        let mut objects: Vec<ObjectLocation> = cache
+            .variables
+            .into_iter()
+            .map(|(name, address)| ObjectLocation { name, address })
+            .collect();
+        objects.sort_by(|a, b| a.name.cmp(&b.name));
+        let mut subprograms = cache.subprograms;
+        subprograms.sort();
+        let mut subroutines = cache.subroutines;
+        subroutines.sort();
+
+        InspectOutput {
+            objects,
+            subprograms,
+            subroutines,
+        }
+    }
+}
+
+/// Parses the DWARF of a binary and prints the objects, subprograms and subroutines
+/// in the requested format.
+///
+/// * `input` - Input for this command
+pub fn inspect_binary(input: &InspectInput) -> Result<()> {
+    let binary = fs::read(&input.binary)
+        .with_context(|| format!("Could not read binary {:?}", &input.binary))?;
+    let ram_start = input.ram_start.unwrap_or(DEFAULT_RAM_START);
+    let cache = super::parse_dwarf(&binary, dwarf::binary_hash(&binary), ram_start)
+        .with_context(|| format!("Could not parse the DWARF of {:?}", &input.binary))?;
+    let output = InspectOutput::from(cache);
+
+    match input.format {
+        OutputFormat::Text => print!("{}", format_text(&output)),
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&output)?),
+    }
+    Ok(())
+}
+
+/// Formats the output as one line per item, grouped by kind.
+fn format_text(output: &InspectOutput) -> String {
+    let ranges = |ranges: &Vec<(u64, u64)>| -> String {
+        ranges
+            .iter()
+            .map(|(low_pc, high_pc)| format!("{:#010x}-{:#010x}", low_pc, high_pc))
+            .collect::<Vec<String>>()
+            .join(", ")
+    };
+
+    let mut text = String::from("Objects:\n");
+    for object in &output.objects {
+        match object.address {
+            Some(address) => text += &format!("  {:#010x} {}\n", address, object.name),
+            None => text += &format!("  <no address> {}\n", object.name),
+        }
+    }
+    text += "Subprograms:\n";
+    for subprogram in &output.subprograms {
+        text += &format!(
+            "  {} ({}) {}\n",
+            subprogram.display_name(),
+            subprogram.linkage_name,
+            ranges(&subprogram.ranges)
+        );
+    }
+    text += "Subroutines:\n";
+    for subroutine in &output.subroutines {
+        text += &format!("  {} {}\n", subroutine.name, ranges(&subroutine.ranges));
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::measure::dwarf::ObjectLocationMap;
+
+    fn output() -> InspectOutput {
+        let mut variables = ObjectLocationMap::new();
+        variables.insert("app::resources::res2".to_string(), None);
+        variables.insert("app::resources::res1".to_string(), Some(0x2000_0004));
+        InspectOutput::from(DwarfCache {
+            version: String::new(),
+            hash: 0,
+            ram_start: DEFAULT_RAM_START,
+            variables,
+            subprograms: vec![Subprogram {
+                name: "uart0".to_string(),
+                linkage_name: "app::uart0".to_string(),
+                ranges: vec![(0x0800_0200, 0x0800_0220)],
+            }],
+            subroutines: vec![Subroutine {
+                name: "<vcell::VolatileCell<T>>::get".to_string(),
+                ranges: vec![(0x0800_0210, 0x0800_0214)],
+            }],
+        })
+    }
+
+    #[test]
+    fn test_inspect_json() {
+        let json = serde_json::to_string(&output()).unwrap();
+        let parsed: InspectOutput = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(parsed, output());
+        let res1 = parsed
+            .objects
+            .iter()
+            .find(|object| object.name == "app::resources::res1")
+            .unwrap();
+        assert_eq!(res1.address, Some(0x2000_0004));
+        assert_eq!(
+            parsed.subprograms[0].ranges,
+            vec![(0x0800_0200, 0x0800_0220)]
+        );
+    }
+
+    #[test]
+    fn test_inspect_text() {
+        assert_eq!(
+            format_text(&output()),
+            "Objects:
+  0x20000004 app::resources::res1
+  <no address> app::resources::res2
+Subprograms:
+  uart0 (app::uart0) 0x08000200-0x08000220
+Subroutines:
+  <vcell::VolatileCell<T>>::get 0x08000210-0x08000214
+"
+        );
+    }
+}
@@ -1,17 +1,92 @@
 use anyhow::{Context, Result};
 use glob::glob;
 use ktest_parser::{KTest, KTestObject};
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::Read;
 use std::path::PathBuf;
 
+/// Name prefixes of the objects KLEE adds to each KTest for its own bookkeeping. These are
+/// never written to the target.
+pub const DEFAULT_IGNORED_OBJECTS: &[&str] = &["model_version"];
+
 /// Reads and parses the latest generated KTest binaries in the given path.
 ///
 /// # Arguments
 /// * `target_dir` - The directory where KLEE outputs its files.
 pub fn parse_ktest_files(target_dir: &PathBuf) -> Result<Vec<KTest>> {
-    let klee_last = target_dir.clone();
-    let ktest_pattern = klee_last.to_str().unwrap().to_owned() + "*.ktest";
+    let ktests: Vec<KTest> = read_ktest_files(target_dir)?
+        .into_iter()
+        .map(|(_, ktest)| ktest)
+        .collect();
+
+    let (ktests, removed) = dedup_ktests(ktests);
+    if removed > 0 {
+        info!("Removed {} duplicate test vectors", removed);
+    }
+
+    Ok(ktests)
+}
+
+/// Helpers for the symbolic objects of a [`KTest`].
+///
+/// ```
+/// use rauk::measure::{parse_ktest_reader, KTestExt};
+///
+/// let mut data = b"KTEST".to_vec();
+/// // Version, arguments, symbolic argvs and their length, then two objects
+/// for value in &[3u32, 0, 0, 0, 2] {
+///     data.extend_from_slice(&value.to_be_bytes());
+/// }
+/// for (name, bytes) in &[("task", &[1u8][..]), ("res1", &[1, 0, 0, 0][..])] {
+///     data.extend_from_slice(&(name.len() as u32).to_be_bytes());
+///     data.extend_from_slice(name.as_bytes());
+///     data.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+///     data.extend_from_slice(bytes);
+/// }
+///
+/// let ktest = parse_ktest_reader(&data[..])?;
+/// assert_eq!(ktest.object_count(), 2);
+/// assert_eq!(ktest.total_bytes(), 5);
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+pub trait KTestExt {
+    /// Returns the number of symbolic objects.
+    fn object_count(&self) -> usize;
+    /// Returns the total size of the symbolic objects in bytes.
+    fn total_bytes(&self) -> usize;
+}
+
+impl KTestExt for KTest {
+    fn object_count(&self) -> usize {
+        self.objects.len()
+    }
+
+    fn total_bytes(&self) -> usize {
+        self.objects.iter().map(|object| object.bytes.len()).sum()
+    }
+}
+
+/// Reads a KTest from a stream, e.g. a file or stdin, and parses it.
+///
+/// * `reader` - The stream with the KTest in the binary format KLEE writes
+pub fn parse_ktest_reader<R: Read>(mut reader: R) -> Result<KTest> {
+    let mut data: Vec<u8> = Vec::new();
+    reader
+        .read_to_end(&mut data)
+        .context("Could not read the KTest")?;
+    let ktest = ktest_parser::parse_ktest(&data)?;
+    Ok(ktest)
+}
+
+/// Reads and parses the KTest binaries in the given path, in the order of their file names.
+/// Returns each KTest with its path.
+///
+/// * `target_dir` - The directory where KLEE outputs its files.
+pub fn read_ktest_files(target_dir: &PathBuf) -> Result<Vec<(PathBuf, KTest)>> {
+    let ktest_pattern = target_dir.join("*.ktest");
     let mut ktest_paths: Vec<PathBuf> = Vec::new();
-    let klee_glob = glob(ktest_pattern.as_str()).context("Failed to read glob pattern")?;
+    let klee_glob = glob(ktest_pattern.to_str().unwrap()).context("Failed to read glob pattern")?;
     for path in klee_glob {
         match path {
             Ok(p) => ktest_paths.push(p),
@@ -20,24 +95,199 @@ pub fn parse_ktest_files(target_dir: &PathBuf) -> Result<Vec<KTest>> {
     }
 
     // Convert ktests to struct
-    let mut ktests: Vec<KTest> = Vec::new();
+    let mut ktests: Vec<(PathBuf, KTest)> = Vec::new();
     for path in ktest_paths {
-        let data = std::fs::read(path)?;
-        let ktest = ktest_parser::parse_ktest(&data)?;
-        ktests.push(ktest);
+        let file = File::open(&path)?;
+        let ktest =
+            parse_ktest_reader(file).with_context(|| format!("Invalid KTest file {:?}", &path))?;
+        ktests.push((path, ktest));
     }
-
     Ok(ktests)
 }
 
+/// Lists the KTests in the given path with the name and size of each object, e.g.
+///
+/// ```text
+/// test000001.ktest
+///   task: 1 byte(s)
+///   res1: 4 byte(s)
+/// ```
+///
+/// * `target_dir` - The directory where KLEE outputs its files.
+pub fn list_ktest_files(target_dir: &PathBuf) -> Result<String> {
+    let mut listing = String::new();
+    for (path, ktest) in read_ktest_files(target_dir)? {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        listing.push_str(&format!("{}\n", name));
+        for object in &ktest.objects {
+            listing.push_str(&format!(
+                "  {}: {} byte(s)\n",
+                object.name,
+                object.bytes.len()
+            ));
+        }
+    }
+    Ok(listing)
+}
+
+/// Removes the KTests that write the same bytes to the same objects as an earlier KTest.
+/// Returns the remaining KTests and the number of removed duplicates.
+pub fn dedup_ktests(ktests: Vec<KTest>) -> (Vec<KTest>, usize) {
+    let total = ktests.len();
+    let mut seen: HashSet<Vec<(String, Vec<u8>)>> = HashSet::new();
+    let unique: Vec<KTest> = ktests
+        .into_iter()
+        .filter(|ktest| {
+            let objects = ktest
+                .objects
+                .iter()
+                .map(|object| (object.name.clone(), object.bytes.clone()))
+                .collect();
+            seen.insert(objects)
+        })
+        .collect();
+    let removed = total - unique.len();
+    (unique, removed)
+}
+
+/// Returns true if the name of the object starts with one of the `prefixes`.
+///
+/// * `name` - The name of the KTest object
+/// * `prefixes` - The name prefixes of the objects to ignore
+pub fn is_ignored_object<S: AsRef<str>>(name: &str, prefixes: &[S]) -> bool {
+    prefixes
+        .iter()
+        .any(|prefix| name.starts_with(prefix.as_ref()))
+}
+
+/// Returns true if the object is a test vector for a peripheral read. The patched vcell
+/// crate names the symbolic value of each read "vcell", while all other objects are named
+/// after the resource they are written to.
+pub fn is_vcell_object(object: &KTestObject) -> bool {
+    object.name.contains("vcell")
+}
+
 /// Returns a list of all KTestObjects that contains the name "vcell".
 pub fn get_vcell_ktestobjects(ktest: &KTest) -> Vec<KTestObject> {
     let mut vcells: Vec<KTestObject> = Vec::new();
 
     for object in &ktest.objects {
-        if object.name.contains("vcell") {
+        if is_vcell_object(object) {
             vcells.push(object.clone());
         }
     }
     vcells
 }
+
+/// Returns a list of all KTestObjects that are written to resources, i.e. all objects
+/// that are not vcell readings.
+pub fn get_resource_ktestobjects(ktest: &KTest) -> Vec<KTestObject> {
+    ktest
+        .objects
+        .iter()
+        .filter(|object| !is_vcell_object(object))
+        .cloned()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a KTest file in the binary format KLEE writes.
+    fn ktest_bytes(objects: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut data = b"KTEST".to_vec();
+        data.extend_from_slice(&3u32.to_be_bytes()); // version
+        data.extend_from_slice(&0u32.to_be_bytes()); // number of arguments
+        data.extend_from_slice(&0u32.to_be_bytes()); // sym argvs
+        data.extend_from_slice(&0u32.to_be_bytes()); // sym argv length
+        data.extend_from_slice(&(objects.len() as u32).to_be_bytes());
+        for (name, bytes) in objects {
+            data.extend_from_slice(&(name.len() as u32).to_be_bytes());
+            data.extend_from_slice(name.as_bytes());
+            data.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+            data.extend_from_slice(bytes);
+        }
+        data
+    }
+
+    fn ktest(objects: &[(&str, &[u8])]) -> KTest {
+        ktest_parser::parse_ktest(&ktest_bytes(objects)).unwrap()
+    }
+
+    #[test]
+    fn test_parse_ktest_reader() {
+        let data = ktest_bytes(&[("task", &[1]), ("res1", &[1, 0, 0, 0])]);
+        let ktest = parse_ktest_reader(std::io::Cursor::new(&data)).unwrap();
+        assert_eq!(ktest.objects.len(), 2);
+        assert_eq!(ktest.objects[1].name, "res1");
+        assert_eq!(ktest.objects[1].bytes, vec![1, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_partition_ktest_objects() {
+        let ktest = ktest(&[
+            ("res1", &[1, 0, 0, 0]),
+            ("vcell", &[2, 0, 0, 0]),
+            ("task", &[0]),
+            ("vcell", &[3, 0, 0, 0]),
+        ]);
+
+        let vcells: Vec<Vec<u8>> = get_vcell_ktestobjects(&ktest)
+            .into_iter()
+            .map(|object| object.bytes)
+            .collect();
+        assert_eq!(vcells, vec![vec![2, 0, 0, 0], vec![3, 0, 0, 0]]);
+
+        let resources: Vec<String> = get_resource_ktestobjects(&ktest)
+            .into_iter()
+            .map(|object| object.name)
+            .collect();
+        assert_eq!(resources, vec!["res1".to_string(), "task".to_string()]);
+    }
+
+    #[test]
+    fn test_list_ktest_files() {
+        let dir = std::env::temp_dir()
+            .join("rauk-klee-list")
+            .join("klee-last");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("test000002.ktest"),
+            ktest_bytes(&[("task", &[1]), ("vcell", &[0, 0, 0, 0])]),
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("test000001.ktest"),
+            ktest_bytes(&[("task", &[0]), ("res1", &[1, 0, 0, 0])]),
+        )
+        .unwrap();
+        std::fs::write(dir.join("info"), "KLEE info").unwrap();
+
+        assert_eq!(
+            list_ktest_files(&dir).unwrap(),
+            "test000001.ktest\n  task: 1 byte(s)\n  res1: 4 byte(s)\n\
+             test000002.ktest\n  task: 1 byte(s)\n  vcell: 4 byte(s)\n"
+        );
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_dedup_ktests() {
+        let ktests = vec![
+            ktest(&[("task", &[0]), ("res1", &[1, 0, 0, 0])]),
+            ktest(&[("task", &[1]), ("res1", &[1, 0, 0, 0])]),
+            ktest(&[("task", &[0]), ("res1", &[1, 0, 0, 0])]),
+            ktest(&[("task", &[1]), ("res1", &[1, 0, 0, 0])]),
+            ktest(&[("task", &[0]), ("res1", &[2, 0, 0, 0])]),
+        ];
+
+        let (unique, removed) = dedup_ktests(ktests);
+        assert_eq!(unique.len(), 3);
+        assert_eq!(removed, 2);
+        assert_eq!(unique[0].objects[1].bytes, vec![1, 0, 0, 0]);
+        assert_eq!(unique[2].objects[1].bytes, vec![2, 0, 0, 0]);
+    }
+}
@@ -0,0 +1,149 @@
+use super::dwarf::ObjectLocationMap;
+use rustc_demangle::demangle;
+use std::collections::HashMap;
+
+/// An object whose address in the DWARF differs from the address in the linker map.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AddressMismatch {
+    /// The name of the object in the DWARF
+    pub name: String,
+    /// The demangled symbol in the linker map
+    pub symbol: String,
+    /// The address resolved from the DWARF
+    pub dwarf_address: Option<u64>,
+    /// The address in the linker map
+    pub map_address: u64,
+}
+
+/// Parses the symbols and their addresses from a linker map. Supports the map formats
+/// of GNU ld and LLVM lld. The symbols are demangled without their hash.
+///
+/// * `contents` - The contents of the `.map` file
+pub fn parse_linker_map(contents: &str) -> HashMap<String, u64> {
+    let mut symbols: HashMap<String, u64> = HashMap::new();
+    for line in contents.lines() {
+        let columns: Vec<&str> = line.split_whitespace().collect();
+        let symbol = match columns.as_slice() {
+            // GNU ld: `0x20000000    symbol`
+            [address, name] if address.starts_with("0x") => u64::from_str_radix(&address[2..], 16)
+                .ok()
+                .map(|a| (a, *name)),
+            // lld: `VMA LMA Size Align symbol`
+            [vma, lma, size, align, name] if !name.starts_with('.') && !name.contains(":(") => {
+                let hex = |s: &str| u64::from_str_radix(s, 16).ok();
+                match (hex(vma), hex(lma), hex(size), hex(align)) {
+                    (Some(a), Some(_), Some(_), Some(_)) => Some((a, *name)),
+                    _ => None,
+                }
+            }
+            _ => None,
+        };
+        if let Some((address, name)) = symbol {
+            symbols.insert(format!("{:#}", demangle(name)), address);
+        }
+    }
+    symbols
+}
+
+/// Compares the addresses resolved from the DWARF with the addresses in the linker map.
+/// An object is matched with the symbol of the same name, or the only symbol whose path
+/// ends with the name. Objects without a unique symbol are skipped.
+///
+/// * `locations` - The object addresses resolved from the DWARF
+/// * `symbols` - The symbols parsed from the linker map
+pub fn address_mismatches(
+    locations: &ObjectLocationMap,
+    symbols: &HashMap<String, u64>,
+) -> Vec<AddressMismatch> {
+    let mut mismatches: Vec<AddressMismatch> = Vec::new();
+    for (name, dwarf_address) in locations {
+        let suffix = format!("::{}", name);
+        let matching: Vec<(&String, &u64)> = match symbols.get_key_value(name) {
+            Some(symbol) => vec![symbol],
+            None => symbols
+                .iter()
+                .filter(|(symbol, _)| symbol.ends_with(&suffix))
+                .collect(),
+        };
+        if let [(symbol, map_address)] = matching.as_slice() {
+            if *dwarf_address != Some(**map_address) {
+                mismatches.push(AddressMismatch {
+                    name: name.clone(),
+                    symbol: symbol.to_string(),
+                    dwarf_address: *dwarf_address,
+                    map_address: **map_address,
+                });
+            }
+        }
+    }
+    mismatches.sort_by(|a, b| a.name.cmp(&b.name));
+    mismatches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GNU_MAP: &str = "
+.bss            0x20000000       0x10
+ .bss._ZN3app9resources4RES117h0123456789abcdefE
+                0x20000000        0x4 target/app.o
+                0x20000000                _ZN3app9resources4RES117h0123456789abcdefE
+                0x20000004                _ZN3app9resources4RES217h0123456789abcdefE
+                0x20000008                COUNTER
+                0x20000010                _ebss = .
+";
+
+    const LLD_MAP: &str = "
+     VMA      LMA     Size Align Out     In      Symbol
+20000000 20000000       10     4 .bss
+20000000 20000000        4     4         target/app.o:(.bss._ZN3app9resources4RES117h0123456789abcdefE)
+20000000 20000000        4     1                 _ZN3app9resources4RES117h0123456789abcdefE
+2000000c 2000000c        4     1                 COUNTER
+";
+
+    #[test]
+    fn test_parse_gnu_linker_map() {
+        let symbols = parse_linker_map(GNU_MAP);
+        assert_eq!(symbols.len(), 3);
+        assert_eq!(symbols.get("app::resources::RES1"), Some(&0x2000_0000));
+        assert_eq!(symbols.get("app::resources::RES2"), Some(&0x2000_0004));
+        assert_eq!(symbols.get("COUNTER"), Some(&0x2000_0008));
+    }
+
+    #[test]
+    fn test_parse_lld_linker_map() {
+        let symbols = parse_linker_map(LLD_MAP);
+        assert_eq!(symbols.len(), 2);
+        assert_eq!(symbols.get("app::resources::RES1"), Some(&0x2000_0000));
+        assert_eq!(symbols.get("COUNTER"), Some(&0x2000_000c));
+    }
+
+    #[test]
+    fn test_address_mismatches() {
+        let symbols = parse_linker_map(GNU_MAP);
+        let mut locations = ObjectLocationMap::new();
+        locations.insert("RES1".to_string(), Some(0x2000_0000));
+        locations.insert("RES2".to_string(), Some(0x2000_0040));
+        locations.insert("COUNTER".to_string(), None);
+        locations.insert("NOT_IN_MAP".to_string(), Some(0x2000_0100));
+
+        assert_eq!(
+            address_mismatches(&locations, &symbols),
+            vec![
+                AddressMismatch {
+                    name: "COUNTER".to_string(),
+                    symbol: "COUNTER".to_string(),
+                    dwarf_address: None,
+                    map_address: 0x2000_0008,
+                },
+                AddressMismatch {
+                    name: "RES2".to_string(),
+                    symbol: "app::resources::RES2".to_string(),
+                    dwarf_address: Some(0x2000_0040),
+                    map_address: 0x2000_0004,
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,132 @@
+use super::trace::{Trace, TraceType};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// For each resource, the resources that are locked while it is held.
+type LockGraph = BTreeMap<String, BTreeSet<String>>;
+
+/// Returns the cycles in the order the resources are locked in across all traces. A
+/// cycle means the resources are locked in an inconsistent order, e.g. one task locks
+/// A then B while another locks B then A. Each cycle is listed once, starting with its
+/// alphabetically first resource.
+///
+/// * `traces` - The traces of all test vectors
+pub fn lock_order_cycles(traces: &[Trace]) -> Vec<Vec<String>> {
+    let mut graph = LockGraph::new();
+    let mut held: Vec<String> = Vec::new();
+    collect_lock_order(traces, &mut held, &mut graph);
+
+    let mut cycles: Vec<Vec<String>> = Vec::new();
+    for start in graph.keys() {
+        let mut path = vec![start.clone()];
+        find_cycles(&graph, start, start, &mut path, &mut cycles);
+    }
+    cycles
+}
+
+/// Adds an edge from each held resource to every resource locked while it is held.
+/// A task that preempts another starts with no held resources.
+fn collect_lock_order(traces: &[Trace], held: &mut Vec<String>, graph: &mut LockGraph) {
+    for trace in traces {
+        if trace.ttype == TraceType::ResourceLock {
+            for resource in held.iter().filter(|resource| **resource != trace.name) {
+                graph
+                    .entry(resource.clone())
+                    .or_default()
+                    .insert(trace.name.clone());
+            }
+            held.push(trace.name.clone());
+            collect_lock_order(&trace.inner, held, graph);
+            held.pop();
+        } else {
+            collect_lock_order(&trace.inner, &mut Vec::new(), graph);
+        }
+    }
+}
+
+/// Finds the cycles through `start` where all other resources come after `start`, so
+/// each cycle is only found from its first resource.
+fn find_cycles(
+    graph: &LockGraph,
+    start: &str,
+    node: &str,
+    path: &mut Vec<String>,
+    cycles: &mut Vec<Vec<String>>,
+) {
+    if let Some(next_resources) = graph.get(node) {
+        for next in next_resources {
+            if next == start {
+                cycles.push(path.clone());
+            } else if next.as_str() > start && !path.contains(next) {
+                path.push(next.clone());
+                find_cycles(graph, start, next, path, cycles);
+                path.pop();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trace(name: &str, ttype: TraceType, inner: Vec<Trace>) -> Trace {
+        Trace {
+            name: name.to_string(),
+            ttype,
+            start: 0,
+            inner,
+            end: 0,
+        }
+    }
+
+    fn lock(name: &str, inner: Vec<Trace>) -> Trace {
+        trace(name, TraceType::ResourceLock, inner)
+    }
+
+    #[test]
+    fn test_inconsistent_lock_order() {
+        let traces = vec![
+            trace(
+                "task1",
+                TraceType::SoftwareTask,
+                vec![lock("A", vec![lock("B", vec![])])],
+            ),
+            trace(
+                "task2",
+                TraceType::HardwareTask,
+                vec![lock("B", vec![lock("A", vec![])])],
+            ),
+        ];
+
+        assert_eq!(
+            lock_order_cycles(&traces),
+            vec![vec!["A".to_string(), "B".to_string()]]
+        );
+    }
+
+    #[test]
+    fn test_consistent_lock_order() {
+        let traces = vec![
+            trace(
+                "task1",
+                TraceType::SoftwareTask,
+                vec![lock("A", vec![lock("B", vec![lock("C", vec![])])])],
+            ),
+            trace(
+                "task2",
+                TraceType::HardwareTask,
+                // A preempting task does not hold the locks of the preempted task
+                vec![lock(
+                    "B",
+                    vec![trace(
+                        "uart0",
+                        TraceType::HardwareTask,
+                        vec![lock("A", vec![])],
+                    )],
+                )],
+            ),
+        ];
+
+        assert!(lock_order_cycles(&traces).is_empty());
+    }
+}
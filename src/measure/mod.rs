@@ -1,23 +1,37 @@
 mod breakpoints;
+mod diff;
 mod dwarf;
 mod hardware;
+mod inspect;
 mod klee;
+mod linker_map;
+mod lock_order;
 mod objdump;
 mod trace;
+mod vector_table;
 
-use self::dwarf::{ObjectLocationMap, Subprogram, Subroutine};
+use self::dwarf::{DwarfCache, ObjectLocationMap, Subprogram, Subroutine};
 use self::objdump::Objdump;
+use crate::cargo;
 use crate::cli::MeasureInput;
 use crate::metadata::RaukMetadata;
+use crate::settings::RaukSettings;
 use crate::utils::core;
-use crate::RaukSettings;
 use anyhow::{anyhow, Context, Result};
-use hardware::MeasurementResult;
+use hardware::Measurement;
 use object::Object;
 use std::path::PathBuf;
 use std::{borrow, fs};
 use trace::Trace;
 
+pub use self::diff::diff_measurements;
+pub(crate) use self::dwarf::DEFAULT_RAM_START;
+pub use self::inspect::inspect_binary;
+pub(crate) use self::klee::list_ktest_files;
+pub use self::klee::{parse_ktest_reader, KTestExt};
+pub(crate) use self::objdump::DEFAULT_OBJDUMP;
+pub(crate) use hardware::DEFAULT_HALT_TIMEOUT_SECONDS;
+
 const RAUK_JSON_OUTPUT: &str = "rauk.json";
 
 /// Contains information about the RTIC application mostly
@@ -35,6 +49,8 @@ pub struct AppInfo {
     objdump: Objdump,
     /// Is the app compile in release mode
     release: bool,
+    /// The byte order of the target
+    endian: gimli::RunTimeEndian,
 }
 
 /// Measure the replay harness using the generated test vectors to get a
@@ -46,31 +62,38 @@ pub struct AppInfo {
 pub fn wcet_measurement(
     input: &MeasureInput,
     settings: &RaukSettings,
-    metadata: &RaukMetadata,
+    metadata: &mut RaukMetadata,
 ) -> Result<Option<PathBuf>> {
-    let (dwarf_path, ktests_path) = get_analysis_paths(&input, &metadata)?;
     let mut updated_input = input.clone();
     updated_input.get_missing_input(settings);
+    let (dwarf_path, ktests_path) = get_analysis_paths(&updated_input, settings, &metadata)?;
+    // Checked before the slower parsing of the DWARF and test vectors
+    if let Some(chip) = &updated_input.chip {
+        core::validate_chip(chip)?;
+    }
 
     let file = fs::File::open(&dwarf_path)?;
     let mmap = unsafe { memmap::Mmap::map(&file)? };
-    let object = object::File::parse(&*mmap)?;
-    let endian = if object.is_little_endian() {
-        gimli::RunTimeEndian::Little
-    } else {
-        gimli::RunTimeEndian::Big
+    let hash = dwarf::binary_hash(&mmap);
+    let endian = object_endian(&object::File::parse(&*mmap)?);
+    let ram_start = updated_input.ram_start.unwrap_or(DEFAULT_RAM_START);
+    let cache_path = metadata.rauk_output_directory.join(dwarf::DWARF_CACHE_FILE);
+    let dwarf_info = match dwarf::load_cache(&cache_path, hash, ram_start) {
+        Some(cache) => {
+            info!("Using cached DWARF");
+            cache
+        }
+        None => {
+            info!("Parsing DWARF");
+            let cache = parse_dwarf(&mmap, hash, ram_start)?;
+            if let Err(e) = dwarf::save_cache(&cache_path, &cache) {
+                warn!("{:?}", e);
+            }
+            cache
+        }
     };
-    let dwarf_cow = dwarf::load_dwarf_from_file(object)?;
-
-    // Borrow a `Cow<[u8]>` to create an `EndianSlice`.
-    let borrow_section: &dyn for<'a> Fn(
-        &'a borrow::Cow<[u8]>,
-    ) -> gimli::EndianSlice<'a, gimli::RunTimeEndian> =
-        &|section| gimli::EndianSlice::new(&*section, endian);
-
-    // Create `EndianSlice`s for all of the sections.
-    let dwarf = dwarf_cow.borrow(&borrow_section);
 
+    info!("Parsing ktests");
     let ktests = klee::parse_ktest_files(&ktests_path)?;
     if ktests.is_empty() {
         return Err(anyhow!(
@@ -78,86 +101,282 @@ pub fn wcet_measurement(
         ));
     }
 
-    let addr = dwarf::get_replay_addresses(&dwarf)?;
-    let subprograms = dwarf::get_subprograms(&dwarf)?;
-    let subroutines = dwarf::get_subroutines(&dwarf)?;
-    let resources = dwarf::get_resources_from_subroutines(&subroutines);
-    let vcells = dwarf::get_vcell_from_subroutines(&subroutines);
-    let objdump = objdump::disassemble(&dwarf_path).context("Could not disassemble the binary")?;
+    let resources = dwarf::get_resources_from_subroutines(&dwarf_info.subroutines);
+    let vcells = if input.vcell_patterns.is_empty() {
+        dwarf::get_vcell_from_subroutines(&dwarf_info.subroutines, dwarf::DEFAULT_VCELL_PATTERNS)
+    } else {
+        dwarf::get_vcell_from_subroutines(&dwarf_info.subroutines, &input.vcell_patterns)
+    };
+    info!("Disassembling binary");
+    let objdump_program = updated_input
+        .objdump
+        .as_deref()
+        .unwrap_or(objdump::DEFAULT_OBJDUMP);
+    let objdump = objdump::disassemble(&dwarf_path, objdump_program)
+        .context("Could not disassemble the binary")?;
+    // Checked before attaching, otherwise the measurement waits for the harness until the timeout
+    objdump::check_replay_harness(&objdump)?;
+    check_linker_map(&dwarf_path, &dwarf_info.variables);
+    // Stored before measuring so the addresses are available if writing the replay objects fails
+    metadata.update_object_locations(&input.build, &dwarf_info.variables);
+
     let app = AppInfo {
-        subprograms,
+        subprograms: dwarf_info.subprograms,
         resource_locks: resources,
-        variables: addr,
+        variables: dwarf_info.variables,
         vcells,
         objdump,
         release: input.is_release(),
+        endian,
     };
 
     let mut session = if let Some(chip) = updated_input.chip {
-        core::open_and_attach_probe(&chip)?
+        core::open_and_attach_probe(&chip, false, &input.probe)?
     } else {
         return Err(anyhow!(
             "Cannot attach to hardware. No chip type given as input"
         ));
     };
-    let mut core = session.core(0)?;
+    let mut core = core::attach_core(&mut session, input.core)?;
+
+    let mut breakpoint_log = if input.trace_breakpoints {
+        let path = metadata
+            .rauk_output_directory
+            .join(hardware::BREAKPOINT_LOG_FILE);
+        info!("Logging the breakpoints to {:?}", path);
+        Some(hardware::BreakpointLog::open(&path)?)
+    } else {
+        None
+    };
 
-    let measurements = hardware::measure_replay_harness(input, &mut core, &ktests, &app)
-        .context("Could not complete the measurement of the replay harness")?;
+    info!("Measuring replay harness");
+    let measurements =
+        hardware::measure_replay_harness(input, &mut core, &ktests, &app, breakpoint_log.as_mut())
+            .context("Could not complete the measurement of the replay harness")?;
 
     let traces = post_measurement_analysis(measurements)
         .context("Could not complete the analysis of measurement data")?;
-    println!("{:#?}", traces);
+    let traces = trace::filter_tasks(traces, &input.tasks);
 
-    let output_path = save_traces_to_directory(&traces, &metadata.rauk_output_directory)?;
+    let mut known_tasks = dwarf::get_rtic_task_names(&app.subprograms);
+    for task in &input.tasks {
+        if !known_tasks.contains(task) {
+            warn!(
+                "Task '{}' given with --task is not a task of the application",
+                task
+            );
+        }
+    }
+    if !input.tasks.is_empty() {
+        known_tasks.retain(|task| input.tasks.contains(task));
+    }
+    for task in trace::unmeasured_tasks(&known_tasks, &traces) {
+        warn!(
+            "Task '{}' was never measured. No test vector exercised it",
+            task
+        );
+    }
+    for cycle in lock_order::lock_order_cycles(&traces) {
+        warn!(
+            "Resources are locked in an inconsistent order: {} -> {}",
+            cycle.join(" -> "),
+            cycle[0]
+        );
+    }
+    trace::print_traces(&traces, input.quiet, &mut std::io::stdout())?;
+
+    let output_path =
+        save_traces_to_directory(traces, &metadata.rauk_output_directory, input.append)?;
 
     Ok(Some(output_path))
 }
 
-fn post_measurement_analysis(measurements: Vec<Vec<MeasurementResult>>) -> Result<Vec<Trace>> {
+/// Parses the variables, subprograms and subroutines from the DWARF of a binary. The
+/// interrupt handlers in the vector table are added to the subprograms so hardware tasks
+/// can be named even if their DWARF name is missing.
+///
+/// * `binary` - The contents of the binary
+/// * `hash` - The hash of the binary
+/// * `ram_start` - The start address of the RAM
+fn parse_dwarf(binary: &[u8], hash: u64, ram_start: u64) -> Result<DwarfCache> {
+    let object = object::File::parse(binary)?;
+    let endian = object_endian(&object);
+    let handlers = vector_table::get_interrupt_handlers(&object, endian)?;
+    let dwarf_cow = dwarf::load_dwarf_from_file(object)?;
+
+    // Borrow a `Cow<[u8]>` to create an `EndianSlice`.
+    let borrow_section: &dyn for<'a> Fn(
+        &'a borrow::Cow<[u8]>,
+    ) -> gimli::EndianSlice<'a, gimli::RunTimeEndian> =
+        &|section| gimli::EndianSlice::new(&*section, endian);
+
+    // Create `EndianSlice`s for all of the sections.
+    let dwarf = dwarf_cow.borrow(&borrow_section);
+
+    let mut subprograms = dwarf::get_subprograms(&dwarf, true)?;
+    vector_table::add_interrupt_handlers(&mut subprograms, handlers);
+
+    Ok(DwarfCache {
+        version: dwarf::DWARF_CACHE_VERSION.to_string(),
+        hash,
+        ram_start,
+        variables: dwarf::get_replay_addresses(&dwarf, ram_start)?,
+        subprograms,
+        subroutines: dwarf::get_subroutines(&dwarf)?,
+    })
+}
+
+/// Returns the byte order of the target the binary is built for.
+fn object_endian(object: &object::File) -> gimli::RunTimeEndian {
+    if object.is_little_endian() {
+        gimli::RunTimeEndian::Little
+    } else {
+        gimli::RunTimeEndian::Big
+    }
+}
+
+/// Turns the measurement of each test vector into traces. Returns an error naming the
+/// test vector if its breakpoints can't be matched into traces.
+///
+/// * `measurements` - The number of each test vector with its measurement
+fn post_measurement_analysis(measurements: Vec<(usize, Vec<Measurement>)>) -> Result<Vec<Trace>> {
     let mut traces: Vec<Trace> = Vec::new();
-    for measurement in measurements {
-        if let Ok(mut trace) = trace::wcet_analysis(measurement) {
-            traces.append(&mut trace);
+    for (number, measurement) in measurements {
+        let mut trace = trace::wcet_analysis(measurement).with_context(|| {
+            format!(
+                "Could not analyse the measurement of test vector {}",
+                number
+            )
+        })?;
+        match trace::idle_times(&trace) {
+            Ok(idle_times) => {
+                for idle in idle_times {
+                    info!(
+                        "Idle for {} cycles between task '{}' and task '{}'",
+                        idle.cycles, idle.after, idle.before
+                    );
+                }
+            }
+            Err(e) => warn!("{:?}", e),
         }
+        traces.append(&mut trace);
     }
     Ok(traces)
 }
 
-/// Get the necessary paths for analysis.
-fn get_analysis_paths(input: &MeasureInput, metadata: &RaukMetadata) -> Result<(PathBuf, PathBuf)> {
+/// Warns about variables whose address differs from the linker map next to the binary,
+/// e.g. `target/thumbv7em-none-eabi/release/app.map`. Does nothing if there is no map.
+fn check_linker_map(binary_path: &PathBuf, variables: &ObjectLocationMap) {
+    let map_path = binary_path.with_extension("map");
+    let contents = match fs::read_to_string(&map_path) {
+        Ok(contents) => contents,
+        Err(_) => return,
+    };
+    info!("Checking variable addresses against {:?}", &map_path);
+    let symbols = linker_map::parse_linker_map(&contents);
+    for mismatch in linker_map::address_mismatches(variables, &symbols) {
+        warn!(
+            "Address of '{}' is {:x?} in the DWARF but {:#x} for '{}' in the linker map. Was the binary built with the same configuration as the flashed one?",
+            mismatch.name, mismatch.dwarf_address, mismatch.map_address, mismatch.symbol
+        );
+    }
+}
+
+/// Get the necessary paths for analysis. The DWARF is read from the replay harness
+/// flashed by rauk, or from where cargo builds it if it was not flashed by rauk.
+fn get_analysis_paths(
+    input: &MeasureInput,
+    settings: &RaukSettings,
+    metadata: &RaukMetadata,
+) -> Result<(PathBuf, PathBuf)> {
     let (name, example) = (input.get_name(), input.is_example());
     let artifact = metadata.get_artifact_detail(&name, input.is_release(), example);
 
-    let mut dwarf_path: PathBuf = PathBuf::new();
-    let mut ktests_path: PathBuf = PathBuf::new();
-
-    if let Some(artifact) = artifact {
-        dwarf_path = match &input.dwarf {
-            Some(path) => path.clone(),
-            None => match artifact.get_dwarf_path() {
-                Some(path) => path,
-                None => return Err(anyhow!("No path to DWARF was given/found")),
-            },
-        };
-
-        ktests_path = match &input.ktests {
-            Some(path) => path.clone(),
-            None => match artifact.get_ktest_path() {
-                Some(path) => path,
-                None => return Err(anyhow!("No path to KTESTS found/given")),
-            },
-        };
-    }
+    let dwarf_path = match (&input.dwarf, artifact.and_then(|a| a.get_dwarf_path())) {
+        (Some(path), _) => path.clone(),
+        (None, Some(path)) => path,
+        (None, None) => {
+            let target_dir = cargo::resolve_target_directory(&metadata.cargo_manifest(), true)?;
+            cargo::executable_path(
+                &target_dir,
+                settings.replay_target().as_deref(),
+                input.is_release(),
+                name,
+                example,
+            )
+        }
+    };
+
+    let ktests_path = match (&input.ktests, artifact.and_then(|a| a.get_ktest_path())) {
+        (Some(path), _) => path.clone(),
+        (None, Some(path)) => path,
+        (None, None) => return Err(anyhow!("No path to KTESTS found/given")),
+    };
 
     Ok((dwarf_path, ktests_path))
 }
 
 /// Saves the analysis result to project directory.
-fn save_traces_to_directory(traces: &Vec<Trace>, project_dir: &PathBuf) -> Result<PathBuf> {
+fn save_traces_to_directory(
+    traces: Vec<Trace>,
+    project_dir: &PathBuf,
+    append: bool,
+) -> Result<PathBuf> {
     let mut path = project_dir.clone();
     path.push(RAUK_JSON_OUTPUT);
-    let serialized = serde_json::to_string(traces)?;
+    let traces = if append && path.exists() {
+        let data = fs::read_to_string(&path)
+            .with_context(|| format!("Could not read traces from {:?}", &path))?;
+        let existing: Vec<Trace> = serde_json::from_str(&data)
+            .with_context(|| format!("Could not parse traces in {:?}", &path))?;
+        trace::merge_traces(existing, traces)
+    } else {
+        traces
+    };
+    let serialized = serde_json::to_string(&traces)?;
     fs::write(&path, serialized)?;
     Ok(path)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::breakpoints::{Breakpoint, EntryBreakpoint, ExitBreakpoint};
+    use super::*;
+
+    #[test]
+    fn test_post_measurement_analysis() {
+        let entry = Breakpoint::Entry(EntryBreakpoint::HardwareTaskStart);
+        let exit = Breakpoint::Exit(ExitBreakpoint::HardwareTaskEnd);
+        let lock_exit = Breakpoint::Exit(ExitBreakpoint::ResourceLockEnd);
+        let valid = vec![
+            Measurement::new(entry.clone(), "uart0".to_string(), 100),
+            Measurement::new(exit, "uart0".to_string(), 250),
+        ];
+
+        let traces = post_measurement_analysis(vec![(1, valid.clone())]).unwrap();
+        assert_eq!(traces.len(), 1);
+        assert_eq!(traces[0].name, "uart0");
+
+        // A scope mismatch is reported with its test vector and PC
+        let mismatched = vec![
+            Measurement::new(entry, "uart0".to_string(), 100),
+            Measurement::new(lock_exit, "res1".to_string(), 200).with_pc(0x0800_0118),
+        ];
+        let err = post_measurement_analysis(vec![(1, valid), (3, mismatched)]).unwrap_err();
+        let message = format!("{:#}", err);
+        assert!(message.starts_with("Could not analyse the measurement of test vector 3"));
+        assert!(message.contains("scope not matching"));
+        assert!(message.contains("at 0x08000118"));
+    }
+
+    #[test]
+    fn test_post_measurement_analysis_corrupted_stream() {
+        // An exit without an entry fails the measurement instead of truncating it
+        let exit = Breakpoint::Exit(ExitBreakpoint::HardwareTaskEnd);
+        let corrupted = vec![Measurement::new(exit, "uart0".to_string(), 300)];
+        let err = post_measurement_analysis(vec![(2, corrupted)]).unwrap_err();
+        let message = format!("{:#}", err);
+        assert!(message.starts_with("Could not analyse the measurement of test vector 2"));
+        assert!(message.contains("empty scope stack"));
+    }
+}
@@ -1,8 +1,15 @@
-use anyhow::Result;
+use super::breakpoints::OtherBreakpoint;
+use crate::utils::command as command_utils;
+use anyhow::{anyhow, Result};
 use std::{collections::HashMap, path::PathBuf, process::Command};
 
+/// The llvm-objdump binary used if none is given
+pub const DEFAULT_OBJDUMP: &str = "llvm-objdump";
+/// How to install llvm-objdump if it is not found
+const OBJDUMP_INSTALL_HINT: &str = "It is part of LLVM, e.g. the llvm package of your distribution";
+
 /// The results/output of llvm-objdump on the rtic binary
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct Objdump {
     instructions: HashMap<u64, String>,
 }
@@ -16,43 +23,204 @@ impl Objdump {
             None
         }
     }
+
+    /// Returns true if there is a `bkpt` instruction with the immediate `imm`.
+    pub fn contains_breakpoint(&self, imm: u8) -> bool {
+        self.instructions
+            .values()
+            .any(|instruction| parse_breakpoint_immediate(instruction) == Some(imm))
+    }
+}
+
+/// Checks that the disassembled binary is a replay harness, i.e. that it contains the
+/// ReplayStart breakpoint. Otherwise the measurement would wait for a breakpoint that
+/// never comes until the halt timeout.
+///
+/// * `objdump` - The disassembled binary
+pub fn check_replay_harness(objdump: &Objdump) -> Result<()> {
+    let replay_start = OtherBreakpoint::ReplayStart as u8;
+    if !objdump.contains_breakpoint(replay_start) {
+        return Err(anyhow!(
+            "The binary has no ReplayStart breakpoint (bkpt #{:#x}) and is not a replay harness. Was it built with the replay feature? Check the replay-feature setting and flash again without --no-patch",
+            replay_start
+        ));
+    }
+    Ok(())
+}
+
+/// Returns the immediate of a breakpoint instruction such as `bkpt #0xff`.
+fn parse_breakpoint_immediate(instruction: &str) -> Option<u8> {
+    let mut split = instruction.split_whitespace();
+    if split.next()? != "bkpt" {
+        return None;
+    }
+    let imm = split.next()?.trim_start_matches('#');
+    match imm.strip_prefix("0x") {
+        Some(hex) => u8::from_str_radix(hex, 16).ok(),
+        None => imm.parse().ok(),
+    }
+}
+
+/// Disassembles a binary at `binary_path` using `llvm-objdump`. Returns an error with
+/// the stderr of llvm-objdump if it fails.
+///
+/// * `binary_path` - The path to the binary
+/// * `objdump` - The name or path of the llvm-objdump binary
+pub fn disassemble(binary_path: &PathBuf, objdump: &str) -> Result<Objdump> {
+    let output = objdump_command(binary_path, objdump)
+        .output()
+        .map_err(|e| command_utils::spawn_error(e, objdump, OBJDUMP_INSTALL_HINT))?;
+
+    if !output.status.success() {
+        return Err(anyhow!(
+            "{} failed to disassemble {:?} ({}): {}",
+            objdump,
+            binary_path,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    let result = String::from_utf8(output.stdout)?;
+    Ok(parse_objdump(&result))
 }
 
-/// Disassembles a binary at `binary_path` using `llvm-bojdump`
-pub fn disassemble(binary_path: &PathBuf) -> Result<Objdump> {
-    let mut objdump = Command::new("llvm-objdump");
+/// Parses the `address: instruction` lines of the output of llvm-objdump. Symbol headers
+/// such as `08000194 <main>:`, section headers and any other lines are skipped.
+fn parse_objdump(output: &str) -> Objdump {
+    let mut map: HashMap<u64, String> = HashMap::new();
+    for line in output.lines() {
+        let line = line.replace("\t", " ");
+        let line = line.trim();
+        if line.ends_with(">:") {
+            continue;
+        }
+        if let Some((address, instruction)) = parse_instruction_line(line) {
+            map.insert(address, instruction);
+        }
+    }
+    Objdump { instructions: map }
+}
 
-    objdump
+/// Returns the address and instruction of a line such as `8000194: bl #0x3c <foo>`.
+fn parse_instruction_line(line: &str) -> Option<(u64, String)> {
+    let index = line.find(':')?;
+    let (address, instruction) = line.split_at(index);
+    let instruction = instruction[1..].trim();
+    if address.is_empty() || !address.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    if instruction.is_empty() {
+        return None;
+    }
+    let address = u64::from_str_radix(address, 16).ok()?;
+    Some((address, instruction.to_string()))
+}
+
+/// Returns the llvm-objdump command that disassembles the binary.
+fn objdump_command(binary_path: &PathBuf, objdump: &str) -> Command {
+    let mut command = Command::new(objdump);
+    command
         .arg("--disassemble")
         .arg("--print-imm-hex")
         .arg("--no-show-raw-insn")
         .arg(binary_path.to_str().unwrap());
+    command
+}
 
-    let output = objdump.output()?;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    let result = String::from_utf8(output.stdout)?;
-    let iter = result
-        .split("\n")
-        .filter(|x| !x.is_empty())
-        .map(|x| x.replace("\t", " "));
+    const OBJDUMP_OUTPUT: &str = "
+app:\tfile format elf32-littlearm
 
-    let mut map: HashMap<u64, String> = HashMap::new();
+Disassembly of section .text:
 
-    // find and add only addresses/instructions to the map
-    for i in iter {
-        let line = i.trim();
-        if line.starts_with("8") {
-            if let Some(index) = line.find(":") {
-                let (address, instruction) = line.split_at(index);
-                let instruction = instruction.strip_prefix(":").unwrap();
-                let instruction = instruction.trim();
-                let address = u64::from_str_radix(address, 16)?;
-                map.insert(address, instruction.to_string());
-            }
-        }
+08000194 <main>:
+ 8000194:      \tpush\t{r7, lr}
+ 8000196:      \tbl\t#0x3c <app::init>
+ 800019a:      \tpop\t{r7, pc}
+
+080001d4 <app::init>:
+ 80001d4:      \tbkpt\t#0xff
+ 80001d6:      \tbx\tlr
+\t\t...
+
+Disassembly of section .data:
+
+20000000 <COUNTER>:
+20000000:      \tmovs\tr0, r0
+";
+
+    #[test]
+    fn test_parse_objdump() {
+        let objdump = parse_objdump(OBJDUMP_OUTPUT);
+        assert_eq!(objdump.instructions.len(), 6);
+        assert_eq!(
+            objdump.get_instruction(&0x0800_0194),
+            Some("push {r7, lr}".to_string())
+        );
+        assert_eq!(
+            objdump.get_instruction(&0x0800_0196),
+            Some("bl #0x3c <app::init>".to_string())
+        );
+        assert_eq!(
+            objdump.get_instruction(&0x0800_01d4),
+            Some("bkpt #0xff".to_string())
+        );
+        assert_eq!(
+            objdump.get_instruction(&0x2000_0000),
+            Some("movs r0, r0".to_string())
+        );
     }
 
-    let result = Objdump { instructions: map };
+    #[test]
+    fn test_check_replay_harness() {
+        let objdump = parse_objdump(OBJDUMP_OUTPUT);
+        assert!(objdump.contains_breakpoint(0xff));
+        assert!(!objdump.contains_breakpoint(2));
+        assert!(check_replay_harness(&objdump).is_ok());
 
-    Ok(result)
+        // Built without the replay feature there are no breakpoints
+        let output = OBJDUMP_OUTPUT.replace("bkpt\t#0xff", "nop");
+        let error = check_replay_harness(&parse_objdump(&output)).unwrap_err();
+        assert!(error.to_string().contains("not a replay harness"));
+
+        assert_eq!(parse_breakpoint_immediate("bkpt #0x3"), Some(3));
+        assert_eq!(parse_breakpoint_immediate("bkpt #255"), Some(255));
+        assert_eq!(parse_breakpoint_immediate("bl #0x3c <app::init>"), None);
+    }
+
+    #[test]
+    fn test_parse_objdump_skips_headers() {
+        assert_eq!(parse_instruction_line("08000194 <main>:"), None);
+        assert_eq!(
+            parse_instruction_line("Disassembly of section .text:"),
+            None
+        );
+        assert_eq!(
+            parse_instruction_line("app: file format elf32-littlearm"),
+            None
+        );
+        assert_eq!(parse_instruction_line("8000194:"), None);
+        assert_eq!(
+            parse_instruction_line("8000194: bx lr"),
+            Some((0x0800_0194, "bx lr".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_disassemble_failing_objdump() {
+        // `false` ignores its arguments and exits with an error
+        let err = disassemble(&PathBuf::from("app"), "false").unwrap_err();
+        assert!(err.to_string().starts_with("false failed to disassemble"));
+    }
+
+    #[test]
+    fn test_objdump_command_custom_binary() {
+        let command = objdump_command(&PathBuf::from("app"), "llvm-objdump-15");
+        assert_eq!(command.get_program(), "llvm-objdump-15");
+        assert_eq!(command.get_args().last().unwrap(), "app");
+    }
 }
@@ -1,9 +1,10 @@
 use super::{
     breakpoints::{Breakpoint, EntryBreakpoint},
-    hardware::MeasurementResult,
+    hardware::Measurement,
 };
 use anyhow::{anyhow, Context, Result};
 use serde::{Deserialize, Serialize};
+use std::io::Write;
 
 /// The different types a Trace can be
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -31,15 +32,21 @@ pub struct Trace {
     /// The type of trace of the object.
     pub ttype: TraceType,
     /// Clock cycle when this object is executing.
-    pub start: u32,
+    pub start: u64,
     /// List of critical sections and blocking objects.
     pub inner: Vec<Trace>,
     /// Clock cycle when this oject has finished executing.
-    pub end: u32,
+    pub end: u64,
 }
 
 impl Trace {
-    fn new(name: String, ttype: TraceType, start: u32, inner: Vec<Trace>, end: u32) -> Trace {
+    /// Returns the number of clock cycles between the start and end of this trace.
+    pub fn duration(&self) -> Result<u64> {
+        cycle_difference(self.start, self.end)
+            .with_context(|| format!("Invalid cycle counts for trace {:?}", &self.name))
+    }
+
+    fn new(name: String, ttype: TraceType, start: u64, inner: Vec<Trace>, end: u64) -> Trace {
         Trace {
             name,
             ttype,
@@ -50,38 +57,161 @@ impl Trace {
     }
 }
 
+/// The idle cycles between two consecutive top-level task traces of a replay.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IdleTime {
+    /// The name of the task that finished before the idle time
+    pub after: String,
+    /// The name of the task that started after the idle time
+    pub before: String,
+    /// The number of cycles between the two tasks
+    pub cycles: u64,
+}
+
+/// Returns the number of cycles from `start` to `end`. The cycle counts are already
+/// corrected for wraps of the 32-bit cycle counter when measuring. Returns an error if
+/// `end` is before `start`, i.e. the breakpoints arrived out of order.
+pub fn cycle_difference(start: u64, end: u64) -> Result<u64> {
+    end.checked_sub(start).ok_or(anyhow!(
+        "Cycle count {} at the end is before the cycle count {} at the start",
+        end,
+        start
+    ))
+}
+
+/// Checks that the cycle counts of all traces and their inner traces are in order.
+fn validate_durations(traces: &[Trace]) -> Result<()> {
+    for trace in traces {
+        trace.duration()?;
+        validate_durations(&trace.inner)?;
+    }
+    Ok(())
+}
+
 /// Run a WCET analysis on the given measurements and return a list of traces.
 ///
-/// * `measurements` - A list of MeasurementResults measured on hardware
-pub fn wcet_analysis(mut measurements: Vec<MeasurementResult>) -> Result<Vec<Trace>> {
+/// * `measurements` - A list of Measurements measured on hardware
+pub fn wcet_analysis(mut measurements: Vec<Measurement>) -> Result<Vec<Trace>> {
     let mut temp: Vec<EntryBreakpoint> = Vec::new();
     measurements.reverse();
     let (traces, _) = wcet_rec(&mut measurements, &mut temp)?;
+    validate_durations(&traces)?;
     Ok(traces)
 }
 
+/// Returns the idle cycles between each pair of consecutive top-level task traces. The
+/// traces must be of a single replay and in the order they were measured.
+///
+/// * `traces` - The top-level traces of a replay
+pub fn idle_times(traces: &[Trace]) -> Result<Vec<IdleTime>> {
+    traces
+        .windows(2)
+        .map(|pair| {
+            let cycles = cycle_difference(pair[0].end, pair[1].start).with_context(|| {
+                format!(
+                    "Task {:?} starts before task {:?} has finished",
+                    &pair[1].name, &pair[0].name
+                )
+            })?;
+            Ok(IdleTime {
+                after: pair[0].name.clone(),
+                before: pair[1].name.clone(),
+                cycles,
+            })
+        })
+        .collect()
+}
+
+/// Returns the traces whose top-level task is one of `tasks`. Returns all traces if
+/// `tasks` is empty.
+///
+/// * `traces` - The traces of all test vectors
+/// * `tasks` - The names of the tasks to keep
+pub fn filter_tasks(traces: Vec<Trace>, tasks: &[String]) -> Vec<Trace> {
+    if tasks.is_empty() {
+        return traces;
+    }
+    traces
+        .into_iter()
+        .filter(|trace| tasks.contains(&trace.name))
+        .collect()
+}
+
+/// Appends the new traces to the existing ones, skipping traces identical to one
+/// already included.
+///
+/// * `existing` - The previously saved traces
+/// * `new` - The traces of this measurement
+pub fn merge_traces(existing: Vec<Trace>, new: Vec<Trace>) -> Vec<Trace> {
+    let mut merged = existing;
+    for trace in new {
+        if !merged.contains(&trace) {
+            merged.push(trace);
+        }
+    }
+    merged
+}
+
+/// Prints the traces to `out` unless `quiet` is set.
+///
+/// * `traces` - The traces of all test vectors
+/// * `quiet` - If only errors should be printed
+/// * `out` - Where to print the traces, stdout when measuring
+pub fn print_traces<W: Write>(traces: &[Trace], quiet: bool, out: &mut W) -> Result<()> {
+    if !quiet {
+        writeln!(out, "{:#?}", traces)?;
+    }
+    Ok(())
+}
+
+/// Returns the known tasks that are not measured in any of the traces, i.e. tasks that
+/// no test vector exercised.
+///
+/// * `known_tasks` - The names of all tasks in the RTIC application
+/// * `traces` - The traces of all test vectors
+pub fn unmeasured_tasks(known_tasks: &[String], traces: &[Trace]) -> Vec<String> {
+    let mut measured: Vec<&str> = Vec::new();
+    collect_task_names(traces, &mut measured);
+
+    known_tasks
+        .iter()
+        .filter(|task| !measured.contains(&task.as_str()))
+        .cloned()
+        .collect()
+}
+
+/// Collects the names of all task traces, including tasks that preempted another task.
+fn collect_task_names<'a>(traces: &'a [Trace], names: &mut Vec<&'a str>) {
+    for trace in traces {
+        if trace.ttype != TraceType::ResourceLock {
+            names.push(&trace.name);
+        }
+        collect_task_names(&trace.inner, names);
+    }
+}
+
 // This function is not the most beautiful code ever written and quite unintuitive!
 // Check the documenation for the analysis to get an understanding of how it works!
 //
-// The `bkpts` contains the tuple (Breakpoint, Name, CYCCNT) of each breakpoint, traced
-// from the replay harness on actual hardware. The `stack` is used internally to keep
+// The `bkpts` contains the Measurement of each breakpoint, traced from the replay
+// harness on actual hardware. The `stack` is used internally to keep
 // track of the correct scopes. That is, that for each Entry a corresponding Exit exists.
 fn wcet_rec(
-    bkpts: &mut Vec<(Breakpoint, String, u32)>,
+    bkpts: &mut Vec<Measurement>,
     stack: &mut Vec<EntryBreakpoint>,
-) -> Result<(Vec<Trace>, (Breakpoint, String, u32))> {
+) -> Result<(Vec<Trace>, Measurement)> {
     // This is the main result of this function
     let mut traces: Vec<Trace> = Vec::new();
-    let (bkpt, name, cyccnt) = match bkpts.pop() {
-        Some((b, n, c)) => (b, n, c),
+    let measurement = match bkpts.pop() {
+        Some(measurement) => measurement,
         None => return Err(anyhow!("Breakpoint vector is empty")),
     };
 
     // Set the current scope's variables. These are always returned in the end.
     // Because the outer scope needs to be able to read the objects data.
-    let curr_bkpt = bkpt.clone();
-    let curr_name = name.clone();
-    let curr_cyccnt = cyccnt.clone();
+    let curr_bkpt = measurement.breakpoint().clone();
+    let curr_name = measurement.name().to_string();
+    let curr_cyccnt = measurement.cycles();
 
     match &curr_bkpt {
         Breakpoint::Entry(e) => {
@@ -95,23 +225,27 @@ fn wcet_rec(
             let start = curr_cyccnt.clone();
             let mut inner = Vec::<Trace>::new();
 
-            // Inner loop
-            let mut prev: Breakpoint;
+            // Inner loop. Nested entries, such as a software task spawned and run inside
+            // a hardware task, return their own entry after consuming their exit.
             let mut end;
             loop {
-                let (mut i, (last, _, e)) = wcet_rec(bkpts, stack).with_context(|| {
-                    format!("Could not proceed with analysis after breakpoint {:?}", &e)
+                let (mut i, last) = wcet_rec(bkpts, stack).with_context(|| {
+                    format!(
+                        "Could not proceed with analysis after breakpoint {}",
+                        &measurement
+                    )
                 })?;
                 inner.append(&mut i);
-                prev = last.clone();
-                end = e;
+                end = last.cycles();
 
-                // If we get two Exits in a row, it means that we're exiting
-                // the inner loop. It should also break if there are no more
-                // objects in the bkpts vector
-                if last.is_exit() && prev.is_exit() || bkpts.is_empty() {
+                // An Exit returned here closes this scope, since the Exits of nested
+                // scopes are consumed by their own Entry
+                if last.breakpoint().is_exit() {
                     break;
                 }
+                if bkpts.is_empty() {
+                    return Err(anyhow!("Entry breakpoint {} is never exited", &measurement));
+                }
             }
             let trace = Trace::new(name, ttype, start, inner, end);
             traces.push(trace);
@@ -119,32 +253,192 @@ fn wcet_rec(
         Breakpoint::Exit(exit) => {
             // The stack should not be empty if we're exiting the analysis.
             // All corresponding Entry/Exit should add up to 255 if correct order.
-            let entry = stack.pop().unwrap() as u32;
+            let entry = match stack.pop() {
+                Some(entry) => entry as u32,
+                None => {
+                    return Err(anyhow!(
+                        "Exit breakpoint {} with empty scope stack",
+                        &measurement
+                    ))
+                }
+            };
             let exit = exit.clone() as u32;
             if entry + exit != 255 {
                 return Err(anyhow!(
-                    "Breakpoint scope not matching! Got entry: {} and exit: {}",
+                    "Breakpoint scope not matching! Got entry: {} and exit: {} at breakpoint {}",
                     entry,
-                    exit
+                    exit,
+                    &measurement
                 ));
             }
         }
         // Should ignore the Default breakpoint instead of returning an error
-        Breakpoint::Other(o) => {
-            return Err(anyhow!("Unsupported breakpoint inside analysis: {:?}", o));
+        Breakpoint::Other(_) => {
+            return Err(anyhow!(
+                "Unsupported breakpoint inside analysis: {}",
+                &measurement
+            ));
         }
     }
 
-    Ok((traces, (curr_bkpt, curr_name, curr_cyccnt)))
+    Ok((traces, measurement))
 }
 
 #[cfg(test)]
 mod tests {
     use super::super::breakpoints::ExitBreakpoint;
     use super::*;
+
+    /// Converts the breakpoints in the `(Breakpoint, Name, CYCCNT)` form to measurements
+    fn measurements(trace: Vec<(Breakpoint, String, u64)>) -> Vec<Measurement> {
+        trace.into_iter().map(Measurement::from).collect()
+    }
+
+    #[test]
+    fn test_cycle_difference() {
+        assert_eq!(cycle_difference(100, 250).unwrap(), 150);
+        assert_eq!(cycle_difference(100, 100).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_cycle_difference_wrapped() {
+        // The counter wrapped from u32::MAX - 9 to 20
+        assert_eq!(
+            cycle_difference(u32::MAX as u64 - 9, (1 << 32) + 20).unwrap(),
+            30
+        );
+    }
+
+    #[test]
+    fn test_cycle_difference_out_of_order() {
+        assert!(cycle_difference(5000, 1000).is_err());
+
+        let trace = Trace::new(
+            "task1".to_string(),
+            TraceType::SoftwareTask,
+            5000,
+            vec![],
+            1000,
+        );
+        assert!(trace.duration().is_err());
+    }
+
+    #[test]
+    fn test_print_traces_quiet() {
+        let traces = vec![Trace::new(
+            "uart0".to_string(),
+            TraceType::HardwareTask,
+            250,
+            vec![],
+            300,
+        )];
+
+        let mut out: Vec<u8> = Vec::new();
+        print_traces(&traces, false, &mut out).unwrap();
+        assert!(String::from_utf8(out).unwrap().contains("uart0"));
+
+        let mut out: Vec<u8> = Vec::new();
+        print_traces(&traces, true, &mut out).unwrap();
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn test_idle_times() {
+        let lock = Trace::new("res1".to_string(), TraceType::ResourceLock, 20, vec![], 40);
+        let traces = vec![
+            Trace::new(
+                "task1".to_string(),
+                TraceType::SoftwareTask,
+                10,
+                vec![lock],
+                100,
+            ),
+            Trace::new(
+                "uart0".to_string(),
+                TraceType::HardwareTask,
+                250,
+                vec![],
+                300,
+            ),
+        ];
+
+        let idle = idle_times(&traces).unwrap();
+        assert_eq!(
+            idle,
+            vec![IdleTime {
+                after: "task1".to_string(),
+                before: "uart0".to_string(),
+                cycles: 150,
+            }]
+        );
+        assert!(idle_times(&traces[..1]).unwrap().is_empty());
+
+        let overlapping = vec![traces[1].clone(), traces[0].clone()];
+        assert!(idle_times(&overlapping).is_err());
+    }
+
+    #[test]
+    fn test_filter_tasks() {
+        let lock = Trace::new("res1".to_string(), TraceType::ResourceLock, 2, vec![], 4);
+        let traces = vec![
+            Trace::new(
+                "task1".to_string(),
+                TraceType::SoftwareTask,
+                0,
+                vec![lock],
+                10,
+            ),
+            Trace::new("task2".to_string(), TraceType::HardwareTask, 20, vec![], 30),
+            Trace::new("task1".to_string(), TraceType::SoftwareTask, 40, vec![], 45),
+            Trace::new("uart0".to_string(), TraceType::HardwareTask, 50, vec![], 60),
+        ];
+
+        let filtered = filter_tasks(traces.clone(), &["task1".to_string(), "uart0".to_string()]);
+        let names: Vec<&str> = filtered.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(names, vec!["task1", "task1", "uart0"]);
+        assert_eq!(filtered[0].inner.len(), 1);
+
+        assert_eq!(filter_tasks(traces.clone(), &[]), traces);
+    }
+
+    #[test]
+    fn test_merge_traces() {
+        let task1 = Trace::new("task1".to_string(), TraceType::SoftwareTask, 0, vec![], 10);
+        let task2 = Trace::new("task2".to_string(), TraceType::HardwareTask, 0, vec![], 30);
+        let task2_slower = Trace::new("task2".to_string(), TraceType::HardwareTask, 0, vec![], 35);
+
+        let merged = merge_traces(
+            vec![task1.clone(), task2.clone()],
+            vec![task2.clone(), task2_slower.clone(), task1.clone()],
+        );
+        assert_eq!(merged, vec![task1, task2, task2_slower]);
+    }
+
+    #[test]
+    fn test_unmeasured_tasks() {
+        let lock = Trace::new("res1".to_string(), TraceType::ResourceLock, 2, vec![], 4);
+        let preempting = Trace::new("uart0".to_string(), TraceType::HardwareTask, 5, vec![], 9);
+        let traces = vec![Trace::new(
+            "task1".to_string(),
+            TraceType::SoftwareTask,
+            0,
+            vec![lock, preempting],
+            10,
+        )];
+        let known_tasks = vec![
+            "task1".to_string(),
+            "task2".to_string(),
+            "uart0".to_string(),
+            "res1".to_string(),
+        ];
+
+        let unmeasured = unmeasured_tasks(&known_tasks, &traces);
+        assert_eq!(unmeasured, vec!["task2".to_string(), "res1".to_string()]);
+    }
+
     #[test]
     fn test_analysis_nested_and_multiple_locks() {
-        let trace: Vec<(Breakpoint, String, u32)> = vec![
+        let trace: Vec<(Breakpoint, String, u64)> = vec![
             (
                 Breakpoint::Entry(EntryBreakpoint::HardwareTaskStart),
                 String::from("task1"),
@@ -187,7 +481,7 @@ mod tests {
             ),
         ];
 
-        let analysis = wcet_analysis(trace).unwrap();
+        let analysis = wcet_analysis(measurements(trace)).unwrap();
         let result = analysis.first().unwrap();
         let expected = Trace {
             name: "task1".to_string(),
@@ -222,7 +516,7 @@ mod tests {
 
     #[test]
     fn test_analysis_multiple_locks() {
-        let trace: Vec<(Breakpoint, String, u32)> = vec![
+        let trace: Vec<(Breakpoint, String, u64)> = vec![
             (
                 Breakpoint::Entry(EntryBreakpoint::SoftwareTaskStart),
                 String::from("task1"),
@@ -264,7 +558,7 @@ mod tests {
                 30,
             ),
         ];
-        let analysis = wcet_analysis(trace).unwrap();
+        let analysis = wcet_analysis(measurements(trace)).unwrap();
         let result = analysis.first().unwrap();
         let expected = Trace {
             name: "task1".to_string(),
@@ -300,7 +594,7 @@ mod tests {
 
     #[test]
     fn test_analysis_nested_locks() {
-        let trace: Vec<(Breakpoint, String, u32)> = vec![
+        let trace: Vec<(Breakpoint, String, u64)> = vec![
             (
                 Breakpoint::Entry(EntryBreakpoint::SoftwareTaskStart),
                 String::from("task1"),
@@ -342,7 +636,7 @@ mod tests {
                 60,
             ),
         ];
-        let analysis = wcet_analysis(trace).unwrap();
+        let analysis = wcet_analysis(measurements(trace)).unwrap();
         let result = analysis.first().unwrap();
         let expected = Trace {
             name: "task1".to_string(),
@@ -371,9 +665,129 @@ mod tests {
         };
         assert_eq!(result, &expected);
     }
+    #[test]
+    fn test_analysis_measurements() {
+        let measurements = vec![
+            Measurement::new(
+                Breakpoint::Entry(EntryBreakpoint::SoftwareTaskStart),
+                "task1".to_string(),
+                100,
+            ),
+            Measurement::new(
+                Breakpoint::Exit(ExitBreakpoint::SoftwareTaskEnd),
+                "task1".to_string(),
+                160,
+            ),
+        ];
+        assert_eq!(
+            measurements[0],
+            Measurement::from((
+                Breakpoint::Entry(EntryBreakpoint::SoftwareTaskStart),
+                "task1".to_string(),
+                100
+            ))
+        );
+
+        let analysis = wcet_analysis(measurements).unwrap();
+        assert_eq!(
+            analysis,
+            vec![Trace::new(
+                "task1".to_string(),
+                TraceType::SoftwareTask,
+                100,
+                vec![],
+                160
+            )]
+        );
+    }
+
+    #[test]
+    fn test_analysis_software_task_in_hardware_task() {
+        let trace: Vec<(Breakpoint, String, u64)> = vec![
+            (
+                Breakpoint::Entry(EntryBreakpoint::HardwareTaskStart),
+                String::from("uart0"),
+                0,
+            ),
+            (
+                Breakpoint::Entry(EntryBreakpoint::ResourceLockStart),
+                String::from("res1"),
+                5,
+            ),
+            (
+                Breakpoint::Exit(ExitBreakpoint::ResourceLockEnd),
+                String::from("res1"),
+                10,
+            ),
+            (
+                Breakpoint::Entry(EntryBreakpoint::SoftwareTaskStart),
+                String::from("task1"),
+                15,
+            ),
+            (
+                Breakpoint::Entry(EntryBreakpoint::ResourceLockStart),
+                String::from("res1"),
+                20,
+            ),
+            (
+                Breakpoint::Exit(ExitBreakpoint::ResourceLockEnd),
+                String::from("res1"),
+                25,
+            ),
+            (
+                Breakpoint::Exit(ExitBreakpoint::SoftwareTaskEnd),
+                String::from("task1"),
+                30,
+            ),
+            (
+                Breakpoint::Exit(ExitBreakpoint::HardwareTaskEnd),
+                String::from("uart0"),
+                40,
+            ),
+        ];
+        let analysis = wcet_analysis(measurements(trace.clone())).unwrap();
+        let lock = |start, end| {
+            Trace::new(
+                "res1".to_string(),
+                TraceType::ResourceLock,
+                start,
+                vec![],
+                end,
+            )
+        };
+        let expected = Trace::new(
+            "uart0".to_string(),
+            TraceType::HardwareTask,
+            0,
+            vec![
+                lock(5, 10),
+                Trace::new(
+                    "task1".to_string(),
+                    TraceType::SoftwareTask,
+                    15,
+                    vec![lock(20, 25)],
+                    30,
+                ),
+            ],
+            40,
+        );
+        assert_eq!(analysis, vec![expected]);
+
+        // The hardware task can't end before the software task it runs
+        let mut swapped = trace.clone();
+        swapped.swap(6, 7);
+        assert!(wcet_analysis(measurements(swapped)).is_err());
+
+        // Nor can it be left open after the software task ends
+        let mut unclosed = trace;
+        unclosed.pop();
+        let err = wcet_analysis(measurements(unclosed)).unwrap_err();
+        assert!(err.to_string().contains("is never exited"));
+    }
+
     #[test]
     fn test_analysis_invalid_input_size() {
-        let trace: Vec<(Breakpoint, String, u32)> = vec![
+        let trace: Vec<(Breakpoint, String, u64)> = vec![
             (
                 Breakpoint::Entry(EntryBreakpoint::HardwareTaskStart),
                 String::from("task1"),
@@ -390,20 +804,20 @@ mod tests {
                 10,
             ),
         ];
-        let analysis = wcet_analysis(trace);
+        let analysis = wcet_analysis(measurements(trace));
         assert!(analysis.is_err());
     }
 
     #[test]
     fn test_analysis_empty_input() {
-        let trace: Vec<(Breakpoint, String, u32)> = vec![];
-        let analysis = wcet_analysis(trace);
+        let trace: Vec<(Breakpoint, String, u64)> = vec![];
+        let analysis = wcet_analysis(measurements(trace));
         assert!(analysis.is_err());
     }
 
     #[test]
     fn test_analysis_empty_inner_trace() {
-        let trace: Vec<(Breakpoint, String, u32)> = vec![
+        let trace: Vec<(Breakpoint, String, u64)> = vec![
             (
                 Breakpoint::Entry(EntryBreakpoint::HardwareTaskStart),
                 String::from("task1"),
@@ -415,7 +829,7 @@ mod tests {
                 10,
             ),
         ];
-        let analysis = wcet_analysis(trace).unwrap();
+        let analysis = wcet_analysis(measurements(trace)).unwrap();
         let result = analysis.first().unwrap();
         let expected = Trace {
             name: "task1".to_string(),
@@ -429,7 +843,7 @@ mod tests {
 
     #[test]
     fn test_analysis_wrong_task_order() {
-        let trace: Vec<(Breakpoint, String, u32)> = vec![
+        let trace: Vec<(Breakpoint, String, u64)> = vec![
             (
                 Breakpoint::Entry(EntryBreakpoint::HardwareTaskStart),
                 String::from("task1"),
@@ -441,13 +855,24 @@ mod tests {
                 10,
             ),
         ];
-        let analysis = wcet_analysis(trace);
+        let analysis = wcet_analysis(measurements(trace));
         assert!(analysis.is_err());
     }
 
+    #[test]
+    fn test_analysis_lone_exit() {
+        let trace: Vec<(Breakpoint, String, u64)> = vec![(
+            Breakpoint::Exit(ExitBreakpoint::HardwareTaskEnd),
+            String::from("task1"),
+            10,
+        )];
+        let err = wcet_analysis(measurements(trace)).unwrap_err();
+        assert!(err.to_string().contains("empty scope stack"));
+    }
+
     #[test]
     fn test_analysis_wrong_lock_order() {
-        let trace: Vec<(Breakpoint, String, u32)> = vec![
+        let trace: Vec<(Breakpoint, String, u64)> = vec![
             (
                 Breakpoint::Entry(EntryBreakpoint::ResourceLockStart),
                 String::from("res1"),
@@ -459,7 +884,7 @@ mod tests {
                 10,
             ),
         ];
-        let analysis = wcet_analysis(trace);
+        let analysis = wcet_analysis(measurements(trace));
         assert!(analysis.is_err());
     }
 }
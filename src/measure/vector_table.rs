@@ -0,0 +1,149 @@
+use super::dwarf::Subprogram;
+use anyhow::{Context, Result};
+use gimli::RunTimeEndian;
+use object::{Object, ObjectSection, ObjectSymbol, SymbolKind};
+use rustc_demangle::demangle;
+
+/// The section `cortex-m-rt` places the vector table in.
+const VECTOR_TABLE_SECTION: &str = ".vector_table";
+
+/// Returns the interrupt and exception handlers in the vector table of the binary as
+/// subprograms named by their symbols. Hardware tasks are bound to these handlers, whose
+/// DWARF names may be missing or reserved. Returns an empty list if the binary has no
+/// vector table.
+///
+/// * `object` - The parsed binary
+/// * `endian` - The byte order of the target
+pub fn get_interrupt_handlers(
+    object: &object::File,
+    endian: RunTimeEndian,
+) -> Result<Vec<Subprogram>> {
+    let table = match object.section_by_name(VECTOR_TABLE_SECTION) {
+        Some(section) => section
+            .data()
+            .context("Could not read the vector table of the binary")?,
+        None => return Ok(vec![]),
+    };
+    let symbols: Vec<(String, u64, u64)> = object
+        .symbols()
+        .filter(|symbol| symbol.kind() == SymbolKind::Text && symbol.size() > 0)
+        .filter_map(|symbol| {
+            let name = symbol.name().ok()?;
+            Some((
+                format!("{:#}", demangle(name)),
+                symbol.address(),
+                symbol.size(),
+            ))
+        })
+        .collect();
+    Ok(handler_subprograms(
+        &handler_addresses(table, endian),
+        &symbols,
+    ))
+}
+
+/// Adds the handlers that are not already covered by a subprogram starting at the same
+/// address.
+///
+/// * `subprograms` - The subprograms parsed from the DWARF
+/// * `handlers` - The handlers from the vector table
+pub fn add_interrupt_handlers(subprograms: &mut Vec<Subprogram>, handlers: Vec<Subprogram>) {
+    for handler in handlers {
+        let covered = subprograms.iter().any(|subprogram| {
+            subprogram
+                .ranges
+                .iter()
+                .any(|(low_pc, _)| handler.ranges.iter().any(|(low, _)| low == low_pc))
+        });
+        if !covered {
+            subprograms.push(handler);
+        }
+    }
+}
+
+/// Returns the unique handler addresses in the vector table. The first entry is the
+/// initial stack pointer and unused entries are zero, both are skipped. The Thumb bit of
+/// the addresses is cleared.
+fn handler_addresses(table: &[u8], endian: RunTimeEndian) -> Vec<u64> {
+    let mut addresses: Vec<u64> = vec![];
+    for entry in table.chunks_exact(4).skip(1) {
+        let bytes = [entry[0], entry[1], entry[2], entry[3]];
+        let value = match endian {
+            RunTimeEndian::Little => u32::from_le_bytes(bytes),
+            RunTimeEndian::Big => u32::from_be_bytes(bytes),
+        };
+        let address = (value & !1) as u64;
+        if address != 0 && !addresses.contains(&address) {
+            addresses.push(address);
+        }
+    }
+    addresses
+}
+
+/// Names the handler addresses with the `(name, address, size)` of the function symbols.
+/// Prefers a symbol that is not reserved if several symbols alias the same handler.
+fn handler_subprograms(addresses: &[u64], symbols: &[(String, u64, u64)]) -> Vec<Subprogram> {
+    let mut handlers: Vec<Subprogram> = vec![];
+    for address in addresses {
+        let mut aliases = symbols
+            .iter()
+            .filter(|(_, symbol_address, _)| (symbol_address & !1) == *address);
+        let symbol = aliases
+            .clone()
+            .find(|(name, _, _)| !name.starts_with("__"))
+            .or_else(|| aliases.next());
+        if let Some((name, _, size)) = symbol {
+            handlers.push(Subprogram {
+                name: name.clone(),
+                linkage_name: name.clone(),
+                ranges: vec![(*address, address + size)],
+            });
+        }
+    }
+    handlers
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_interrupt_handlers_from_vector_table() {
+        let mut table: Vec<u8> = vec![];
+        // Initial stack pointer, Reset, an unused entry, UART0 and a handler
+        // without a symbol used twice
+        for entry in &[
+            0x2001_0000u32,
+            0x0800_0101,
+            0x0,
+            0x0800_0201,
+            0x0800_0301,
+            0x0800_0301,
+        ] {
+            table.extend_from_slice(&entry.to_le_bytes());
+        }
+        let symbols = vec![
+            ("Reset".to_string(), 0x0800_0101, 0x40),
+            ("__cortex_m_rt_UART0".to_string(), 0x0800_0201, 0x20),
+            ("UART0".to_string(), 0x0800_0201, 0x20),
+            ("app::foo".to_string(), 0x0800_0241, 0x10),
+        ];
+
+        let addresses = handler_addresses(&table, RunTimeEndian::Little);
+        assert_eq!(addresses, vec![0x0800_0100, 0x0800_0200, 0x0800_0300]);
+
+        let handlers = handler_subprograms(&addresses, &symbols);
+        let names: Vec<&str> = handlers.iter().map(|h| h.display_name()).collect();
+        assert_eq!(names, vec!["Reset", "UART0"]);
+        assert_eq!(handlers[1].ranges, vec![(0x0800_0200, 0x0800_0220)]);
+
+        let mut subprograms = vec![Subprogram {
+            name: "Reset".to_string(),
+            linkage_name: "Reset".to_string(),
+            ranges: vec![(0x0800_0100, 0x0800_0140)],
+        }];
+        add_interrupt_handlers(&mut subprograms, handlers);
+        let names: Vec<&str> = subprograms.iter().map(|s| s.display_name()).collect();
+        assert_eq!(names, vec!["Reset", "UART0"]);
+    }
+}
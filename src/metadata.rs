@@ -3,7 +3,7 @@ use anyhow::{anyhow, Context, Result};
 use chrono::prelude::Utc;
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     path::{Path, PathBuf},
 };
 
@@ -19,6 +19,9 @@ pub struct RaukMetadata {
     pub rauk_output_directory: PathBuf,
     pub previous_execution: PreviousExecution,
     pub artifacts: Artifacts,
+    /// The Cargo.toml to build, if not the one in the project directory
+    #[serde(skip)]
+    pub manifest_path: Option<PathBuf>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -42,6 +45,9 @@ pub struct ArtifactDetail {
     pub generate_output: Option<OutputInfo>,
     pub flash_output: Option<OutputInfo>,
     pub measure_output: Option<OutputInfo>,
+    /// The memory addresses of the replay objects resolved during the last measure
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub object_locations: Option<BTreeMap<String, Option<u64>>>,
 }
 
 impl ArtifactDetail {
@@ -50,6 +56,7 @@ impl ArtifactDetail {
             generate_output: None,
             flash_output: None,
             measure_output: None,
+            object_locations: None,
         }
     }
 
@@ -90,13 +97,30 @@ impl RaukMetadata {
                     }
                 },
             },
+            manifest_path: None,
         }
     }
 
-    /// Loads the output info file **if it exists** in the project path.
-    /// Will overwrite all values in the current struct!
+    /// Returns the path to the Cargo.toml to build.
+    pub fn cargo_manifest(&self) -> PathBuf {
+        self.manifest_path
+            .clone()
+            .unwrap_or_else(|| self.project_directory.join("Cargo.toml"))
+    }
+
+    /// Returns the directory of the Cargo.toml to build.
+    pub fn cargo_directory(&self) -> PathBuf {
+        let manifest = self.cargo_manifest();
+        match manifest.parent() {
+            Some(dir) => dir.to_path_buf(),
+            None => self.project_directory.clone(),
+        }
+    }
+
+    /// Loads the output info file **if it exists** in the output directory.
+    /// Will overwrite all values in the current struct except the output directory!
     pub fn load(&mut self) -> Result<()> {
-        let info_path = get_metadata_path(&self.project_directory);
+        let info_path = self.rauk_output_directory.join(RAUK_METADATA_FILE);
 
         if info_path.exists() {
             info!("Loading metadata from previous execution");
@@ -110,7 +134,6 @@ impl RaukMetadata {
             };
 
             self.project_directory = output_info.project_directory;
-            self.rauk_output_directory = output_info.rauk_output_directory;
             self.previous_execution = output_info.previous_execution;
             self.artifacts = output_info.artifacts;
         }
@@ -118,12 +141,11 @@ impl RaukMetadata {
         Ok(())
     }
 
-    /// Writes the contents of RaukMetadata to file.
+    /// Writes the contents of RaukMetadata to file in the output directory.
     pub fn save(&self) -> Result<()> {
-        let rauk_path = get_rauk_output_path(&self.project_directory);
-        let _ = std::fs::create_dir_all(rauk_path);
+        let _ = std::fs::create_dir_all(&self.rauk_output_directory);
 
-        let info_path = get_metadata_path(&self.project_directory);
+        let info_path = self.rauk_output_directory.join(RAUK_METADATA_FILE);
         let data = serde_json::to_string(&self).context("Failed to serialize metadata to json")?;
         std::fs::write(info_path, data)?;
 
@@ -207,6 +229,44 @@ impl RaukMetadata {
         Ok(())
     }
 
+    /// Stores the memory addresses of the replay objects resolved when measuring an artifact.
+    pub fn update_object_locations(
+        &mut self,
+        build: &BuildDetails,
+        locations: &HashMap<String, Option<u64>>,
+    ) {
+        let name = build.get_name();
+        let example = build.is_example();
+        let release = build.is_release();
+
+        let mut artifact = match self.get_artifact_detail(&name, release, example) {
+            Some(artifact) => artifact.clone(),
+            None => ArtifactDetail::new(),
+        };
+        artifact.object_locations = Some(
+            locations
+                .iter()
+                .map(|(name, address)| (name.clone(), *address))
+                .collect(),
+        );
+
+        self.insert(&name, artifact, release, example);
+    }
+
+    /// Stores the statistics of the KLEE run that generated the tests of an artifact.
+    /// Does nothing if the artifact has no generate output.
+    pub fn update_klee_stats(&mut self, build: &BuildDetails, stats: KleeStats) {
+        let name = build.get_name();
+        let example = build.is_example();
+        let release = build.is_release();
+
+        if let Some(artifact) = self.get_mut_artifact_detail(&name, release, example) {
+            if let Some(output) = artifact.generate_output.as_mut() {
+                output.klee_stats = Some(stats);
+            }
+        }
+    }
+
     /// Mark the program execution as successful. I.e. no breaking errors
     /// internally in rauk itself (not the RTIC application). If not called
     /// the next execution of rauk will refuse to continue.
@@ -235,6 +295,8 @@ impl Default for PreviousExecution {
 pub struct OutputInfo {
     pub output_path: Option<PathBuf>,
     pub last_changed: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub klee_stats: Option<KleeStats>,
 }
 
 impl OutputInfo {
@@ -244,10 +306,25 @@ impl OutputInfo {
         OutputInfo {
             output_path,
             last_changed: Some(time.to_rfc3339()),
+            klee_stats: None,
         }
     }
 }
 
+/// Path and coverage statistics of a KLEE run. Each value is `None` if KLEE did
+/// not report it.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct KleeStats {
+    pub explored_paths: Option<u64>,
+    pub completed_paths: Option<u64>,
+    pub partially_completed_paths: Option<u64>,
+    pub generated_tests: Option<u64>,
+    pub total_instructions: Option<u64>,
+    pub covered_instructions: Option<u64>,
+    pub uncovered_instructions: Option<u64>,
+}
+
 /// Returns the path to rauk artifacts and outputs
 pub fn get_rauk_output_path(project_dir: &Path) -> PathBuf {
     let mut out_path = PathBuf::from(&project_dir);
@@ -255,6 +332,18 @@ pub fn get_rauk_output_path(project_dir: &Path) -> PathBuf {
     out_path
 }
 
+/// Returns the directory rauk writes its metadata, logs, caches and results to. That is
+/// `target/rauk` in the project directory unless `output_dir` is given.
+///
+/// * `project_dir` - The path to the RTIC project
+/// * `output_dir` - The `--output-dir` given on the command line
+pub fn output_directory(project_dir: &Path, output_dir: Option<&PathBuf>) -> PathBuf {
+    match output_dir {
+        Some(dir) => dir.clone(),
+        None => get_rauk_output_path(project_dir),
+    }
+}
+
 /// Returns the path to the metadata file
 pub fn get_metadata_path(project_dir: &Path) -> PathBuf {
     let mut out_path = get_rauk_output_path(&project_dir);
@@ -263,9 +352,79 @@ pub fn get_metadata_path(project_dir: &Path) -> PathBuf {
 }
 
 /// Creates a new metadata structs and tries to load an existing one if it
-/// exists.
-pub fn load_metadata(project_dir: &PathBuf) -> Result<RaukMetadata> {
+/// exists in the output directory.
+///
+/// * `project_dir` - The path to the RTIC project
+/// * `output_dir` - The directory rauk writes its output to
+pub fn load_metadata(project_dir: &PathBuf, output_dir: &PathBuf) -> Result<RaukMetadata> {
     let mut meta = RaukMetadata::new(project_dir);
+    meta.rauk_output_directory = output_dir.clone();
     meta.load()?;
     Ok(meta)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_object_locations_round_trip() {
+        let project_dir = std::env::temp_dir().join("rauk-metadata-locations");
+        let _ = std::fs::remove_dir_all(&project_dir);
+        std::fs::create_dir_all(&project_dir).unwrap();
+
+        let build = BuildDetails {
+            bin: Some("app".to_string()),
+            example: None,
+            release: true,
+            package: None,
+        };
+        let mut locations = HashMap::new();
+        locations.insert("app::resources::res1".to_string(), Some(0x2000_0004));
+        locations.insert("app::resources::res2".to_string(), None);
+
+        let mut metadata = RaukMetadata::new(&project_dir);
+        metadata.update_object_locations(&build, &locations);
+        metadata.program_execution_successful();
+        metadata.save().unwrap();
+
+        let loaded = load_metadata(&project_dir, &get_rauk_output_path(&project_dir)).unwrap();
+        let artifact = loaded.get_artifact_detail("app", true, false).unwrap();
+        let stored = artifact.object_locations.as_ref().unwrap();
+        assert_eq!(stored.len(), 2);
+        assert_eq!(stored["app::resources::res1"], Some(0x2000_0004));
+        assert_eq!(stored["app::resources::res2"], None);
+
+        let _ = std::fs::remove_dir_all(&project_dir);
+    }
+
+    #[test]
+    fn test_custom_output_directory() {
+        let project_dir = std::env::temp_dir().join("rauk-metadata-output-dir");
+        let output_dir = std::env::temp_dir().join("rauk-metadata-output-dir-custom");
+        let _ = std::fs::remove_dir_all(&project_dir);
+        let _ = std::fs::remove_dir_all(&output_dir);
+        std::fs::create_dir_all(&project_dir).unwrap();
+
+        assert_eq!(
+            output_directory(&project_dir, None),
+            project_dir.join(RAUK_OUTPUT_DIR)
+        );
+        assert_eq!(
+            output_directory(&project_dir, Some(&output_dir)),
+            output_dir
+        );
+
+        let mut metadata = load_metadata(&project_dir, &output_dir).unwrap();
+        metadata.program_execution_successful();
+        metadata.save().unwrap();
+        assert!(output_dir.join(RAUK_METADATA_FILE).exists());
+        assert!(!get_rauk_output_path(&project_dir).exists());
+
+        let loaded = load_metadata(&project_dir, &output_dir).unwrap();
+        assert_eq!(loaded.rauk_output_directory, output_dir);
+
+        let _ = std::fs::remove_dir_all(&project_dir);
+        let _ = std::fs::remove_dir_all(&output_dir);
+    }
+}
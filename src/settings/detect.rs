@@ -0,0 +1,168 @@
+use anyhow::{Context, Result};
+use std::fs::read_to_string;
+use std::path::PathBuf;
+use toml::Value;
+
+/// The configuration file of cargo-embed
+pub const EMBED_TOML: &str = "Embed.toml";
+/// The cargo configuration files of the project, the first one found is used
+pub const CARGO_CONFIG_FILES: &[&str] = &[".cargo/config.toml", ".cargo/config"];
+
+/// The chip and target configured for other tools in the project. Used when neither
+/// the command line nor the settings file give them.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DetectedSettings {
+    /// The chip of the default profile in `Embed.toml`
+    pub chip: Option<String>,
+    /// The build target in `.cargo/config.toml`
+    pub target: Option<String>,
+}
+
+/// Reads the chip from `Embed.toml` and the target from `.cargo/config.toml` in the
+/// project directory. Missing or invalid files are skipped.
+///
+/// * `project_dir` - The path to the RTIC project
+pub fn detect_settings(project_dir: &PathBuf) -> DetectedSettings {
+    let chip = read_detected(&project_dir.join(EMBED_TOML), parse_embed_chip);
+    let target = CARGO_CONFIG_FILES
+        .iter()
+        .map(|file| project_dir.join(file))
+        .find(|path| path.exists())
+        .and_then(|path| read_detected(&path, parse_cargo_config_target));
+
+    DetectedSettings { chip, target }
+}
+
+/// Parses the file at `path` with `parse`. Logs a warning if the file can't be read
+/// or parsed.
+fn read_detected(path: &PathBuf, parse: fn(&str) -> Result<Option<String>>) -> Option<String> {
+    if !path.exists() {
+        return None;
+    }
+    let value = read_to_string(path)
+        .with_context(|| format!("Could not read {:?}", path))
+        .and_then(|contents| parse(&contents).with_context(|| format!("Invalid {:?}", path)));
+    match value {
+        Ok(value) => {
+            if let Some(value) = &value {
+                info!("Found '{}' in {:?}", value, path);
+            }
+            value
+        }
+        Err(e) => {
+            warn!("{:?}", e);
+            None
+        }
+    }
+}
+
+/// Returns the chip of the default profile in the contents of an `Embed.toml`, i.e.
+/// `chip` in the `[default.general]` table.
+pub fn parse_embed_chip(contents: &str) -> Result<Option<String>> {
+    let embed: Value = toml::from_str(contents)?;
+    let chip = embed
+        .get("default")
+        .and_then(|profile| profile.get("general"))
+        .and_then(|general| general.get("chip"))
+        .and_then(Value::as_str)
+        .map(String::from);
+    Ok(chip)
+}
+
+/// Returns the build target in the contents of a `.cargo/config.toml`, i.e. `target`
+/// in the `[build]` table. If several targets are given the first one is used.
+pub fn parse_cargo_config_target(contents: &str) -> Result<Option<String>> {
+    let config: Value = toml::from_str(contents)?;
+    let target = match config.get("build").and_then(|build| build.get("target")) {
+        Some(Value::String(target)) => Some(target.clone()),
+        Some(Value::Array(targets)) => targets.first().and_then(Value::as_str).map(String::from),
+        _ => None,
+    };
+    Ok(target)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir_all, remove_dir_all, write};
+
+    #[test]
+    fn test_parse_embed_chip() {
+        let contents = "[default.probe]\n\
+                        protocol = \"Swd\"\n\
+                        [default.general]\n\
+                        chip = \"STM32F401RETx\"\n\
+                        [release.general]\n\
+                        chip = \"nRF52840_xxAA\"\n";
+        assert_eq!(
+            parse_embed_chip(contents).unwrap(),
+            Some("STM32F401RETx".to_string())
+        );
+        assert_eq!(
+            parse_embed_chip("[default.rtt]\nenabled = true\n").unwrap(),
+            None
+        );
+        assert!(parse_embed_chip("[default.general\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_cargo_config_target() {
+        let contents = "[target.thumbv7em-none-eabihf]\n\
+                        runner = \"probe-run --chip STM32F401RETx\"\n\
+                        [build]\n\
+                        target = \"thumbv7em-none-eabihf\"\n";
+        assert_eq!(
+            parse_cargo_config_target(contents).unwrap(),
+            Some("thumbv7em-none-eabihf".to_string())
+        );
+        assert_eq!(
+            parse_cargo_config_target(
+                "[build]\ntarget = [\"thumbv6m-none-eabi\", \"thumbv7m-none-eabi\"]\n"
+            )
+            .unwrap(),
+            Some("thumbv6m-none-eabi".to_string())
+        );
+        assert_eq!(
+            parse_cargo_config_target("[build]\njobs = 4\n").unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_detect_settings() {
+        let dir = std::env::temp_dir().join("rauk-settings-detect");
+        let _ = remove_dir_all(&dir);
+        create_dir_all(dir.join(".cargo")).unwrap();
+        assert_eq!(detect_settings(&dir), DetectedSettings::default());
+
+        write(
+            dir.join(EMBED_TOML),
+            "[default.general]\nchip = \"STM32F401RETx\"\n",
+        )
+        .unwrap();
+        write(
+            dir.join(".cargo/config"),
+            "[build]\ntarget = \"thumbv7em-none-eabi\"\n",
+        )
+        .unwrap();
+        assert_eq!(
+            detect_settings(&dir),
+            DetectedSettings {
+                chip: Some("STM32F401RETx".to_string()),
+                target: Some("thumbv7em-none-eabi".to_string()),
+            }
+        );
+
+        // config.toml takes precedence over the legacy config
+        write(
+            dir.join(".cargo/config.toml"),
+            "[build]\ntarget = \"thumbv6m-none-eabi\"\n",
+        )
+        .unwrap();
+        assert_eq!(
+            detect_settings(&dir).target,
+            Some("thumbv6m-none-eabi".to_string())
+        );
+        let _ = remove_dir_all(&dir);
+    }
+}
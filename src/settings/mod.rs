@@ -1,15 +1,21 @@
-use anyhow::Result;
-use serde::Deserialize;
+mod detect;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Write};
 use std::path::PathBuf;
 use std::{fs::File, io::Read};
+use structopt::StructOpt;
 use toml;
 
-use crate::cli::{FlashInput, MeasureInput};
+use crate::cli::{FlashInput, GenerateInput, MeasureInput};
+use crate::{flash, generate, measure};
+pub use detect::{detect_settings, DetectedSettings};
 
 pub const RAUK_CONFIG_TOML: &str = "rauk.toml";
 
-#[derive(Deserialize)]
-#[serde(rename_all = "kebab-case")]
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
 pub struct General {
     #[serde(default)]
     pub no_patch: Option<bool>,
@@ -19,53 +25,442 @@ pub struct General {
     pub target: Option<String>,
     #[serde(default)]
     pub halt_timeout: Option<u64>,
+    #[serde(default)]
+    pub analysis_feature: Option<String>,
+    #[serde(default)]
+    pub replay_feature: Option<String>,
+}
+
+/// Settings of the generate command. Take precedence over `[general]`.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct GenerateSettings {
+    #[serde(default)]
+    pub release: Option<bool>,
+    #[serde(default)]
+    pub emit_all_errors: Option<bool>,
+    #[serde(default)]
+    pub analysis_feature: Option<String>,
+    #[serde(default)]
+    pub klee_args: Option<Vec<String>>,
+    #[serde(default)]
+    pub klee_max_time: Option<u64>,
+    #[serde(default)]
+    pub klee: Option<String>,
+}
+
+/// Settings of the flash command. Take precedence over `[general]`.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct FlashSettings {
+    #[serde(default)]
+    pub release: Option<bool>,
+    #[serde(default)]
+    pub chip: Option<String>,
+    #[serde(default)]
+    pub target: Option<String>,
+    #[serde(default)]
+    pub halt_timeout: Option<u64>,
+    #[serde(default)]
+    pub replay_feature: Option<String>,
+    #[serde(default)]
+    pub verify: Option<bool>,
+}
+
+/// Settings of the measure command. Take precedence over `[general]`.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct MeasureSettings {
+    #[serde(default)]
+    pub release: Option<bool>,
+    #[serde(default)]
+    pub chip: Option<String>,
+    #[serde(default)]
+    pub halt_timeout: Option<u64>,
+    #[serde(default)]
+    pub ram_start: Option<u64>,
+    #[serde(default)]
+    pub objdump: Option<String>,
 }
 
 /// Rauk settings file that can be used instead of command input
-#[derive(Deserialize)]
-#[serde(rename_all = "kebab-case")]
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
 pub struct RaukSettings {
     #[serde(default)]
     pub general: Option<General>,
+    #[serde(default)]
+    pub generate: Option<GenerateSettings>,
+    #[serde(default)]
+    pub flash: Option<FlashSettings>,
+    #[serde(default)]
+    pub measure: Option<MeasureSettings>,
+    /// The chip and target found in `Embed.toml` and `.cargo/config.toml`
+    #[serde(skip)]
+    pub detected: DetectedSettings,
 }
 
 impl RaukSettings {
     pub fn new() -> Self {
-        RaukSettings { general: None }
+        RaukSettings {
+            general: None,
+            generate: None,
+            flash: None,
+            measure: None,
+            detected: DetectedSettings::default(),
+        }
+    }
+
+    /// Returns true if the project's Cargo.toml should not be patched. The
+    /// `--no-patch` flag takes precedence, otherwise the `no-patch` setting is used.
+    pub fn resolve_no_patch(&self, cli_no_patch: bool) -> bool {
+        cli_no_patch
+            || self
+                .general
+                .as_ref()
+                .and_then(|general| general.no_patch)
+                .unwrap_or(false)
+    }
+
+    /// Returns the target the replay harness is built for if none is given on the
+    /// command line. The `[flash]` section takes precedence over `[general]`.
+    pub fn replay_target(&self) -> Option<String> {
+        let general = self.general.clone().unwrap_or_default();
+        let flash = self.flash.clone().unwrap_or_default();
+        flash
+            .target
+            .or(general.target)
+            .or_else(|| self.detected.target.clone())
+    }
+
+    /// Returns the effective value and source of the `[general]` settings that are not
+    /// resolved per command.
+    pub fn resolved_settings(&self, cli_no_patch: bool) -> Vec<ResolvedSetting> {
+        let general = self.general.clone().unwrap_or_default();
+        vec![ResolvedSetting::resolve(
+            "no-patch",
+            &cli_flag(cli_no_patch),
+            &general.no_patch,
+            Some(false),
+        )]
+    }
+}
+
+/// Where the value of a setting comes from. A value given on the command line has
+/// precedence over the settings file, which has precedence over the configuration of
+/// other tools in the project and then the default.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SettingSource {
+    Cli,
+    File,
+    Detected,
+    Default,
+}
+
+impl fmt::Display for SettingSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SettingSource::Cli => write!(f, "command line"),
+            SettingSource::File => write!(f, "{}", RAUK_CONFIG_TOML),
+            SettingSource::Detected => write!(
+                f,
+                "{} or {}",
+                detect::EMBED_TOML,
+                detect::CARGO_CONFIG_FILES[0]
+            ),
+            SettingSource::Default => write!(f, "default"),
+        }
+    }
+}
+
+/// The effective value of a setting and where it comes from
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResolvedSetting {
+    pub key: &'static str,
+    pub value: Option<String>,
+    pub source: SettingSource,
+}
+
+impl ResolvedSetting {
+    fn resolve<T: ToString>(
+        key: &'static str,
+        cli: &Option<T>,
+        file: &Option<T>,
+        default: Option<T>,
+    ) -> ResolvedSetting {
+        let (value, source) = match (cli, file) {
+            (Some(value), _) => (Some(value.to_string()), SettingSource::Cli),
+            (None, Some(value)) => (Some(value.to_string()), SettingSource::File),
+            (None, None) => (
+                default.map(|value| value.to_string()),
+                SettingSource::Default,
+            ),
+        };
+        ResolvedSetting { key, value, source }
+    }
+
+    /// Resolves a setting without a default that can be detected from the configuration
+    /// of other tools in the project.
+    fn resolve_detected(
+        key: &'static str,
+        cli: &Option<String>,
+        file: &Option<String>,
+        detected: &Option<String>,
+    ) -> ResolvedSetting {
+        match (cli, file, detected) {
+            (None, None, Some(value)) => ResolvedSetting {
+                key,
+                value: Some(value.clone()),
+                source: SettingSource::Detected,
+            },
+            _ => ResolvedSetting::resolve(key, cli, file, None),
+        }
+    }
+}
+
+impl fmt::Display for ResolvedSetting {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} = {} ({})",
+            self.key,
+            self.value.as_deref().unwrap_or("<unset>"),
+            self.source
+        )
+    }
+}
+
+/// Returns a flag given on the command line as a setting. A flag that is not given
+/// leaves the setting to the settings file.
+fn cli_flag(value: bool) -> Option<bool> {
+    if value {
+        Some(true)
+    } else {
+        None
+    }
+}
+
+/// Logs the resolved settings at info level.
+pub fn log_resolved_settings(resolved: &[ResolvedSetting]) {
+    for setting in resolved {
+        info!("{}", setting);
+    }
+}
+
+impl GenerateInput {
+    /// If input is missing, check if it is available in the settings
+    /// and overwrite the missing input with those values. The `[generate]`
+    /// section takes precedence over `[general]`.
+    pub fn get_missing_input(&mut self, settings: &RaukSettings) {
+        let general = settings.general.clone().unwrap_or_default();
+        let generate = settings.generate.clone().unwrap_or_default();
+
+        if !self.build.release {
+            self.build.release = generate.release.unwrap_or(false);
+        }
+        if !self.emit_all_errors {
+            self.emit_all_errors = generate.emit_all_errors.unwrap_or(false);
+        }
+        if self.analysis_feature.is_none() {
+            self.analysis_feature = generate.analysis_feature.or(general.analysis_feature);
+        }
+        if self.klee_args.is_empty() {
+            self.klee_args = generate.klee_args.unwrap_or_default();
+        }
+        if self.klee_max_time.is_none() {
+            self.klee_max_time = generate.klee_max_time;
+        }
+        if self.klee.is_none() {
+            self.klee = generate.klee;
+        }
+    }
+
+    /// Returns the effective value and source of each setting of this command.
+    pub fn resolved_settings(&self, settings: &RaukSettings) -> Vec<ResolvedSetting> {
+        let general = settings.general.clone().unwrap_or_default();
+        let generate = settings.generate.clone().unwrap_or_default();
+        let cli_klee_args = if self.klee_args.is_empty() {
+            None
+        } else {
+            Some(format!("{:?}", self.klee_args))
+        };
+        vec![
+            ResolvedSetting::resolve(
+                "release",
+                &cli_flag(self.build.release),
+                &generate.release,
+                Some(false),
+            ),
+            ResolvedSetting::resolve(
+                "emit-all-errors",
+                &cli_flag(self.emit_all_errors),
+                &generate.emit_all_errors,
+                Some(false),
+            ),
+            ResolvedSetting::resolve(
+                "analysis-feature",
+                &self.analysis_feature,
+                &generate.analysis_feature.or(general.analysis_feature),
+                Some(generate::DEFAULT_ANALYSIS_FEATURE.to_string()),
+            ),
+            ResolvedSetting::resolve(
+                "klee-args",
+                &cli_klee_args,
+                &generate.klee_args.map(|args| format!("{:?}", args)),
+                Some("[]".to_string()),
+            ),
+            ResolvedSetting::resolve(
+                "klee-max-time",
+                &self.klee_max_time,
+                &generate.klee_max_time,
+                None,
+            ),
+            ResolvedSetting::resolve(
+                "klee",
+                &self.klee,
+                &generate.klee,
+                Some(generate::DEFAULT_KLEE.to_string()),
+            ),
+        ]
     }
 }
 
 impl FlashInput {
     /// If input is missing, check if it is available in the settings
-    /// and overwrite the missing input with those values.
+    /// and overwrite the missing input with those values. The `[flash]`
+    /// section takes precedence over `[general]`.
     pub fn get_missing_input(&mut self, settings: &RaukSettings) {
-        if let Some(general) = &settings.general {
-            if self.target.is_none() {
-                self.target = general.target.clone();
-            }
-            if self.chip.is_none() {
-                self.chip = general.chip.clone();
-            }
-            if self.halt_timeout.is_none() {
-                self.halt_timeout = general.halt_timeout.clone();
-            }
+        let general = settings.general.clone().unwrap_or_default();
+        let flash = settings.flash.clone().unwrap_or_default();
+
+        if !self.build.release {
+            self.build.release = flash.release.unwrap_or(false);
+        }
+        if !self.verify {
+            self.verify = flash.verify.unwrap_or(false);
+        }
+        if self.replay_feature.is_none() {
+            self.replay_feature = flash.replay_feature.or(general.replay_feature);
+        }
+        if self.target.is_none() {
+            self.target = settings.replay_target();
+        }
+        if self.chip.is_none() {
+            self.chip = flash
+                .chip
+                .or(general.chip)
+                .or_else(|| settings.detected.chip.clone());
+        }
+        if self.halt_timeout.is_none() {
+            self.halt_timeout = flash.halt_timeout.or(general.halt_timeout);
         }
     }
+
+    /// Returns the effective value and source of each setting of this command.
+    pub fn resolved_settings(&self, settings: &RaukSettings) -> Vec<ResolvedSetting> {
+        let general = settings.general.clone().unwrap_or_default();
+        let flash = settings.flash.clone().unwrap_or_default();
+        vec![
+            ResolvedSetting::resolve(
+                "release",
+                &cli_flag(self.build.release),
+                &flash.release,
+                Some(false),
+            ),
+            ResolvedSetting::resolve_detected(
+                "chip",
+                &self.chip,
+                &flash.chip.or(general.chip),
+                &settings.detected.chip,
+            ),
+            ResolvedSetting::resolve_detected(
+                "target",
+                &self.target,
+                &flash.target.or(general.target),
+                &settings.detected.target,
+            ),
+            ResolvedSetting::resolve(
+                "halt-timeout",
+                &self.halt_timeout,
+                &flash.halt_timeout.or(general.halt_timeout),
+                Some(flash::DEFAULT_HALT_TIMEOUT_SECONDS),
+            ),
+            ResolvedSetting::resolve(
+                "replay-feature",
+                &self.replay_feature,
+                &flash.replay_feature.or(general.replay_feature),
+                Some(flash::DEFAULT_REPLAY_FEATURE.to_string()),
+            ),
+            ResolvedSetting::resolve("verify", &cli_flag(self.verify), &flash.verify, Some(false)),
+        ]
+    }
 }
 
 impl MeasureInput {
     /// If input is missing, check if it is available in the settings
-    /// and overwrite the missing input with those values.
+    /// and overwrite the missing input with those values. The `[measure]`
+    /// section takes precedence over `[general]`.
     pub fn get_missing_input(&mut self, settings: &RaukSettings) {
-        if let Some(general) = &settings.general {
-            if self.chip.is_none() {
-                self.chip = general.chip.clone();
-            }
-            if self.halt_timeout.is_none() {
-                self.halt_timeout = general.halt_timeout.clone();
-            }
+        let general = settings.general.clone().unwrap_or_default();
+        let measure = settings.measure.clone().unwrap_or_default();
+
+        if !self.build.release {
+            self.build.release = measure.release.unwrap_or(false);
+        }
+        if self.chip.is_none() {
+            self.chip = measure
+                .chip
+                .or(general.chip)
+                .or_else(|| settings.detected.chip.clone());
+        }
+        if self.halt_timeout.is_none() {
+            self.halt_timeout = measure.halt_timeout.or(general.halt_timeout);
+        }
+        if self.ram_start.is_none() {
+            self.ram_start = measure.ram_start;
+        }
+        if self.objdump.is_none() {
+            self.objdump = measure.objdump;
         }
     }
+
+    /// Returns the effective value and source of each setting of this command.
+    pub fn resolved_settings(&self, settings: &RaukSettings) -> Vec<ResolvedSetting> {
+        let general = settings.general.clone().unwrap_or_default();
+        let measure = settings.measure.clone().unwrap_or_default();
+        vec![
+            ResolvedSetting::resolve(
+                "release",
+                &cli_flag(self.build.release),
+                &measure.release,
+                Some(false),
+            ),
+            ResolvedSetting::resolve_detected(
+                "chip",
+                &self.chip,
+                &measure.chip.or(general.chip),
+                &settings.detected.chip,
+            ),
+            ResolvedSetting::resolve(
+                "halt-timeout",
+                &self.halt_timeout,
+                &measure.halt_timeout.or(general.halt_timeout),
+                Some(measure::DEFAULT_HALT_TIMEOUT_SECONDS),
+            ),
+            ResolvedSetting::resolve(
+                "ram-start",
+                &self.ram_start,
+                &measure.ram_start,
+                Some(measure::DEFAULT_RAM_START),
+            ),
+            ResolvedSetting::resolve(
+                "objdump",
+                &self.objdump,
+                &measure.objdump,
+                Some(measure::DEFAULT_OBJDUMP.to_string()),
+            ),
+        ]
+    }
 }
 
 /// Check if the settings file exists in the project directory.
@@ -79,23 +474,286 @@ pub fn settings_file_exists(project_dir: &PathBuf) -> bool {
 fn load_settings_from_dir(project_dir: &PathBuf) -> Result<RaukSettings> {
     let mut rauk_config_path = project_dir.clone();
     rauk_config_path.push(RAUK_CONFIG_TOML);
-    let mut file = File::open(rauk_config_path)?;
+    let mut file = File::open(&rauk_config_path)?;
     let mut contents = String::new();
     file.read_to_string(&mut contents)?;
-    let settings: RaukSettings = toml::from_str(&contents)?;
+    let settings: RaukSettings = toml::from_str(&contents)
+        .with_context(|| format!("Invalid settings file {:?}", &rauk_config_path))?;
     Ok(settings)
 }
 
 /// Loads settings from file if it exists, otherwise creates an empty
-/// settings struct.
+/// settings struct. The chip and target of other tools in the project are detected
+/// as fallbacks.
 pub fn load_settings(project_dir: &PathBuf) -> Result<RaukSettings> {
-    let settings = if settings_file_exists(&project_dir) {
+    let mut settings = if settings_file_exists(&project_dir) {
         info!("Loading user settings from file");
         load_settings_from_dir(&project_dir)?
     } else {
         info!("No user settings file found");
         RaukSettings::new()
     };
+    settings.detected = detect_settings(project_dir);
 
     Ok(settings)
 }
+
+/// Loads the settings file and returns the effective value and source of each setting,
+/// grouped by command. Includes the values detected from other tools in the project and
+/// the defaults.
+///
+/// * `project_dir` - The path to the RTIC project
+/// * `no_patch` - The `--no-patch` flag given on the command line
+pub fn resolved_settings_report(project_dir: &PathBuf, no_patch: bool) -> Result<String> {
+    let settings = load_settings(project_dir)?;
+    // No command is run, so the input only needs the name of a binary
+    let generate = GenerateInput::from_iter(&["generate", "--bin", "<bin>"]);
+    let flash = FlashInput::from_iter(&["flash", "--bin", "<bin>"]);
+    let measure = MeasureInput::from_iter(&["measure", "--bin", "<bin>"]);
+
+    let sections = [
+        ("general", settings.resolved_settings(no_patch)),
+        ("generate", generate.resolved_settings(&settings)),
+        ("flash", flash.resolved_settings(&settings)),
+        ("measure", measure.resolved_settings(&settings)),
+    ];
+    let mut report = String::new();
+    for (section, resolved) in &sections {
+        writeln!(report, "[{}]", section)?;
+        for setting in resolved {
+            writeln!(report, "{}", setting)?;
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir_all, remove_dir_all, write};
+
+    /// Returns the resolved setting with the given key
+    fn setting(resolved: Vec<ResolvedSetting>, key: &str) -> ResolvedSetting {
+        resolved
+            .into_iter()
+            .find(|setting| setting.key == key)
+            .unwrap()
+    }
+
+    fn project_with_settings(name: &str, contents: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rauk-settings-{}", name));
+        let _ = remove_dir_all(&dir);
+        create_dir_all(&dir).unwrap();
+        write(dir.join(RAUK_CONFIG_TOML), contents).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_resolved_settings_report_covers_every_key() {
+        let contents = "[general]\n\
+                        no-patch = true\n\
+                        chip = \"STM32F401RETx\"\n\
+                        target = \"thumbv7em-none-eabihf\"\n\
+                        halt-timeout = 20\n\
+                        analysis-feature = \"rauk-klee\"\n\
+                        replay-feature = \"rauk-replay\"\n\
+                        [generate]\n\
+                        release = true\n\
+                        emit-all-errors = true\n\
+                        analysis-feature = \"rauk-klee\"\n\
+                        klee-args = [\"--max-depth=100\"]\n\
+                        klee-max-time = 60\n\
+                        klee = \"/opt/klee/bin/klee\"\n\
+                        [flash]\n\
+                        release = true\n\
+                        chip = \"STM32F401RETx\"\n\
+                        target = \"thumbv7em-none-eabihf\"\n\
+                        halt-timeout = 20\n\
+                        replay-feature = \"rauk-replay\"\n\
+                        verify = true\n\
+                        [measure]\n\
+                        release = true\n\
+                        chip = \"STM32F401RETx\"\n\
+                        halt-timeout = 20\n\
+                        ram-start = 536870912\n\
+                        objdump = \"llvm-objdump-15\"\n";
+        let dir = project_with_settings("every-key", contents);
+        let report = resolved_settings_report(&dir, false).unwrap();
+
+        let file: toml::Value = toml::from_str(contents).unwrap();
+        for (section, table) in file.as_table().unwrap() {
+            let start = report.find(&format!("[{}]", section)).unwrap();
+            let lines = report[start..].lines().skip(1);
+            let lines: Vec<&str> = lines.take_while(|line| !line.starts_with('[')).collect();
+            for key in table.as_table().unwrap().keys() {
+                // The general settings are resolved by the commands that use them
+                let prefix = format!("{} = ", key);
+                let found = if section == "general" {
+                    report.lines().any(|line| line.starts_with(&prefix))
+                } else {
+                    lines.iter().any(|line| line.starts_with(&prefix))
+                };
+                assert!(found, "{} of [{}] is not in the report", key, section);
+            }
+        }
+        assert!(report.contains("klee-args = [\"--max-depth=100\"] (rauk.toml)"));
+        let _ = remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_resolved_settings_invalid_file() {
+        let dir = project_with_settings("invalid", "[general]\nchip = 5\n");
+        assert!(resolved_settings_report(&dir, false).is_err());
+        let _ = remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_resolved_settings_precedence() {
+        let mut settings = RaukSettings::new();
+        settings.general = Some(General {
+            chip: Some("STM32F401RETx".to_string()),
+            ..General::default()
+        });
+        let chip = |input: &MeasureInput, settings: &RaukSettings| {
+            setting(input.resolved_settings(settings), "chip")
+        };
+
+        // The command line wins over the file
+        let input =
+            MeasureInput::from_iter(&["measure", "--bin", "app", "--chip", "nRF52840_xxAA"]);
+        let resolved = chip(&input, &settings);
+        assert_eq!(resolved.value, Some("nRF52840_xxAA".to_string()));
+        assert_eq!(resolved.source, SettingSource::Cli);
+
+        // The file wins over the default
+        let input = MeasureInput::from_iter(&["measure", "--bin", "app"]);
+        let resolved = chip(&input, &settings);
+        assert_eq!(resolved.value, Some("STM32F401RETx".to_string()));
+        assert_eq!(resolved.source, SettingSource::File);
+
+        let resolved = chip(&input, &RaukSettings::new());
+        assert_eq!(resolved.value, None);
+        assert_eq!(resolved.source, SettingSource::Default);
+
+        let halt_timeout = setting(input.resolved_settings(&settings), "halt-timeout");
+        assert_eq!(halt_timeout.value, Some("10".to_string()));
+        assert_eq!(halt_timeout.source, SettingSource::Default);
+    }
+
+    #[test]
+    fn test_generate_section_populates_input() {
+        let dir = project_with_settings(
+            "generate-section",
+            "[general]\n\
+             analysis-feature = \"general-feature\"\n\
+             [generate]\n\
+             release = true\n\
+             emit-all-errors = true\n\
+             analysis-feature = \"rauk-klee\"\n\
+             klee-args = [\"--max-depth=100\"]\n\
+             klee-max-time = 60\n\
+             klee = \"/opt/klee/bin/klee\"\n",
+        );
+        let settings = load_settings(&dir).unwrap();
+
+        let mut input = GenerateInput::from_iter(&["generate", "--bin", "app"]);
+        input.get_missing_input(&settings);
+        assert!(input.build.release);
+        assert!(input.emit_all_errors);
+        assert_eq!(input.analysis_feature, Some("rauk-klee".to_string()));
+        assert_eq!(input.klee_args, vec!["--max-depth=100".to_string()]);
+        assert_eq!(input.klee_max_time, Some(60));
+        assert_eq!(input.klee, Some("/opt/klee/bin/klee".to_string()));
+
+        // The command line still takes precedence
+        let mut input =
+            GenerateInput::from_iter(&["generate", "--bin", "app", "--klee-max-time", "5"]);
+        input.get_missing_input(&settings);
+        assert_eq!(input.klee_max_time, Some(5));
+
+        let _ = remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_no_patch_setting() {
+        let dir = project_with_settings("no-patch", "[general]\nno-patch = true\n");
+        let settings = load_settings(&dir).unwrap();
+        assert!(settings.resolve_no_patch(false));
+        assert!(settings.resolve_no_patch(true));
+
+        let settings = RaukSettings::new();
+        assert!(!settings.resolve_no_patch(false));
+        assert!(settings.resolve_no_patch(true));
+        let _ = remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_settings_unknown_key() {
+        let dir = project_with_settings(
+            "unknown-key",
+            "[general]\nchip = \"STM32F401RETx\"\nhalt-timout = 20\n",
+        );
+        let error = load_settings(&dir).unwrap_err();
+        let message = format!("{:#}", error);
+        assert!(message.contains("unknown field `halt-timout`"));
+        let _ = remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_resolved_settings_report() {
+        let dir = project_with_settings("valid", "[measure]\nchip = \"STM32F401RETx\"\n");
+        write(
+            dir.join(detect::EMBED_TOML),
+            "[default.general]\nchip = \"nRF52840_xxAA\"\n",
+        )
+        .unwrap();
+
+        let report = resolved_settings_report(&dir, true).unwrap();
+        let lines: Vec<&str> = report.lines().collect();
+        let section = |name: &str| {
+            let start = lines.iter().position(|line| *line == name).unwrap() + 1;
+            lines[start..]
+                .iter()
+                .take_while(|line| !line.starts_with('['))
+                .cloned()
+                .collect::<Vec<&str>>()
+        };
+
+        assert_eq!(section("[general]"), vec!["no-patch = true (command line)"]);
+        let detected = format!("chip = nRF52840_xxAA ({})", SettingSource::Detected);
+        assert!(section("[flash]").contains(&detected.as_str()));
+        assert!(section("[flash]").contains(&"halt-timeout = 5 (default)"));
+        assert!(section("[measure]").contains(&"chip = STM32F401RETx (rauk.toml)"));
+        assert!(section("[generate]").contains(&"klee = klee (default)"));
+        let _ = remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_detected_settings_fallback() {
+        let mut settings = RaukSettings::new();
+        settings.detected = DetectedSettings {
+            chip: Some("nRF52840_xxAA".to_string()),
+            target: Some("thumbv7em-none-eabihf".to_string()),
+        };
+
+        let mut input = FlashInput::from_iter(&["flash", "--bin", "app"]);
+        let resolved = setting(input.resolved_settings(&settings), "chip");
+        assert_eq!(resolved.source, SettingSource::Detected);
+        input.get_missing_input(&settings);
+        assert_eq!(input.chip, Some("nRF52840_xxAA".to_string()));
+        assert_eq!(input.target, Some("thumbv7em-none-eabihf".to_string()));
+
+        // The settings file takes precedence
+        settings.general = Some(General {
+            chip: Some("STM32F401RETx".to_string()),
+            ..General::default()
+        });
+        let mut input = MeasureInput::from_iter(&["measure", "--bin", "app"]);
+        assert_eq!(
+            setting(input.resolved_settings(&settings), "chip").source,
+            SettingSource::File
+        );
+        input.get_missing_input(&settings);
+        assert_eq!(input.chip, Some("STM32F401RETx".to_string()));
+    }
+}
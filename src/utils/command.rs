@@ -0,0 +1,42 @@
+use anyhow::anyhow;
+use std::io;
+
+/// Turns the error of spawning an external program into an error that explains how to
+/// install the program if it was not found.
+///
+/// * `error` - The error returned when spawning the program
+/// * `program` - The name of the program's binary
+/// * `hint` - How to install the program
+pub fn spawn_error(error: io::Error, program: &str, hint: &str) -> anyhow::Error {
+    if error.kind() == io::ErrorKind::NotFound {
+        anyhow!(
+            "Could not find `{}`. It must be installed and on PATH. {}",
+            program,
+            hint
+        )
+    } else {
+        anyhow::Error::new(error).context(format!("Failed to execute {}", program))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+
+    #[test]
+    fn test_not_found_spawn_error() {
+        let error = Command::new("rauk-program-that-does-not-exist")
+            .output()
+            .unwrap_err();
+        let message = spawn_error(error, "klee", "Install KLEE").to_string();
+        assert_eq!(
+            message,
+            "Could not find `klee`. It must be installed and on PATH. Install KLEE"
+        );
+
+        let error = io::Error::new(io::ErrorKind::PermissionDenied, "denied");
+        let message = format!("{:#}", spawn_error(error, "klee", "Install KLEE"));
+        assert_eq!(message, "Failed to execute klee: denied");
+    }
+}
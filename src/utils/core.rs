@@ -1,24 +1,269 @@
-use anyhow::{anyhow, Result};
-use probe_rs::{Core, MemoryInterface, Probe, Session};
+use crate::cli::{ProbeOptions, Protocol, Timer};
+use anyhow::{anyhow, Context, Result};
+use probe_rs::{
+    Core, CoreRegisterAddress, CoreStatus, DebugProbeInfo, MemoryInterface, Probe, Session,
+    WireProtocol,
+};
+use std::time::Duration;
+
+/// A debug session that can attach to one of the target's cores.
+pub trait CoreSession<'a> {
+    type Core;
+
+    /// Returns the number of cores of the target
+    fn core_count(&self) -> usize;
+
+    /// Attaches to the core with the given index
+    fn core_at(&'a mut self, index: usize) -> Result<Self::Core>;
+}
+
+impl<'a> CoreSession<'a> for Session {
+    type Core = Core<'a>;
+
+    fn core_count(&self) -> usize {
+        self.list_cores().len()
+    }
+
+    fn core_at(&'a mut self, index: usize) -> Result<Core<'a>> {
+        Ok(self.core(index)?)
+    }
+}
+
+/// The core operations used when measuring. Implemented for probe-rs cores and by
+/// mocks in tests, so the measurement can be tested without hardware.
+pub trait CoreAccess {
+    /// Continues execution of the core
+    fn run(&mut self) -> Result<()>;
+
+    /// Executes a single instruction
+    fn step(&mut self) -> Result<()>;
+
+    /// Halts the core. Returns an error if it does not halt within `timeout`.
+    fn halt(&mut self, timeout: Duration) -> Result<()>;
+
+    /// Resets the core and halts it before the first instruction
+    fn reset_and_halt(&mut self, timeout: Duration) -> Result<()>;
+
+    /// Returns true if the core is halted
+    fn core_halted(&mut self) -> Result<bool>;
+
+    /// Waits until the core halts. Returns an error if it does not halt within `timeout`.
+    fn wait_for_core_halted(&mut self, timeout: Duration) -> Result<()>;
+
+    /// Returns the status of the core
+    fn status(&mut self) -> Result<CoreStatus>;
+
+    /// Returns the address of the program counter register
+    fn program_counter(&self) -> CoreRegisterAddress;
+
+    /// Returns the address of the link register
+    fn return_address(&self) -> CoreRegisterAddress;
+
+    fn read_core_reg(&mut self, address: CoreRegisterAddress) -> Result<u32>;
+
+    fn write_core_reg(&mut self, address: CoreRegisterAddress, value: u32) -> Result<()>;
+
+    fn read_8(&mut self, address: u32, data: &mut [u8]) -> Result<()>;
+
+    fn read_32(&mut self, address: u32, data: &mut [u32]) -> Result<()>;
+
+    fn write_word_32(&mut self, address: u32, data: u32) -> Result<()>;
+
+    fn write_8(&mut self, address: u32, data: &[u8]) -> Result<()>;
+
+    /// Writes any buffered memory writes to the target
+    fn flush(&mut self) -> Result<()>;
+
+    fn set_hw_breakpoint(&mut self, address: u32) -> Result<()>;
+
+    fn clear_hw_breakpoint(&mut self, address: u32) -> Result<()>;
+}
+
+impl<'a> CoreAccess for Core<'a> {
+    fn run(&mut self) -> Result<()> {
+        Ok(Core::run(self)?)
+    }
+
+    fn step(&mut self) -> Result<()> {
+        Core::step(self)?;
+        Ok(())
+    }
+
+    fn halt(&mut self, timeout: Duration) -> Result<()> {
+        Core::halt(self, timeout)?;
+        Ok(())
+    }
+
+    fn reset_and_halt(&mut self, timeout: Duration) -> Result<()> {
+        Core::reset_and_halt(self, timeout)?;
+        Ok(())
+    }
+
+    fn core_halted(&mut self) -> Result<bool> {
+        Ok(Core::core_halted(self)?)
+    }
+
+    fn wait_for_core_halted(&mut self, timeout: Duration) -> Result<()> {
+        Ok(Core::wait_for_core_halted(self, timeout)?)
+    }
+
+    fn status(&mut self) -> Result<CoreStatus> {
+        Ok(Core::status(self)?)
+    }
+
+    fn program_counter(&self) -> CoreRegisterAddress {
+        self.registers().program_counter().into()
+    }
+
+    fn return_address(&self) -> CoreRegisterAddress {
+        self.registers().return_address().into()
+    }
+
+    fn read_core_reg(&mut self, address: CoreRegisterAddress) -> Result<u32> {
+        Ok(Core::read_core_reg(self, address)?)
+    }
+
+    fn write_core_reg(&mut self, address: CoreRegisterAddress, value: u32) -> Result<()> {
+        Ok(Core::write_core_reg(self, address, value)?)
+    }
+
+    fn read_8(&mut self, address: u32, data: &mut [u8]) -> Result<()> {
+        Ok(MemoryInterface::read_8(self, address, data)?)
+    }
+
+    fn read_32(&mut self, address: u32, data: &mut [u32]) -> Result<()> {
+        Ok(MemoryInterface::read_32(self, address, data)?)
+    }
+
+    fn write_word_32(&mut self, address: u32, data: u32) -> Result<()> {
+        Ok(MemoryInterface::write_word_32(self, address, data)?)
+    }
+
+    fn write_8(&mut self, address: u32, data: &[u8]) -> Result<()> {
+        Ok(MemoryInterface::write_8(self, address, data)?)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(MemoryInterface::flush(self)?)
+    }
+
+    fn set_hw_breakpoint(&mut self, address: u32) -> Result<()> {
+        Ok(Core::set_hw_breakpoint(self, address)?)
+    }
+
+    fn clear_hw_breakpoint(&mut self, address: u32) -> Result<()> {
+        Ok(Core::clear_hw_breakpoint(self, address)?)
+    }
+}
 
 const CYCCNT: u32 = 0xe000_1004;
+const SYST_CSR: u32 = 0xe000_e010;
+const SYST_RVR: u32 = 0xe000_e014;
+const SYST_CVR: u32 = 0xe000_e018;
+/// The largest reload value of the 24-bit SysTick counter
+const SYST_MAX_RELOAD: u32 = 0x00ff_ffff;
+/// Debug Exception and Monitor Control Register
+const DEMCR: u32 = 0xe000_edfc;
+/// Halt the core on the reset vector
+const DEMCR_VC_CORERESET: u32 = 1 << 0;
+/// Application Interrupt and Reset Control Register
+const AIRCR: u32 = 0xe000_ed0c;
+/// Requests a system reset, with the key that makes the write take effect
+const AIRCR_SYSRESETREQ: u32 = 0x05fa_0004;
+
+/// A counter on the target that is read at each breakpoint to measure clock cycles.
+pub trait CycleSource {
+    /// Address of the register holding the counter value
+    fn address(&self) -> u32;
+
+    /// Decodes the raw register value into an up-counting cycle count
+    fn decode(&self, raw: u32) -> u32;
+
+    /// Number of cycles until the counter wraps around
+    fn period(&self) -> u64;
+
+    /// Prepares the counter before measuring
+    fn setup(&self, _core: &mut dyn CoreAccess) -> Result<()> {
+        Ok(())
+    }
+
+    /// Reads the current cycle count from the core
+    fn read(&self, core: &mut dyn CoreAccess) -> Result<u32> {
+        let mut buf = [0u32; 1];
+        core.read_32(self.address(), &mut buf)?;
+        Ok(self.decode(buf[0]))
+    }
+}
+
+/// The 32-bit DWT cycle counter. Must be enabled by the application.
+pub struct DwtCycleCounter;
+
+impl CycleSource for DwtCycleCounter {
+    fn address(&self) -> u32 {
+        CYCCNT
+    }
+
+    fn decode(&self, raw: u32) -> u32 {
+        raw
+    }
+
+    fn period(&self) -> u64 {
+        1 << 32
+    }
+}
+
+/// The 24-bit SysTick timer running on the processor clock. Counts down from the
+/// maximum reload value, so at most 2^24 cycles can pass between two breakpoints.
+/// The application must not use SysTick itself.
+pub struct SysTickCounter;
+
+impl CycleSource for SysTickCounter {
+    fn address(&self) -> u32 {
+        SYST_CVR
+    }
+
+    fn decode(&self, raw: u32) -> u32 {
+        SYST_MAX_RELOAD - (raw & SYST_MAX_RELOAD)
+    }
+
+    fn period(&self) -> u64 {
+        SYST_MAX_RELOAD as u64 + 1
+    }
 
-pub fn step_from_breakpoint(core: &mut Core) -> Result<()> {
+    fn setup(&self, core: &mut dyn CoreAccess) -> Result<()> {
+        core.write_word_32(SYST_RVR, SYST_MAX_RELOAD)?;
+        // Any write clears the current value
+        core.write_word_32(SYST_CVR, 0)?;
+        // Enable the counter on the processor clock without interrupts
+        core.write_word_32(SYST_CSR, 0b101)?;
+        Ok(())
+    }
+}
+
+/// Returns the cycle source of the given timer.
+pub fn cycle_source(timer: Timer) -> Box<dyn CycleSource> {
+    match timer {
+        Timer::Dwt => Box::new(DwtCycleCounter),
+        Timer::SysTick => Box::new(SysTickCounter),
+    }
+}
+
+pub fn step_from_breakpoint<C: CoreAccess>(core: &mut C) -> Result<()> {
     let mut smbf = [0u8; 2];
-    let pc = core.registers().program_counter();
+    let pc = core.program_counter();
     let pc_val = core.read_core_reg(pc)?;
     let step_pc = pc_val + 0x2;
 
     core.read_8(pc_val, &mut smbf)?;
 
-    core.write_core_reg(pc.into(), step_pc)?;
+    core.write_core_reg(pc, step_pc)?;
     core.step()?;
     Ok(())
 }
 
 /// Wrapper around probe::core.run(). But also continues
 /// if there is a breakpoint at the current program counter.
-pub fn run(core: &mut Core) -> Result<()> {
+pub fn run<C: CoreAccess>(core: &mut C) -> Result<()> {
     if core.core_halted()? {
         if breakpoint_at_pc(core)? {
             step_from_breakpoint(core)?;
@@ -29,7 +274,7 @@ pub fn run(core: &mut Core) -> Result<()> {
 }
 
 /// Checks if there is a breakpoint at the current program counter.
-pub fn breakpoint_at_pc(core: &mut Core) -> Result<bool> {
+pub fn breakpoint_at_pc<C: CoreAccess>(core: &mut C) -> Result<bool> {
     let mut instr16 = [0u8; 2];
     let pc_val = current_pc(core)?;
     core.read_8(pc_val, &mut instr16)?;
@@ -41,12 +286,12 @@ pub fn breakpoint_at_pc(core: &mut Core) -> Result<bool> {
     Ok(check)
 }
 
-pub fn current_pc(core: &mut Core) -> Result<u32> {
-    let pc = core.registers().program_counter();
-    Ok(core.read_core_reg(pc)?)
+pub fn current_pc<C: CoreAccess>(core: &mut C) -> Result<u32> {
+    let pc = core.program_counter();
+    core.read_core_reg(pc)
 }
 
-pub fn read_breakpoint_value(core: &mut Core) -> Result<u8> {
+pub fn read_breakpoint_value<C: CoreAccess>(core: &mut C) -> Result<u8> {
     let mut instr16 = [0u8; 2];
     let pc_val = current_pc(core)?;
     core.read_8(pc_val, &mut instr16)?;
@@ -60,20 +305,399 @@ pub fn read_breakpoint_value(core: &mut Core) -> Result<u8> {
     }
 }
 
-pub fn read_cycle_counter(core: &mut Core) -> Result<u32> {
-    let mut buf = [0u32, 1];
-    core.read_32(CYCCNT, &mut buf)?;
-    Ok(buf[0])
+/// Resets the system via SYSRESETREQ and halts the core at the reset vector. The
+/// reset vector catch is set before the reset, so no firmware runs before the halt.
+/// The previous value of DEMCR is restored afterwards.
+pub fn software_reset_and_halt<C: CoreAccess>(core: &mut C, timeout: Duration) -> Result<()> {
+    let mut demcr = [0u32; 1];
+    core.read_32(DEMCR, &mut demcr)?;
+    core.write_word_32(DEMCR, demcr[0] | DEMCR_VC_CORERESET)?;
+    core.write_word_32(AIRCR, AIRCR_SYSRESETREQ)?;
+    core.flush()?;
+    core.wait_for_core_halted(timeout)
+        .context("The core did not halt at the reset vector after the software reset")?;
+    core.write_word_32(DEMCR, demcr[0])?;
+    Ok(())
+}
+
+/// Attaches to the core at `index`. Returns an error if the target has no such core.
+pub fn attach_core<'a, S: CoreSession<'a>>(session: &'a mut S, index: usize) -> Result<S::Core> {
+    let count = session.core_count();
+    if index >= count {
+        return Err(anyhow!(
+            "Core index {} is out of range. The target has {} core(s)",
+            index,
+            count
+        ));
+    }
+    session.core_at(index)
+}
+
+/// Returns the sorted names of the chips known to probe-rs, optionally only those
+/// containing `filter`.
+pub fn list_chips(filter: Option<&str>) -> Result<Vec<String>> {
+    let families = probe_rs::config::families().context("Could not read the probe-rs targets")?;
+    let names = families
+        .iter()
+        .flat_map(|family| family.variants.iter().map(|chip| chip.name.clone()))
+        .collect();
+    Ok(filter_chips(names, filter))
 }
 
-/// Opens the first probe it can find and return its session
-pub fn open_and_attach_probe(chip_name: &String) -> Result<Session> {
+/// Returns an error with similar chip names if probe-rs does not know the chip.
+pub fn validate_chip(chip: &str) -> Result<()> {
+    if probe_rs::config::get_target_by_name(chip).is_ok() {
+        return Ok(());
+    }
+    let suggestions = similar_chips(chip, list_chips(None)?);
+    if suggestions.is_empty() {
+        Err(anyhow!(
+            "Unknown chip '{}'. Run `rauk chips` to list the known chips",
+            chip
+        ))
+    } else {
+        Err(anyhow!(
+            "Unknown chip '{}'. Did you mean one of: {}? Run `rauk chips` to list the known chips",
+            chip,
+            suggestions.join(", ")
+        ))
+    }
+}
+
+/// Returns up to five of the names sharing the longest prefix with `chip`, ignoring
+/// case. Nothing is returned if no name shares at least four characters.
+fn similar_chips(chip: &str, names: Vec<String>) -> Vec<String> {
+    let chip = chip.to_lowercase();
+    let common_prefix = |name: &String| {
+        chip.chars()
+            .zip(name.to_lowercase().chars())
+            .take_while(|(a, b)| a == b)
+            .count()
+    };
+    let longest = names.iter().map(common_prefix).max().unwrap_or(0);
+    if longest < 4 {
+        return Vec::new();
+    }
+    names
+        .into_iter()
+        .filter(|name| common_prefix(name) == longest)
+        .take(5)
+        .collect()
+}
+
+/// Keeps the chip names containing `filter`, ignoring case, and sorts them.
+fn filter_chips(mut names: Vec<String>, filter: Option<&str>) -> Vec<String> {
+    if let Some(filter) = filter {
+        let filter = filter.to_lowercase();
+        names.retain(|name| name.to_lowercase().contains(&filter));
+    }
+    names.sort();
+    names
+}
+
+/// Opens the first probe it can find and return its session. If `under_reset` is set
+/// the probe holds the nRST line while attaching, resetting the target. Retries as
+/// given by `options` if the probe is not ready, e.g. right after a reset.
+pub fn open_and_attach_probe(
+    chip_name: &String,
+    under_reset: bool,
+    options: &ProbeOptions,
+) -> Result<Session> {
+    retry_with_backoff(
+        options.attach_retries + 1,
+        Duration::from_millis(options.attach_delay_ms),
+        || attach_probe(chip_name, under_reset, options),
+    )
+}
+
+/// Calls `f` until it succeeds, at most `attempts` times. Waits `delay` after the
+/// first failure and doubles the wait after each further failure. Returns the last
+/// error if all attempts fail.
+pub fn retry_with_backoff<T, F: FnMut() -> Result<T>>(
+    attempts: u32,
+    delay: Duration,
+    mut f: F,
+) -> Result<T> {
+    let mut delay = delay;
+    let mut attempt = 1;
+    loop {
+        match f() {
+            Ok(value) => return Ok(value),
+            Err(e) if attempt < attempts => {
+                warn!(
+                    "Attempt {}/{} failed: {:#}. Retrying in {} ms",
+                    attempt,
+                    attempts,
+                    e,
+                    delay.as_millis()
+                );
+                std::thread::sleep(delay);
+                delay *= 2;
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// The probe settings that can be changed before attaching. Implemented for probe-rs
+/// probes and by mocks in tests.
+pub trait ConfigurableProbe {
+    fn select_protocol(&mut self, protocol: WireProtocol) -> Result<()>;
+
+    /// Sets the clock speed and returns the speed the probe actually uses
+    fn set_speed(&mut self, speed_khz: u32) -> Result<u32>;
+}
+
+impl ConfigurableProbe for Probe {
+    fn select_protocol(&mut self, protocol: WireProtocol) -> Result<()> {
+        Ok(Probe::select_protocol(self, protocol)?)
+    }
+
+    fn set_speed(&mut self, speed_khz: u32) -> Result<u32> {
+        Ok(Probe::set_speed(self, speed_khz)?)
+    }
+}
+
+/// Applies the protocol and speed given in `options` to the probe. Settings that are
+/// not given keep the probe's default.
+pub fn configure_probe<P: ConfigurableProbe>(probe: &mut P, options: &ProbeOptions) -> Result<()> {
+    if let Some(protocol) = options.protocol {
+        let wire_protocol = match protocol {
+            Protocol::Swd => WireProtocol::Swd,
+            Protocol::Jtag => WireProtocol::Jtag,
+        };
+        probe
+            .select_protocol(wire_protocol)
+            .with_context(|| format!("Could not select the {:?} protocol", protocol))?;
+    }
+    if let Some(speed_khz) = options.speed_khz {
+        let actual = probe
+            .set_speed(speed_khz)
+            .with_context(|| format!("Could not set the probe speed to {} kHz", speed_khz))?;
+        if actual != speed_khz {
+            info!(
+                "Probe speed set to {} kHz instead of the requested {} kHz",
+                actual, speed_khz
+            );
+        }
+    }
+    Ok(())
+}
+
+fn attach_probe(chip_name: &String, under_reset: bool, options: &ProbeOptions) -> Result<Session> {
     let probes = Probe::list_all();
 
     if probes.is_empty() {
-        return Err(anyhow!("There are no debug probes connected"));
+        return Err(anyhow!(
+            "There are no debug probes connected. Check that the probe is plugged in and that you have permission to access it"
+        ));
     } else {
-        let probe = probes[0].open()?;
-        Ok(probe.attach(chip_name)?)
+        let description = probe_description(&probes[0]);
+        let mut probe = probes[0]
+            .open()
+            .with_context(|| format!("Could not open the debug probe {}", description))?;
+        configure_probe(&mut probe, options)?;
+        let session = if under_reset {
+            probe.attach_under_reset(chip_name)
+        } else {
+            probe.attach(chip_name)
+        };
+        session.map_err(|e| attach_error(e.into(), chip_name, &description))
+    }
+}
+
+/// Returns the identifier of the probe with its USB vendor and product ids, and the
+/// serial number if known, e.g. `STLink V2-1 (0483:374b, serial 066DFF)`.
+fn probe_description(info: &DebugProbeInfo) -> String {
+    let mut description = format!(
+        "{} ({:04x}:{:04x}",
+        info.identifier, info.vendor_id, info.product_id
+    );
+    if let Some(serial) = &info.serial_number {
+        description.push_str(&format!(", serial {}", serial));
+    }
+    description.push(')');
+    description
+}
+
+/// Explains that the probe was found but could not attach to the chip.
+///
+/// * `error` - The error returned when attaching
+/// * `chip_name` - The chip that was attached to
+/// * `probe` - The description of the probe
+fn attach_error(error: anyhow::Error, chip_name: &str, probe: &str) -> anyhow::Error {
+    error.context(format!(
+        "The debug probe {} is connected but could not attach to the chip '{}'. Check that the chip name matches the target, that the target is powered and wired to the probe, or flash with `--reset hw` to attach under reset",
+        probe, chip_name
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct MockSession {
+        cores: usize,
+    }
+
+    impl<'a> CoreSession<'a> for MockSession {
+        type Core = usize;
+
+        fn core_count(&self) -> usize {
+            self.cores
+        }
+
+        fn core_at(&'a mut self, index: usize) -> Result<usize> {
+            Ok(index)
+        }
+    }
+
+    #[test]
+    fn test_attach_error() {
+        let error = attach_error(
+            anyhow!("An error with the usage of the probe occured"),
+            "STM32F401RETx",
+            "STLink V2-1 (0483:374b, serial 066DFF)",
+        );
+        let message = format!("{:#}", error);
+        assert!(message.starts_with(
+            "The debug probe STLink V2-1 (0483:374b, serial 066DFF) is connected but could not attach to the chip 'STM32F401RETx'"
+        ));
+        assert!(message.ends_with(": An error with the usage of the probe occured"));
+    }
+
+    #[test]
+    fn test_similar_chips() {
+        let names = vec![
+            "STM32F401RETx".to_string(),
+            "STM32F401RCTx".to_string(),
+            "STM32F411CEUx".to_string(),
+            "nRF52840_xxAA".to_string(),
+        ];
+
+        assert_eq!(
+            similar_chips("stm32f401rexx", names.clone()),
+            vec!["STM32F401RETx".to_string()]
+        );
+        assert_eq!(
+            similar_chips("STM32F401", names.clone()),
+            vec!["STM32F401RETx".to_string(), "STM32F401RCTx".to_string()]
+        );
+        assert!(similar_chips("esp32", names).is_empty());
+    }
+
+    #[test]
+    fn test_filter_chips() {
+        let names = vec![
+            "STM32F401RETx".to_string(),
+            "nRF52840_xxAA".to_string(),
+            "STM32F411CEUx".to_string(),
+            "STM32L432KCUx".to_string(),
+        ];
+
+        assert_eq!(
+            filter_chips(names.clone(), Some("stm32f4")),
+            vec!["STM32F401RETx".to_string(), "STM32F411CEUx".to_string()]
+        );
+        assert_eq!(
+            filter_chips(names.clone(), Some("esp32")),
+            Vec::<String>::new()
+        );
+        assert_eq!(filter_chips(names, None).len(), 4);
+    }
+
+    #[test]
+    fn test_dwt_decode() {
+        let source = cycle_source(Timer::Dwt);
+        assert_eq!(source.address(), CYCCNT);
+        assert_eq!(source.decode(1234), 1234);
+        assert_eq!(source.decode(u32::MAX), u32::MAX);
+        assert_eq!(source.period(), 1 << 32);
+    }
+
+    #[test]
+    fn test_systick_decode() {
+        let source = cycle_source(Timer::SysTick);
+        assert_eq!(source.address(), SYST_CVR);
+        // Counts down from the reload value
+        assert_eq!(source.decode(0x00ff_ffff), 0);
+        assert_eq!(source.decode(0x00ff_fff0), 15);
+        assert_eq!(source.decode(0), 0x00ff_ffff);
+        // The reserved upper bits are ignored
+        assert_eq!(source.decode(0xff00_0010), 0x00ff_ffef);
+        assert_eq!(source.period(), 1 << 24);
+    }
+
+    #[derive(Default)]
+    struct MockProbe {
+        protocol: Option<WireProtocol>,
+        speed_khz: Option<u32>,
+    }
+
+    impl ConfigurableProbe for MockProbe {
+        fn select_protocol(&mut self, protocol: WireProtocol) -> Result<()> {
+            self.protocol = Some(protocol);
+            Ok(())
+        }
+
+        fn set_speed(&mut self, speed_khz: u32) -> Result<u32> {
+            self.speed_khz = Some(speed_khz);
+            Ok(speed_khz)
+        }
+    }
+
+    #[test]
+    fn test_configure_probe() {
+        let mut probe = MockProbe::default();
+        configure_probe(&mut probe, &ProbeOptions::default()).unwrap();
+        assert_eq!(probe.protocol, None);
+        assert_eq!(probe.speed_khz, None);
+
+        let options = ProbeOptions {
+            protocol: Some(Protocol::Jtag),
+            speed_khz: Some(4000),
+            ..ProbeOptions::default()
+        };
+        configure_probe(&mut probe, &options).unwrap();
+        assert_eq!(probe.protocol, Some(WireProtocol::Jtag));
+        assert_eq!(probe.speed_khz, Some(4000));
+    }
+
+    #[test]
+    fn test_retry_with_backoff() {
+        let mut calls = 0;
+        let result = retry_with_backoff(3, Duration::from_millis(1), || {
+            calls += 1;
+            if calls < 3 {
+                Err(anyhow!("Probe not ready"))
+            } else {
+                Ok(calls)
+            }
+        });
+        assert_eq!(result.unwrap(), 3);
+    }
+
+    #[test]
+    fn test_retry_with_backoff_returns_last_error() {
+        let mut calls = 0;
+        let result: Result<()> = retry_with_backoff(2, Duration::from_millis(1), || {
+            calls += 1;
+            Err(anyhow!("Attempt {}", calls))
+        });
+        assert_eq!(result.unwrap_err().to_string(), "Attempt 2");
+        assert_eq!(calls, 2);
+    }
+
+    #[test]
+    fn test_attach_core_index() {
+        let mut session = MockSession { cores: 2 };
+        assert_eq!(attach_core(&mut session, 1).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_attach_core_out_of_range() {
+        let mut session = MockSession { cores: 1 };
+        assert!(attach_core(&mut session, 1).is_err());
     }
 }
@@ -1 +1,3 @@
+pub mod command;
 pub mod core;
+pub mod hash;